@@ -0,0 +1,7 @@
+-- 1. Drop tls columns from brokers table
+ALTER TABLE brokers
+    DROP COLUMN IF EXISTS client_key,
+    DROP COLUMN IF EXISTS client_certificate,
+    DROP COLUMN IF EXISTS ca_certificate,
+    DROP COLUMN IF EXISTS verify_hostname,
+    DROP COLUMN IF EXISTS tls_enabled;
@@ -0,0 +1,7 @@
+-- 1. add tls columns to brokers table
+ALTER TABLE brokers
+    ADD COLUMN tls_enabled BOOLEAN NOT NULL DEFAULT FALSE,
+    ADD COLUMN verify_hostname BOOLEAN NOT NULL DEFAULT TRUE,
+    ADD COLUMN ca_certificate TEXT,
+    ADD COLUMN client_certificate TEXT,
+    ADD COLUMN client_key TEXT;
@@ -0,0 +1,4 @@
+-- 1. Drop credential columns from brokers table
+ALTER TABLE brokers
+    DROP COLUMN IF EXISTS password,
+    DROP COLUMN IF EXISTS username;
@@ -0,0 +1,5 @@
+-- 1. add credential columns to brokers table
+-- password is stored encrypted (AES-256-GCM, BROKER_SECRET_KEY)
+ALTER TABLE brokers
+    ADD COLUMN username VARCHAR(255),
+    ADD COLUMN password TEXT;
@@ -0,0 +1,4 @@
+-- 1. Drop reconnect state columns from brokers table
+ALTER TABLE brokers
+    DROP COLUMN IF EXISTS last_error,
+    DROP COLUMN IF EXISTS reconnect_attempts;
@@ -0,0 +1,4 @@
+-- 1. add reconnect state columns to brokers table
+ALTER TABLE brokers
+    ADD COLUMN reconnect_attempts INTEGER NOT NULL DEFAULT 0,
+    ADD COLUMN last_error TEXT;
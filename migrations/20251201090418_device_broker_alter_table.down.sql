@@ -0,0 +1,6 @@
+-- 1. Drop index
+DROP INDEX IF EXISTS idx_devices_broker_id;
+
+-- 2. Drop broker_id column from devices table
+ALTER TABLE devices
+    DROP COLUMN IF EXISTS broker_id;
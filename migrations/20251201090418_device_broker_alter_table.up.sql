@@ -0,0 +1,18 @@
+-- 1. bind devices to a broker
+ALTER TABLE devices
+    ADD COLUMN broker_id INT REFERENCES brokers(id);
+
+-- 2. existing devices keep the broker they were implicitly tied to,
+--    the connected one or else the oldest, without any broker they stay
+--    unbound and are logged at startup
+UPDATE devices
+SET broker_id = (
+    SELECT id FROM brokers
+    WHERE deleted_at IS NULL
+    ORDER BY connected DESC, id ASC
+    LIMIT 1
+)
+WHERE broker_id IS NULL;
+
+-- 3. index for subscribe on connect
+CREATE INDEX idx_devices_broker_id ON devices(broker_id);
@@ -0,0 +1,5 @@
+-- 1. Drop mqtt v5 connect properties from brokers table
+ALTER TABLE brokers
+    DROP COLUMN IF EXISTS topic_alias_maximum,
+    DROP COLUMN IF EXISTS receive_maximum,
+    DROP COLUMN IF EXISTS session_expiry_interval;
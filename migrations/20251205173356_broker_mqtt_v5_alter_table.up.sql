@@ -0,0 +1,5 @@
+-- 1. add mqtt v5 connect properties to brokers table
+ALTER TABLE brokers
+    ADD COLUMN session_expiry_interval INTEGER CHECK (session_expiry_interval >= 0),
+    ADD COLUMN receive_maximum INTEGER CHECK (receive_maximum BETWEEN 1 AND 65535),
+    ADD COLUMN topic_alias_maximum INTEGER CHECK (topic_alias_maximum BETWEEN 0 AND 65535);
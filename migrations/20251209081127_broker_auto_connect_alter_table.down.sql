@@ -0,0 +1,3 @@
+-- 1. Drop auto_connect from brokers table
+ALTER TABLE brokers
+    DROP COLUMN IF EXISTS auto_connect;
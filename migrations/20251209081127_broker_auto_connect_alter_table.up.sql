@@ -0,0 +1,6 @@
+-- 1. add auto_connect to brokers table, connected now only mirrors the live client
+ALTER TABLE brokers
+    ADD COLUMN auto_connect BOOLEAN NOT NULL DEFAULT FALSE;
+
+-- 2. brokers connected before this migration reconnect on startup
+UPDATE brokers SET auto_connect = connected;
@@ -0,0 +1,4 @@
+-- 1. Drop payload decoder columns from devices table
+ALTER TABLE devices
+    DROP COLUMN IF EXISTS payload_metric,
+    DROP COLUMN IF EXISTS payload_format;
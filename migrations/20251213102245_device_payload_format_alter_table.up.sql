@@ -0,0 +1,7 @@
+-- 1. payload decoder used for the readings of each device
+ALTER TABLE devices
+    ADD COLUMN payload_format TEXT NOT NULL DEFAULT 'json';
+
+-- 2. metric used when the payload does not carry one (numeric, csv without metric)
+ALTER TABLE devices
+    ADD COLUMN payload_metric TEXT;
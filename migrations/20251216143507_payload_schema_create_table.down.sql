@@ -0,0 +1,8 @@
+-- 1. Drop trigger
+DROP TRIGGER IF EXISTS set_updated_at_payload_schemas ON payload_schemas;
+
+-- 2. Drop index
+DROP INDEX IF EXISTS idx_payload_schemas_sensor_type;
+
+-- 3. Drop payload_schemas table
+DROP TABLE IF EXISTS payload_schemas;
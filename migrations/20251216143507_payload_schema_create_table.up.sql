@@ -0,0 +1,22 @@
+-- 1. create payload_schemas table, protobuf descriptor sets per device (sensor) type
+CREATE TABLE payload_schemas (
+    id SERIAL PRIMARY KEY,
+    uuid UUID NOT NULL UNIQUE,
+    sensor_type VARCHAR(50) NOT NULL,
+    message_name VARCHAR(255) NOT NULL,
+    descriptor_set BYTEA NOT NULL,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMPTZ,
+    deleted_at TIMESTAMPTZ
+);
+
+-- 2. one active schema per sensor type
+CREATE UNIQUE INDEX idx_payload_schemas_sensor_type
+    ON payload_schemas (LOWER(sensor_type))
+    WHERE deleted_at IS NULL;
+
+-- 3. Trigger update updated_at
+CREATE TRIGGER set_updated_at_payload_schemas
+    BEFORE UPDATE ON payload_schemas
+    FOR EACH ROW
+    EXECUTE FUNCTION trigger_set_updated_at();
@@ -0,0 +1,6 @@
+-- 1. Drop index
+DROP INDEX IF EXISTS idx_scales_device_id;
+
+-- 2. Drop scale_validation column from devices table
+ALTER TABLE devices
+    DROP COLUMN IF EXISTS scale_validation;
@@ -0,0 +1,6 @@
+-- 1. how readings are checked against the device scales (off, reject, dead_letter, lenient)
+ALTER TABLE devices
+    ADD COLUMN scale_validation TEXT NOT NULL DEFAULT 'off';
+
+-- 2. index for the scale lookup on ingestion
+CREATE INDEX idx_scales_device_id ON scales(device_id) WHERE deleted_at IS NULL;
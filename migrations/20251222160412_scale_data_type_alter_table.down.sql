@@ -0,0 +1,3 @@
+-- 1. Drop data_type column from scales table
+ALTER TABLE scales
+    DROP COLUMN IF EXISTS data_type;
@@ -0,0 +1,3 @@
+-- 1. declared data type of a metric (double, int, bool, string), NULL infers it from the value
+ALTER TABLE scales
+    ADD COLUMN data_type VARCHAR(20);
@@ -0,0 +1,4 @@
+-- 1. Drop retention columns from devices table
+ALTER TABLE devices
+    DROP COLUMN IF EXISTS retention_raw_days,
+    DROP COLUMN IF EXISTS retention_rollup_days;
@@ -0,0 +1,4 @@
+-- 1. retention of raw readings and hourly rollups in days, NULL uses the global setting, 0 keeps forever
+ALTER TABLE devices
+    ADD COLUMN retention_raw_days INTEGER CHECK (retention_raw_days >= 0),
+    ADD COLUMN retention_rollup_days INTEGER CHECK (retention_rollup_days >= 0);
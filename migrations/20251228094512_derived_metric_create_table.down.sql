@@ -0,0 +1,8 @@
+-- 1. Drop trigger
+DROP TRIGGER IF EXISTS set_updated_at_derived_metrics ON derived_metrics;
+
+-- 2. Drop index
+DROP INDEX IF EXISTS idx_derived_metrics_device_metric;
+
+-- 3. Drop derived_metrics table
+DROP TABLE IF EXISTS derived_metrics;
@@ -0,0 +1,23 @@
+-- 1. create derived_metrics table, metrics computed at ingestion from the other metrics of a device
+CREATE TABLE derived_metrics (
+    id SERIAL PRIMARY KEY,
+    uuid UUID NOT NULL UNIQUE,
+    device_id INT NOT NULL REFERENCES devices(id),
+    metric VARCHAR(255) NOT NULL,
+    expression VARCHAR(512) NOT NULL,
+    unit VARCHAR(255) NOT NULL,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMPTZ,
+    deleted_at TIMESTAMPTZ
+);
+
+-- 2. one active derived metric per device and name
+CREATE UNIQUE INDEX idx_derived_metrics_device_metric
+    ON derived_metrics (device_id, metric)
+    WHERE deleted_at IS NULL;
+
+-- 3. Trigger update updated_at
+CREATE TRIGGER set_updated_at_derived_metrics
+    BEFORE UPDATE ON derived_metrics
+    FOR EACH ROW
+    EXECUTE FUNCTION trigger_set_updated_at();
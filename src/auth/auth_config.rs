@@ -8,6 +8,7 @@ pub struct AuthConfig {
     iss_claims: String,
     public_key_path: String,
     private_key_path: String,
+    admin_emails: Vec<String>,
 }
 
 impl AuthConfig {
@@ -32,6 +33,13 @@ impl AuthConfig {
 
             private_key_path: std::env::var("PRIVATE_KEY_PATH")
                 .expect("PRIVATE_KEY_PATH must be specified"),
+
+            admin_emails: std::env::var("ADMIN_EMAILS")
+                .unwrap_or_default()
+                .split(',')
+                .map(|email| email.trim().to_lowercase())
+                .filter(|email| !email.is_empty())
+                .collect(),
         }
     }
 
@@ -59,6 +67,11 @@ impl AuthConfig {
         &AUTH_CONFIG.private_key_path
     }
 
+    /// Users allowed on the admin endpoints, listed by email in `ADMIN_EMAILS`.
+    pub fn is_admin(email: &str) -> bool {
+        AUTH_CONFIG.admin_emails.iter().any(|admin| admin == &email.to_lowercase())
+    }
+
     pub async fn set_auth_keys(private_key_path: &str, public_key_path: &str) {
 
         match JwtPath::set_private_key_path(private_key_path){
@@ -1,3 +1,4 @@
+use actix_web::HttpRequest;
 use jwt_lib::jwt_decode;
 use scrypt::password_hash::{PasswordHash, PasswordVerifier};
 use scrypt::Scrypt;
@@ -36,6 +37,27 @@ pub fn verify_password(password: &str, password_hash: &str) -> Result<(), AppErr
     }
 }
 
+/// Bearer token of a request, from the Authorization header or from the
+/// `token` query parameter for clients that cannot set headers (WebSocket, SSE).
+pub fn request_token(req: &HttpRequest, query_token: Option<&str>) -> Result<String, AppError> {
+
+    if let Some(auth) = req.headers().get("Authorization").and_then(|auth| auth.to_str().ok()) {
+        return Ok(auth.replace("Bearer ", ""));
+    }
+
+    match query_token {
+        Some(token) if !token.is_empty() => Ok(token.to_string()),
+        _ => Err(
+            AppError::Unauthorized(
+                AppMsgError{
+                    api_msg_error: "Unauthorized".to_string(),
+                    log_msg_error: format!("file: {}, line: {}, Authorization is empty", file!(), line!()),
+                }
+            )
+        )?
+    }
+}
+
 pub async fn token_info(token: String) -> Result<Token, AppError>{
 
     let algorithm = AuthConfig::get_algorithm();
@@ -0,0 +1,73 @@
+use std::time::Duration;
+use once_cell::sync::Lazy;
+
+pub struct BrokerReconnectConfig {
+    min_delay_ms: u64,
+    max_delay_ms: u64,
+    max_attempts: u32,
+}
+
+impl BrokerReconnectConfig {
+    pub fn init_broker_reconnect_config() -> BrokerReconnectConfig {
+        BrokerReconnectConfig {
+            min_delay_ms: std::env::var("BROKER_RECONNECT_MIN_DELAY_MS")
+                .unwrap_or_else(|_| "1000".to_string())
+                .parse()
+                .expect("BROKER_RECONNECT_MIN_DELAY_MS must be a number"),
+
+            max_delay_ms: std::env::var("BROKER_RECONNECT_MAX_DELAY_MS")
+                .unwrap_or_else(|_| "60000".to_string())
+                .parse()
+                .expect("BROKER_RECONNECT_MAX_DELAY_MS must be a number"),
+
+            max_attempts: std::env::var("BROKER_RECONNECT_MAX_ATTEMPTS")
+                .unwrap_or_else(|_| "0".to_string())
+                .parse()
+                .expect("BROKER_RECONNECT_MAX_ATTEMPTS must be a number"),
+        }
+    }
+
+    /// 0 means retry forever.
+    pub fn get_max_attempts() -> u32 {
+        BROKER_RECONNECT_CONFIG.max_attempts
+    }
+
+    /// Exponential backoff with equal jitter: half of the capped delay is fixed,
+    /// the other half is random, so brokers restarted together don't retry in lockstep.
+    pub fn get_delay(attempt: u32) -> Duration {
+        let config = &*BROKER_RECONNECT_CONFIG;
+
+        let exp = config.min_delay_ms
+            .saturating_mul(1u64 << attempt.saturating_sub(1).min(32));
+        let capped = exp.min(config.max_delay_ms).max(1);
+
+        let half = capped / 2;
+        let jitter = rand::random_range(0..=capped - half);
+
+        Duration::from_millis(half + jitter)
+    }
+}
+
+static BROKER_RECONNECT_CONFIG: Lazy<BrokerReconnectConfig> = Lazy::new(BrokerReconnectConfig::init_broker_reconnect_config);
+
+
+pub struct BrokerShutdownConfig {
+    timeout_secs: u64,
+}
+
+impl BrokerShutdownConfig {
+    pub fn init_broker_shutdown_config() -> BrokerShutdownConfig {
+        BrokerShutdownConfig {
+            timeout_secs: std::env::var("BROKER_SHUTDOWN_TIMEOUT_SECS")
+                .unwrap_or_else(|_| "30".to_string())
+                .parse()
+                .expect("BROKER_SHUTDOWN_TIMEOUT_SECS must be a number"),
+        }
+    }
+
+    pub fn get_timeout() -> Duration {
+        Duration::from_secs(BROKER_SHUTDOWN_CONFIG.timeout_secs)
+    }
+}
+
+static BROKER_SHUTDOWN_CONFIG: Lazy<BrokerShutdownConfig> = Lazy::new(BrokerShutdownConfig::init_broker_shutdown_config);
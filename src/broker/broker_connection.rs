@@ -1,17 +1,24 @@
 use std::sync::Arc;
 use actix_web::web;
-use log::info;
-use crate::broker::broker_model::{BrokerCommand, BrokerHandle, BrokerManager, BrokerResponse};
+use log::{error, info};
+use crate::broker::broker_model::{BrokerCommand, BrokerHandle, BrokerManager, BrokerResponse, BrokerStats};
 use futures::stream::StreamExt;
 use mongodb::Client;
 use sqlx::PgPool;
-use tokio::sync::mpsc;
-use tokio::time::{sleep, Duration};
+use chrono::Utc;
+use tokio::sync::{mpsc, Mutex};
+use tokio::time::sleep;
 use tokio_util::sync::CancellationToken;
-use crate::broker::broker_tool::{broker_change_state, build_subscribe_all_topics_qoss, create_client, create_connection_options, create_options};
-use crate::data_store::data_store_device_handler::put_device_collection;
+use uuid::Uuid;
+use crate::broker::broker_config::{BrokerReconnectConfig, BrokerShutdownConfig};
+use crate::broker::broker_query::{get_broker_secret_query, get_brokers_auto_connect_query, put_broker_reconnect_query};
+use crate::broker::broker_tool::{broker_change_state, broker_publish_state, build_subscribe_all_topics_qoss, build_subscribe_map_topics_qoss, create_client, create_connection_options, create_options};
 use crate::device::device_message_query::get_device_message_subscribe_query;
+use crate::device::device_query::get_unbound_device_uuids_query;
 use crate::error_app::error_app::{AppError, AppMsgInfError};
+use crate::ingestion::ingestion_queue::IngestionQueue;
+use crate::ingestion::ingestion_worker::spawn_ingestion_workers;
+use crate::realtime::realtime_model::BrokerState;
 
 
 pub async fn connect(
@@ -21,8 +28,10 @@ pub async fn connect(
     manager: web::Data<BrokerManager>,
 )-> Result<(), AppError> {
 
+    let secret = get_broker_secret_query(pool, &broker.uuid).await?;
+
     let options = create_options(&broker);
-    let connection_options = create_connection_options(&broker).await?;
+    let connection_options = create_connection_options(&broker, secret.as_ref()).await?;
     let mut cli = create_client(options).await?;
     let mut stream = cli.get_stream(25);
 
@@ -40,7 +49,7 @@ pub async fn connect(
         line!()
     );
 
-    let subscribers = get_device_message_subscribe_query(pool).await?;
+    let subscribers = get_device_message_subscribe_query(pool, &broker.uuid).await?;
     info!("file: {}, line: {}: Subscribers: {:?}", file!(), line!(), subscribers);
 
     let subs = build_subscribe_all_topics_qoss(subscribers.clone());
@@ -60,11 +69,24 @@ pub async fn connect(
     let cancel_child = cancel_token.child_token();
     let (cmd_tx, cmd_rx) = mpsc::channel::<BrokerCommand>(32);
 
+    let stats = Arc::new(Mutex::new(BrokerStats {
+        connected_at: Some(Utc::now()),
+        subscriptions: subscribers
+            .iter()
+            .map(|s| (s.topic.clone(), s.qos))
+            .collect(),
+        ..Default::default()
+    }));
+
+    let ingestion = Arc::new(IngestionQueue::new(&broker.uuid));
+
     let client = Arc::new(cli);
     let handle = BrokerHandle {
         cancel_token: cancel_token.clone(),
         client: client.clone(),
         cmd_tx,
+        stats: stats.clone(),
+        ingestion: ingestion.clone(),
     };
 
     manager.insert(broker.uuid, handle).await;
@@ -76,51 +98,24 @@ pub async fn connect(
 
     let broker_uuid = broker.uuid;
 
-    tokio::spawn({
+    spawn_ingestion_workers(broker_uuid, ingestion.clone(), pool.clone(), mongo_db, stats.clone(), &manager);
+
+    manager.spawn({
         let manager = manager.clone();
         let client = client.clone();
         let pool = pool.clone();
         let broker_uuid = broker_uuid;
-        let subscribers = subscribers.clone();
-        let subs = subs.clone();
+        let stats = stats.clone();
         let cancel_child = cancel_child.clone();
         let mut cmd_rx = cmd_rx;
-        let mongo_db = mongo_db;
+        let ingestion = ingestion.clone();
 
         async move {
-            let mut reconnect_attempt = 0;
-
             loop {
                 tokio::select! {
                     Some(cmd) = cmd_rx.recv() => {
-                    match cmd {
-                        BrokerCommand::Subscribe { topic, qos } => {
-                            if let Err(err) = client.subscribe(&topic, qos).await {
-                                info!("file: {}, line: {}: Failed to subscribe: {}, topic: {}, qos: {}",
-                                   file!(),
-                                   line!(),
-                                   topic,
-                                   qos,
-                                   err
-                                );
-                            } else {
-                                info!("Subscribed to topic: {}, qos: {}", topic, qos);
-                            }
-                        }
-                        BrokerCommand::Unsubscribe { topic } => {
-                            if let Err(err) = client.unsubscribe(&topic).await {
-                                info!("file: {}, line: {}: Failed to unsubscribe: {}: topic: {}",
-                                   file!(),
-                                   line!(),
-                                   err,
-                                   topic,
-                                );
-                            } else {
-                                info!("Unsubscribed from {}", topic);
-                            }
-                        }
+                        handle_broker_command(&client, &stats, cmd).await;
                     }
-                }
 
                 _ = cancel_child.cancelled() => {
                     info!("file: {}, line: {}: Request disconnecting broker {}",
@@ -136,7 +131,7 @@ pub async fn connect(
                             err
                         );
                     }
-                    // let _ = broker_change_state(broker_uuid, false, &pool, true).await;
+                    let _ = broker_change_state(&broker_uuid, false, &pool, true).await;
                     break;
                 }
 
@@ -144,21 +139,98 @@ pub async fn connect(
                         match msg_opt {
                             Some(Some(msg)) => {
                                 info!("📥 MQTT message received: {}", msg);
-                                put_device_collection(mongo_db.clone(), &msg).await;
+                                stats.lock().await.last_message_at = Some(Utc::now());
+
+                                // a blocked push keeps serving commands, and gives up
+                                // the message on cancel so the disconnect runs next
+                                let push = ingestion.push(msg);
+                                tokio::pin!(push);
+
+                                loop {
+                                    tokio::select! {
+                                        _ = &mut push => break,
+                                        _ = cancel_child.cancelled() => break,
+                                        Some(cmd) = cmd_rx.recv() => {
+                                            handle_broker_command(&client, &stats, cmd).await;
+                                        }
+                                    }
+                                }
                             }
                             Some(None) => {
                                 info!("Lost connection. Attempting reconnect...");
-                                while let Err(err) = client.reconnect().await {
+                                let _ = broker_change_state(&broker_uuid, false, &pool, true).await;
+                                broker_publish_state(&broker_uuid, BrokerState::Reconnecting, &pool).await;
+
+                                let max_attempts = BrokerReconnectConfig::get_max_attempts();
+                                let mut reconnect_attempt: u32 = 0;
+                                let mut last_error: Option<String> = None;
+                                let mut cancelled = false;
+
+                                // recorded once per transition, the live attempt count is in BrokerStats
+                                let _ = put_broker_reconnect_query(&pool, &broker_uuid, false, 0, None).await;
+
+                                let reconnected = loop {
+                                    if max_attempts > 0 && reconnect_attempt >= max_attempts {
+                                        break false;
+                                    }
+
                                     reconnect_attempt += 1;
-                                    info!("Reconnect attempt #{} failed: {}", reconnect_attempt, err);
-                                    sleep(Duration::from_secs(1)).await;
-                                    let _ = broker_change_state(&broker_uuid, false, &pool, true).await;
+                                    stats.lock().await.reconnect_attempt = reconnect_attempt;
+
+                                    match client.reconnect().await {
+                                        Ok(_) => break true,
+                                        Err(err) => {
+                                            let delay = BrokerReconnectConfig::get_delay(reconnect_attempt);
+                                            info!("Reconnect attempt #{} failed: {}, next attempt in {:?}", reconnect_attempt, err, delay);
+                                            last_error = Some(err.to_string());
+
+                                            cancelled = tokio::select! {
+                                                _ = sleep(delay) => false,
+                                                _ = cancel_child.cancelled() => true,
+                                            };
+
+                                            if cancelled {
+                                                break false;
+                                            }
+                                        }
+                                    }
+                                };
+
+                                if cancelled {
+                                    info!("file: {}, line: {}, Reconnecting broker {} cancelled", file!(), line!(), broker_uuid);
+                                    break;
+                                }
+
+                                let _ = put_broker_reconnect_query(
+                                    &pool,
+                                    &broker_uuid,
+                                    reconnected,
+                                    reconnect_attempt as i32,
+                                    last_error
+                                ).await;
+
+                                if !reconnected {
+                                    error!("file: {}, line: {}, Giving up reconnecting broker {} after {} attempt(s)",
+                                        file!(),
+                                        line!(),
+                                        broker_uuid,
+                                        reconnect_attempt
+                                    );
+                                    break;
                                 }
 
-                                let _ = broker_change_state(&broker_uuid, true, &pool, true).await;
                                 info!("file: {}, line: {}, ✅ Reconnected.", file!(), line!());
+                                broker_publish_state(&broker_uuid, BrokerState::Connected, &pool).await;
+
+                                let subs = {
+                                    let mut stats = stats.lock().await;
+                                    stats.connected_at = Some(Utc::now());
+                                    stats.reconnect_count += 1;
+                                    stats.reconnect_attempt = 0;
+                                    build_subscribe_map_topics_qoss(&stats.subscriptions)
+                                };
 
-                                if !subscribers.is_empty() {
+                                if !subs.topics.is_empty() {
                                     if let Err(err) = client.subscribe_many(&subs.topics, &subs.qoss).await {
                                         info!("Resubscribe failed: {}", err);
                                     } else {
@@ -177,9 +249,261 @@ pub async fn connect(
 
             // 🔴 Cleanup no final da task
             info!("🧹 Cleaning up broker {}", broker_uuid);
+            ingestion.close();
             manager.remove(&broker_uuid).await;
         }
     });
 
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Runs a command sent to the broker task by the handlers.
+async fn handle_broker_command(
+    client: &paho_mqtt::AsyncClient,
+    stats: &Mutex<BrokerStats>,
+    cmd: BrokerCommand,
+) {
+    match cmd {
+        BrokerCommand::Subscribe { topic, qos } => {
+            if let Err(err) = client.subscribe(&topic, qos).await {
+                info!("file: {}, line: {}: Failed to subscribe: {}, topic: {}, qos: {}",
+                   file!(),
+                   line!(),
+                   err,
+                   topic,
+                   qos
+                );
+            } else {
+                info!("Subscribed to topic: {}, qos: {}", topic, qos);
+                stats.lock().await.subscriptions.insert(topic, qos);
+            }
+        }
+        BrokerCommand::Unsubscribe { topic } => {
+            if let Err(err) = client.unsubscribe(&topic).await {
+                info!("file: {}, line: {}: Failed to unsubscribe: {}: topic: {}",
+                   file!(),
+                   line!(),
+                   err,
+                   topic,
+                );
+            } else {
+                info!("Unsubscribed from {}", topic);
+                stats.lock().await.subscriptions.remove(&topic);
+            }
+        }
+        BrokerCommand::Publish { message, resp } => {
+            let topic = message.topic().to_string();
+            let result = client.publish(message).await.map_err(|err| err.to_string());
+
+            match &result {
+                Ok(_) => info!("📤 Published to topic: {}", topic),
+                Err(err) => info!("file: {}, line: {}: Failed to publish: {}: topic: {}",
+                   file!(),
+                   line!(),
+                   err,
+                   topic,
+                ),
+            }
+
+            let _ = resp.send(result);
+        }
+    }
+}
+
+/// Reconnects every broker flagged with `auto_connect` in Postgres.
+/// The `BrokerManager` starts empty after a restart, so without this no
+/// device data is ingested until someone reconnects by hand. A broker that
+/// fails is retried in the background, the others are restored meanwhile.
+pub async fn restore_broker_connections(
+    pool: &PgPool,
+    mongo_db: Client,
+    manager: web::Data<BrokerManager>,
+) -> Result<(), AppError> {
+
+    let brokers = get_brokers_auto_connect_query(pool).await?;
+
+    let unbound = get_unbound_device_uuids_query(pool).await?;
+
+    if !unbound.is_empty() {
+        error!("file: {}, line: {}: {} device(s) not bound to a broker are not ingested: {:?}",
+            file!(),
+            line!(),
+            unbound.len(),
+            unbound
+        );
+    }
+
+    info!("file: {}, line: {}: Restoring {} broker connection(s)",
+        file!(),
+        line!(),
+        brokers.len()
+    );
+
+    for broker in brokers {
+        match connect(pool, mongo_db.clone(), &broker, manager.clone()).await {
+            Ok(_) => {
+                info!("file: {}, line: {}: Broker {} restored",
+                    file!(),
+                    line!(),
+                    broker.uuid
+                );
+
+                mark_broker_restored(&broker.uuid, pool).await;
+            }
+            Err(err) => {
+                error!("file: {}, line: {}: Failed to restore broker {}: {:?}",
+                    file!(),
+                    line!(),
+                    broker.uuid,
+                    err
+                );
+
+                if let Err(err) = broker_change_state(&broker.uuid, false, pool, false).await {
+                    error!("file: {}, line: {}: Failed to mark broker {} disconnected: {:?}",
+                        file!(),
+                        line!(),
+                        broker.uuid,
+                        err
+                    );
+                }
+
+                spawn_restore_retry(pool.clone(), mongo_db.clone(), broker.uuid, manager.clone());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Persists `connected = true` for a restored broker, the shutdown left it
+/// false and the state feed still reports it disconnected.
+async fn mark_broker_restored(broker_uuid: &Uuid, pool: &PgPool) {
+    if let Err(err) = broker_change_state(broker_uuid, true, pool, false).await {
+        error!("file: {}, line: {}: Failed to mark broker {} connected: {:?}",
+            file!(),
+            line!(),
+            broker_uuid,
+            err
+        );
+    }
+}
+
+/// Keeps retrying a broker that could not be restored at startup, with the
+/// `BrokerReconnectConfig` backoff. Stops once the broker is connected, by
+/// this task or by hand, or no longer flagged with `auto_connect`.
+fn spawn_restore_retry(
+    pool: PgPool,
+    mongo_db: Client,
+    broker_uuid: Uuid,
+    manager: web::Data<BrokerManager>,
+) {
+    tokio::spawn(async move {
+        let max_attempts = BrokerReconnectConfig::get_max_attempts();
+        let mut attempt: u32 = 0;
+        let mut last_error: Option<String> = None;
+
+        loop {
+            if max_attempts > 0 && attempt >= max_attempts {
+                error!("file: {}, line: {}: Giving up restoring broker {} after {} attempt(s)",
+                    file!(),
+                    line!(),
+                    broker_uuid,
+                    attempt
+                );
+
+                let _ = put_broker_reconnect_query(
+                    &pool,
+                    &broker_uuid,
+                    false,
+                    attempt as i32,
+                    last_error
+                ).await;
+                break;
+            }
+
+            attempt += 1;
+            sleep(BrokerReconnectConfig::get_delay(attempt)).await;
+
+            if manager.get(&broker_uuid).await.is_some() {
+                break;
+            }
+
+            let broker = match get_brokers_auto_connect_query(&pool).await {
+                Ok(brokers) => match brokers.into_iter().find(|broker| broker.uuid == broker_uuid) {
+                    Some(broker) => broker,
+                    None => break,
+                },
+                Err(err) => {
+                    error!("file: {}, line: {}: Failed to load broker {}: {:?}", file!(), line!(), broker_uuid, err);
+                    continue;
+                }
+            };
+
+            match connect(&pool, mongo_db.clone(), &broker, manager.clone()).await {
+                Ok(_) => {
+                    info!("file: {}, line: {}: Broker {} restored after {} attempt(s)",
+                        file!(),
+                        line!(),
+                        broker_uuid,
+                        attempt
+                    );
+
+                    mark_broker_restored(&broker_uuid, &pool).await;
+                    break;
+                }
+                Err(err) => {
+                    error!("file: {}, line: {}: Restore attempt #{} failed for broker {}: {:?}",
+                        file!(),
+                        line!(),
+                        attempt,
+                        broker_uuid,
+                        err
+                    );
+
+                    last_error = Some(format!("{:?}", err));
+                }
+            }
+        }
+    });
+}
+
+
+/// Drains every managed broker before exit: cancels the broker tasks, which
+/// disconnect the client and persist `connected = false`, then waits for the
+/// ingestion workers to flush their queues into Mongo. `auto_connect` is kept
+/// so the next startup restores them, `restore_broker_connections` persists
+/// `connected = true` again for each broker it restores.
+pub async fn shutdown_broker_connections(
+    pool: &PgPool,
+    manager: web::Data<BrokerManager>,
+) {
+    let brokers = manager.list().await;
+
+    info!("file: {}, line: {}: Shutting down {} broker connection(s)",
+        file!(),
+        line!(),
+        brokers.len()
+    );
+
+    if manager.shutdown(BrokerShutdownConfig::get_timeout()).await {
+        info!("file: {}, line: {}: Broker tasks drained", file!(), line!());
+        return;
+    }
+
+    error!("file: {}, line: {}: Timeout draining broker tasks, forcing disconnect",
+        file!(),
+        line!()
+    );
+
+    for (broker_uuid, handle) in brokers {
+        if let Err(err) = handle.client.disconnect(None).await {
+            error!("file: {}, line: {}: Error while disconnecting broker {}: {}",
+                file!(),
+                line!(),
+                broker_uuid,
+                err
+            );
+        }
+        let _ = broker_change_state(&broker_uuid, false, pool, true).await;
+    }
+}
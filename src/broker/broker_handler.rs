@@ -3,11 +3,11 @@ use log::info;
 use uuid::Uuid;
 use web::Json;
 use crate::broker::broker_model::{BrokerCreate, BrokerFilter, BrokerManager, BrokerUpdate};
-use crate::broker::broker_query::{delete_broker_query, get_broker_count_query, get_broker_query, get_broker_update_check_query, get_broker_with_uuid_query, post_broker_query, put_broker_query};
+use crate::broker::broker_query::{delete_broker_query, get_broker_count_query, get_broker_query, get_broker_update_check_query, get_broker_with_uuid_query, post_broker_query, put_broker_auto_connect_query, put_broker_query};
 use crate::error_app::error_app::{AppError, AppMsgError};
 use crate::state::AppState;
 use crate::broker::broker_connection as mod_broker_connection;
-use crate::broker::broker_tool::broker_change_state;
+use crate::broker::broker_tool::{broker_change_state, validate_broker_tls, validate_broker_v5};
 
 pub async fn broker_create(
     broker: Json<BrokerCreate>,
@@ -16,6 +16,9 @@ pub async fn broker_create(
 
     let broker = broker.into_inner();
 
+    validate_broker_tls(&broker.ca_certificate, &broker.client_certificate, &broker.client_key)?;
+    validate_broker_v5(broker.version, broker.session_expiry_interval, broker.receive_maximum, broker.topic_alias_maximum)?;
+
     let broker_check = get_broker_count_query(&app_state.db, &broker.port)
         .await
         .map_err(|e| e)?;
@@ -66,6 +69,9 @@ pub async fn broker_update(
 
     let broker_uuid = broker_uuid.into_inner();
 
+    validate_broker_tls(&broker_update.ca_certificate, &broker_update.client_certificate, &broker_update.client_key)?;
+    validate_broker_v5(broker_update.version, broker_update.session_expiry_interval, broker_update.receive_maximum, broker_update.topic_alias_maximum)?;
+
     let broker_port = match get_broker_update_check_query(&app_state.db, &broker_uuid, &broker_update)
         .await{
         Ok(result) => result,
@@ -112,13 +118,15 @@ pub async fn broker_connection(
 
     let broker = broker.unwrap();
 
-    if broker.connected{
+    // the manager is the source of truth, the stored flag lags behind restarts
+    if broker_manager.get(&broker.uuid).await.is_some(){
         return Ok(HttpResponse::NoContent().finish())
     };
 
     mod_broker_connection::connect(&app_state.db, app_state.mongo.clone(), &broker, broker_manager.clone()).await?;
 
     broker_change_state(&broker.uuid, true, &app_state.db, false).await?;
+    put_broker_auto_connect_query(&app_state.db, &broker.uuid, true).await?;
 
     Ok(HttpResponse::NoContent().finish())
 }
@@ -142,6 +150,7 @@ pub async fn broker_disconnect(
         handle.cancel_token.cancel();
 
         broker_change_state(&broker_uuid, false, &app_state.db, true).await?;
+        put_broker_auto_connect_query(&app_state.db, &broker_uuid, false).await?;
 
         Ok(HttpResponse::NoContent().finish())
 
@@ -156,4 +165,37 @@ pub async fn broker_disconnect(
             )
         )?
     }
+}
+
+pub async fn broker_status(
+    broker_uuid: web::Path<Uuid>,
+    manager: web::Data<BrokerManager>,
+) -> Result<HttpResponse, AppError>{
+
+    let broker_uuid = broker_uuid.into_inner();
+
+    match manager.get(&broker_uuid).await{
+        Some(handle) => Ok(HttpResponse::Ok().json(handle.status(broker_uuid).await)),
+        None => Err(
+            AppError::NotFound(
+                AppMsgError{
+                    api_msg_error: "Broker not found or not connected".to_string(),
+                    log_msg_error: format!("Broker not found or not connected in BrokerManager, uuid: {}", &broker_uuid)
+                }
+            )
+        )?
+    }
+}
+
+pub async fn broker_status_list(
+    manager: web::Data<BrokerManager>,
+) -> Result<HttpResponse, AppError>{
+
+    let mut result = Vec::new();
+
+    for (broker_uuid, handle) in manager.list().await {
+        result.push(handle.status(broker_uuid).await);
+    }
+
+    Ok(HttpResponse::Ok().json(&result))
 }
\ No newline at end of file
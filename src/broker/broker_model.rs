@@ -1,13 +1,18 @@
 use std::collections::HashMap;
 use std::sync::Arc;
-use tokio::sync::{mpsc, Mutex};
+use tokio::sync::{mpsc, oneshot, Mutex};
 use actix_web::web;
 use actix_web::web::Query;
 use chrono::Utc;
 use mqtt_device::{AsyncClient, AsyncReceiver, Message};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
+use std::future::Future;
+use std::time::Duration;
 use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use crate::ingestion::ingestion_model::IngestionMetricsResponse;
+use crate::ingestion::ingestion_queue::IngestionQueue;
 use crate::paginate::paginate_model::{Pagination, PaginationFrom};
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -33,6 +38,14 @@ pub struct Broker {
     pub last_will_qos: Option<i32>,
     pub last_will_retain: Option<bool>,
     pub connected: Option<bool>,
+    pub tls_enabled: bool,
+    pub verify_hostname: bool,
+    pub username: Option<String>,
+    pub reconnect_attempts: i32,
+    pub last_error: Option<String>,
+    pub session_expiry_interval: Option<i32>,
+    pub receive_maximum: Option<i32>,
+    pub topic_alias_maximum: Option<i32>,
     #[serde(default)]
     pub created_at: Option<chrono::DateTime<Utc>>,
     #[serde(default)]
@@ -53,6 +66,17 @@ pub struct BrokerCreate {
     pub last_will_message: Option<String>,
     pub last_will_qos: Option<i32>,
     pub last_will_retain: Option<bool>,
+    #[serde(default)]
+    pub tls_enabled: bool,
+    pub verify_hostname: Option<bool>,
+    pub ca_certificate: Option<String>,
+    pub client_certificate: Option<String>,
+    pub client_key: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub session_expiry_interval: Option<i32>,
+    pub receive_maximum: Option<i32>,
+    pub topic_alias_maximum: Option<i32>,
 }
 
 impl From<web::Json<BrokerCreate>> for BrokerCreate {
@@ -69,6 +93,16 @@ impl From<web::Json<BrokerCreate>> for BrokerCreate {
             last_will_message: broker.last_will_message.clone(),
             last_will_qos: broker.last_will_qos.clone(),
             last_will_retain: broker.last_will_retain.clone(),
+            tls_enabled: broker.tls_enabled,
+            verify_hostname: broker.verify_hostname,
+            ca_certificate: broker.ca_certificate.clone(),
+            client_certificate: broker.client_certificate.clone(),
+            client_key: broker.client_key.clone(),
+            username: broker.username.clone(),
+            password: broker.password.clone(),
+            session_expiry_interval: broker.session_expiry_interval,
+            receive_maximum: broker.receive_maximum,
+            topic_alias_maximum: broker.topic_alias_maximum,
         }
     }
 }
@@ -88,6 +122,14 @@ pub struct BrokerResponse {
     pub last_will_qos: Option<i32>,
     pub last_will_retain: Option<bool>,
     pub connected: bool,
+    pub tls_enabled: bool,
+    pub verify_hostname: bool,
+    pub username: Option<String>,
+    pub reconnect_attempts: i32,
+    pub last_error: Option<String>,
+    pub session_expiry_interval: Option<i32>,
+    pub receive_maximum: Option<i32>,
+    pub topic_alias_maximum: Option<i32>,
     pub created_at: Option<chrono::DateTime<Utc>>,
     pub updated_at: Option<chrono::DateTime<Utc>>,
     pub deleted_at: Option<chrono::DateTime<Utc>>,
@@ -183,6 +225,18 @@ pub struct BrokerUpdate {
     pub last_will_qos: i32,
     pub last_will_retain: bool,
     pub connected: bool,
+    /// Omitted keeps the stored value, like the TLS material below.
+    pub tls_enabled: Option<bool>,
+    pub verify_hostname: Option<bool>,
+    pub ca_certificate: Option<String>,
+    pub client_certificate: Option<String>,
+    pub client_key: Option<String>,
+    /// Omitted keeps the stored value, an empty string clears it.
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub session_expiry_interval: Option<i32>,
+    pub receive_maximum: Option<i32>,
+    pub topic_alias_maximum: Option<i32>,
 }
 
 impl From<web::Json<BrokerUpdate>> for BrokerUpdate {
@@ -199,14 +253,55 @@ impl From<web::Json<BrokerUpdate>> for BrokerUpdate {
             last_will_qos: broker.last_will_qos.clone(),
             last_will_retain: broker.last_will_retain.clone(),
             connected: broker.connected.clone(),
+            tls_enabled: broker.tls_enabled,
+            verify_hostname: broker.verify_hostname,
+            ca_certificate: broker.ca_certificate.clone(),
+            client_certificate: broker.client_certificate.clone(),
+            client_key: broker.client_key.clone(),
+            username: broker.username.clone(),
+            password: broker.password.clone(),
+            session_expiry_interval: broker.session_expiry_interval,
+            receive_maximum: broker.receive_maximum,
+            topic_alias_maximum: broker.topic_alias_maximum,
         }
     }
 }
 
+/// PEM material and password used to build the paho connect options.
+/// Never serialized, it only travels from Postgres to the connect step.
+/// `client_key` and `password` are stored encrypted, see `broker_secret`.
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct BrokerSecret {
+    pub ca_certificate: Option<String>,
+    pub client_certificate: Option<String>,
+    pub client_key: Option<String>,
+    pub password: Option<String>,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct BrokerClientKey {
+    pub uuid: Uuid,
+    pub client_key: String,
+}
+
 #[derive(Debug)]
 pub enum BrokerCommand {
     Subscribe{topic: String, qos: i32},
     Unsubscribe{topic: String},
+    Publish{message: Message, resp: oneshot::Sender<Result<(), String>>},
+}
+
+/// Runtime counters of a managed broker, updated by the broker task.
+#[derive(Debug, Clone, Default)]
+pub struct BrokerStats {
+    pub connected_at: Option<chrono::DateTime<Utc>>,
+    pub messages_received: u64,
+    pub messages_failed: u64,
+    pub last_message_at: Option<chrono::DateTime<Utc>>,
+    pub subscriptions: HashMap<String, i32>,
+    pub reconnect_count: u64,
+    /// Attempt of the reconnect in progress, 0 while connected.
+    pub reconnect_attempt: u32,
 }
 
 #[derive(Clone)]
@@ -214,6 +309,58 @@ pub struct BrokerHandle {
     pub cancel_token: CancellationToken,
     pub client: Arc<AsyncClient>,
     pub cmd_tx: mpsc::Sender<BrokerCommand>,
+    pub stats: Arc<Mutex<BrokerStats>>,
+    pub ingestion: Arc<IngestionQueue>,
+}
+
+impl BrokerHandle {
+    pub async fn status(&self, broker_uuid: Uuid) -> BrokerStatusResponse {
+        let stats = self.stats.lock().await;
+
+        let uptime_secs = stats.connected_at
+            .map(|connected_at| (Utc::now() - connected_at).num_seconds().max(0));
+
+        let mut subscriptions: Vec<BrokerSubscription> = stats.subscriptions
+            .iter()
+            .map(|(topic, qos)| BrokerSubscription { topic: topic.clone(), qos: *qos })
+            .collect();
+        subscriptions.sort_by(|a, b| a.topic.cmp(&b.topic));
+
+        BrokerStatusResponse {
+            broker_uuid,
+            connected: self.client.is_connected(),
+            connected_at: stats.connected_at,
+            uptime_secs,
+            messages_received: stats.messages_received,
+            messages_failed: stats.messages_failed,
+            last_message_at: stats.last_message_at,
+            subscriptions,
+            reconnect_count: stats.reconnect_count,
+            reconnect_attempt: stats.reconnect_attempt,
+            ingestion: self.ingestion.metrics(),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct BrokerSubscription {
+    pub topic: String,
+    pub qos: i32,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct BrokerStatusResponse {
+    pub broker_uuid: Uuid,
+    pub connected: bool,
+    pub connected_at: Option<chrono::DateTime<Utc>>,
+    pub uptime_secs: Option<i64>,
+    pub messages_received: u64,
+    pub messages_failed: u64,
+    pub last_message_at: Option<chrono::DateTime<Utc>>,
+    pub subscriptions: Vec<BrokerSubscription>,
+    pub reconnect_count: u64,
+    pub reconnect_attempt: u32,
+    pub ingestion: IngestionMetricsResponse,
 }
 
 pub struct BrokerStream{
@@ -224,9 +371,35 @@ pub struct BrokerStream{
 #[derive(Clone, Default)]
 pub struct BrokerManager{
     brokers: Arc<Mutex<HashMap<Uuid, BrokerHandle>>>,
+    tasks: TaskTracker,
 }
 
 impl BrokerManager{
+    /// Spawns a broker task tracked by the manager, so shutdown can wait for it.
+    pub fn spawn<F>(&self, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.tasks.spawn(task);
+    }
+
+    /// Cancels every managed broker and waits for their tasks to finish,
+    /// returns false when the timeout elapsed first.
+    pub async fn shutdown(&self, timeout: Duration) -> bool {
+        for (broker_uuid, handle) in self.list().await {
+            log::info!("file: {}, line: {}: Cancelling broker {}",
+                file!(),
+                line!(),
+                broker_uuid
+            );
+            handle.cancel_token.cancel();
+        }
+
+        self.tasks.close();
+
+        tokio::time::timeout(timeout, self.tasks.wait()).await.is_ok()
+    }
+
     pub async fn insert(&self, broker_uuid: Uuid, handle: BrokerHandle) {
         let mut brokers = self.brokers.lock().await;
         brokers.insert(broker_uuid, handle);
@@ -248,6 +421,13 @@ impl BrokerManager{
         brokers.get(broker_uuid).cloned()
     }
 
+    pub async fn list(&self) -> Vec<(Uuid, BrokerHandle)> {
+        let brokers = self.brokers.lock().await;
+        brokers.iter()
+            .map(|(broker_uuid, handle)| (*broker_uuid, handle.clone()))
+            .collect()
+    }
+
     pub async fn remove(&self, broker_uuid: &Uuid) {
         let mut brokers = self.brokers.lock().await;
         brokers.remove(broker_uuid);
@@ -1,6 +1,7 @@
 use sqlx::{query_scalar, PgPool, QueryBuilder};
 use uuid::Uuid;
-use crate::broker::broker_model::{BrokerCreate, BrokerFilter, BrokerPaginationResponse, BrokerResponse, BrokerUpdate};
+use crate::broker::broker_model::{BrokerClientKey, BrokerCreate, BrokerFilter, BrokerPaginationResponse, BrokerResponse, BrokerSecret, BrokerUpdate};
+use crate::broker::broker_secret::{encrypt_secret_opt, encrypt_secret_update};
 use crate::error_app::error_app::{AppError, AppMsgError};
 use crate::paginate::paginate_model::Pagination;
 
@@ -10,6 +11,9 @@ pub async fn post_broker_query(
     broker_uuid: &Uuid,
 ) -> Result<BrokerResponse, AppError> {
 
+    let client_key = encrypt_secret_opt(&broker.client_key)?;
+    let password = encrypt_secret_opt(&broker.password)?;
+
     let result = sqlx::query_as!(
         BrokerResponse,
         r#"
@@ -24,8 +28,18 @@ pub async fn post_broker_query(
             last_will_topic,
             last_will_message,
             last_will_qos,
-            last_will_retain
-        ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+            last_will_retain,
+            tls_enabled,
+            verify_hostname,
+            ca_certificate,
+            client_certificate,
+            client_key,
+            username,
+            password,
+            session_expiry_interval,
+            receive_maximum,
+            topic_alias_maximum
+        ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18, $19, $20, $21)
         RETURNING
             uuid,
             host,
@@ -40,6 +54,14 @@ pub async fn post_broker_query(
             last_will_qos,
             last_will_retain,
             connected,
+            tls_enabled,
+            verify_hostname,
+            username,
+            reconnect_attempts,
+            last_error,
+            session_expiry_interval,
+            receive_maximum,
+            topic_alias_maximum,
             created_at,
             updated_at,
             deleted_at
@@ -55,6 +77,16 @@ pub async fn post_broker_query(
         broker.last_will_message,
         broker.last_will_qos,
         broker.last_will_retain,
+        broker.tls_enabled,
+        broker.verify_hostname.unwrap_or(true),
+        broker.ca_certificate,
+        broker.client_certificate,
+        client_key,
+        broker.username,
+        password,
+        broker.session_expiry_interval,
+        broker.receive_maximum,
+        broker.topic_alias_maximum,
     )
         .fetch_one(pool)
         .await
@@ -90,6 +122,14 @@ pub async fn get_broker_query(
         last_will_qos,
         last_will_retain,
         connected,
+        tls_enabled,
+        verify_hostname,
+        username,
+        reconnect_attempts,
+        last_error,
+        session_expiry_interval,
+        receive_maximum,
+        topic_alias_maximum,
         created_at,
         updated_at,
         deleted_at
@@ -193,6 +233,14 @@ pub async fn get_broker_with_uuid_query(
             last_will_qos,
             last_will_retain,
             connected,
+            tls_enabled,
+            verify_hostname,
+            username,
+            reconnect_attempts,
+            last_error,
+            session_expiry_interval,
+            receive_maximum,
+            topic_alias_maximum,
             created_at,
             updated_at,
             deleted_at
@@ -279,6 +327,9 @@ pub async fn put_broker_query(
     broker_update: &BrokerUpdate,
 ) -> Result<BrokerResponse, AppError> {
 
+    let client_key = encrypt_secret_opt(&broker_update.client_key)?;
+    let password = encrypt_secret_update(&broker_update.password)?;
+
     match sqlx::query_as!(
         BrokerResponse,
         r#"
@@ -293,8 +344,18 @@ pub async fn put_broker_query(
             last_will_message = $8,
             last_will_qos = $9,
             last_will_retain = $10,
-            connected = $11
-        WHERE uuid = $12
+            connected = $11,
+            tls_enabled = COALESCE($12, tls_enabled),
+            verify_hostname = COALESCE($13, verify_hostname),
+            ca_certificate = COALESCE($14, ca_certificate),
+            client_certificate = COALESCE($15, client_certificate),
+            client_key = COALESCE($16, client_key),
+            username = CASE WHEN $17 = '' THEN NULL ELSE COALESCE($17, username) END,
+            password = CASE WHEN $18 = '' THEN NULL ELSE COALESCE($18, password) END,
+            session_expiry_interval = $19,
+            receive_maximum = $20,
+            topic_alias_maximum = $21
+        WHERE uuid = $22
         RETURNING
             uuid,
             host,
@@ -309,6 +370,14 @@ pub async fn put_broker_query(
             last_will_qos,
             last_will_retain,
             connected,
+            tls_enabled,
+            verify_hostname,
+            username,
+            reconnect_attempts,
+            last_error,
+            session_expiry_interval,
+            receive_maximum,
+            topic_alias_maximum,
             created_at,
             updated_at,
             deleted_at
@@ -324,6 +393,16 @@ pub async fn put_broker_query(
         broker_update.last_will_qos,
         broker_update.last_will_retain,
         broker_update.connected,
+        broker_update.tls_enabled,
+        broker_update.verify_hostname,
+        broker_update.ca_certificate,
+        broker_update.client_certificate,
+        client_key,
+        broker_update.username,
+        password,
+        broker_update.session_expiry_interval,
+        broker_update.receive_maximum,
+        broker_update.topic_alias_maximum,
         broker_uuid
     ).fetch_one(pool).await{
         Ok(result) => Ok(result),
@@ -372,9 +451,9 @@ pub async fn get_broker_count_total_query(pool: &PgPool) -> Result<i64, AppError
         }
 }
 
-pub async fn get_broker_connected_query(
+pub async fn get_brokers_auto_connect_query(
     pool: &PgPool,
-) -> Result<Option<BrokerResponse>, AppError> {
+) -> Result<Vec<BrokerResponse>, AppError> {
 
     match sqlx::query_as!(
         BrokerResponse,
@@ -393,17 +472,206 @@ pub async fn get_broker_connected_query(
             last_will_qos,
             last_will_retain,
             connected,
+            tls_enabled,
+            verify_hostname,
+            username,
+            reconnect_attempts,
+            last_error,
+            session_expiry_interval,
+            receive_maximum,
+            topic_alias_maximum,
             created_at,
             updated_at,
             deleted_at
             FROM brokers
-            WHERE connected = true
+            WHERE auto_connect = true
             AND deleted_at IS NULL
-            LIMIT 1
+            ORDER BY id ASC
         "#
-    ).fetch_optional(pool)
+    ).fetch_all(pool)
         .await{
             Ok(result) => Ok(result),
             Err(error) => Err(AppError::DBError(error.to_string()))?
         }
-}
\ No newline at end of file
+}
+
+pub async fn get_broker_secret_query(
+    pool: &PgPool,
+    broker_uuid: &Uuid,
+) -> Result<Option<BrokerSecret>, AppError> {
+
+    match sqlx::query_as!(
+        BrokerSecret,
+        r#"
+        SELECT
+            ca_certificate,
+            client_certificate,
+            client_key,
+            password
+            FROM brokers
+            WHERE deleted_at IS NULL
+            AND uuid = $1
+        "#,
+        broker_uuid
+    ).fetch_optional(pool).await{
+        Ok(result) => Ok(result),
+        Err(err) => Err(
+            AppError::DBError(err.to_string()))?
+    }
+}
+
+/// Client keys stored before they were encrypted, recognized by their PEM
+/// header, base64 output never contains one.
+pub async fn get_broker_plaintext_client_keys_query(
+    pool: &PgPool,
+) -> Result<Vec<BrokerClientKey>, AppError> {
+
+    match sqlx::query_as!(
+        BrokerClientKey,
+        r#"
+        SELECT
+            uuid,
+            client_key as "client_key!"
+            FROM brokers
+            WHERE client_key LIKE '%-----BEGIN%'
+        "#
+    ).fetch_all(pool).await{
+        Ok(result) => Ok(result),
+        Err(err) => Err(
+            AppError::DBError(err.to_string()))?
+    }
+}
+
+pub async fn put_broker_client_key_query(
+    pool: &PgPool,
+    broker_uuid: &Uuid,
+    client_key: &str,
+) -> Result<(), AppError> {
+
+    match sqlx::query!(
+        "UPDATE brokers SET client_key = $1 WHERE uuid = $2",
+        client_key,
+        broker_uuid
+    ).execute(pool).await{
+        Ok(_) => Ok(()),
+        Err(err) => Err(
+            AppError::DBError(err.to_string()))?
+    }
+}
+
+pub async fn put_broker_reconnect_query(
+    pool: &PgPool,
+    broker_uuid: &Uuid,
+    connected: bool,
+    reconnect_attempts: i32,
+    last_error: Option<String>,
+) -> Result<(), AppError> {
+
+    match sqlx::query!(
+        r#"
+        UPDATE brokers
+        SET connected = $1,
+            reconnect_attempts = $2,
+            last_error = $3
+        WHERE uuid = $4
+        "#,
+        connected,
+        reconnect_attempts,
+        last_error,
+        broker_uuid
+    ).execute(pool).await
+    {
+        Ok(_) => Ok(()),
+        Err(e) => Err(AppError::DBError(e.to_string())),
+    }
+}
+
+pub async fn get_broker_with_device_id_query(
+    pool: &PgPool,
+    device_id: i32,
+) -> Result<Option<BrokerResponse>, AppError> {
+
+    match sqlx::query_as!(
+        BrokerResponse,
+        r#"
+        SELECT
+            b.uuid,
+            b.host,
+            b.port,
+            b.client_id,
+            b.version,
+            b.version_text as "version_text!: String",
+            b.keep_alive,
+            b.clean_session,
+            b.last_will_topic,
+            b.last_will_message,
+            b.last_will_qos,
+            b.last_will_retain,
+            b.connected,
+            b.tls_enabled,
+            b.verify_hostname,
+            b.username,
+            b.reconnect_attempts,
+            b.last_error,
+            b.session_expiry_interval,
+            b.receive_maximum,
+            b.topic_alias_maximum,
+            b.created_at,
+            b.updated_at,
+            b.deleted_at
+            FROM brokers b
+            INNER JOIN devices d ON d.broker_id = b.id
+            WHERE b.deleted_at IS NULL
+            AND d.id = $1
+        "#,
+        device_id
+    ).fetch_optional(pool).await{
+        Ok(result) => Ok(result),
+        Err(err) => Err(
+            AppError::DBError(err.to_string()))?
+    }
+}
+
+pub async fn put_broker_auto_connect_query(
+    pool: &PgPool,
+    broker_uuid: &Uuid,
+    auto_connect: bool,
+) -> Result<(), AppError> {
+
+    match sqlx::query!(
+        r#"
+        UPDATE brokers
+        SET auto_connect = $1
+        WHERE uuid = $2
+        "#,
+        auto_connect,
+        broker_uuid
+    ).execute(pool).await
+    {
+        Ok(_) => Ok(()),
+        Err(e) => Err(AppError::DBError(e.to_string())),
+    }
+}
+
+/// Users owning a device on the broker, the audience of its state changes.
+pub async fn get_broker_user_uuids_query(
+    pool: &PgPool,
+    broker_uuid: &Uuid,
+) -> Result<Vec<Uuid>, AppError> {
+
+    match sqlx::query_scalar!(
+        r#"
+        SELECT DISTINCT u.uuid
+        FROM devices d
+        JOIN users u ON u.id = d.user_id
+        JOIN brokers b ON b.id = d.broker_id
+        WHERE b.uuid = $1
+          AND d.deleted_at IS NULL
+        "#,
+        broker_uuid
+    ).fetch_all(pool)
+        .await {
+        Ok(user_uuids) => Ok(user_uuids),
+        Err(e) => Err(AppError::DBError(e.to_string())),
+    }
+}
@@ -1,7 +1,7 @@
 use actix_web::middleware::from_fn;
 use actix_web::web;
 use crate::auth;
-use crate::broker::broker_handler::{broker_connection, broker_create, broker_get_filter, broker_delete, broker_update, broker_disconnect};
+use crate::broker::broker_handler::{broker_connection, broker_create, broker_get_filter, broker_delete, broker_update, broker_disconnect, broker_status, broker_status_list};
 
 pub fn broker_cfg(cfg: &mut web::ServiceConfig) {
     cfg.service(
@@ -9,6 +9,8 @@ pub fn broker_cfg(cfg: &mut web::ServiceConfig) {
             .wrap(from_fn(auth::auth_midlleware::auth_middleware))
             .route("", web::post().to(broker_create))
             .route("", web::get().to(broker_get_filter))
+            .route("/status", web::get().to(broker_status_list))
+            .route("/{uuid}/status", web::get().to(broker_status))
             .route("/{uuid}", web::delete().to(broker_delete))
             .route("/{uuid}", web::put().to(broker_update))
             .route("/connection/{uuid}", web::post().to(broker_connection))
@@ -0,0 +1,117 @@
+use once_cell::sync::Lazy;
+use openssl::base64::{decode_block, encode_block};
+use openssl::rand::rand_bytes;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use crate::error_app::error_app::AppError;
+
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+pub struct BrokerSecretConfig {
+    secret_key: Vec<u8>,
+}
+
+impl BrokerSecretConfig {
+    pub fn init_broker_secret_config() -> BrokerSecretConfig {
+
+        let secret_key = decode_block(
+            &std::env::var("BROKER_SECRET_KEY")
+                .expect("BROKER_SECRET_KEY must be specified")
+        ).expect("BROKER_SECRET_KEY must be base64 encoded");
+
+        if secret_key.len() != 32 {
+            panic!("BROKER_SECRET_KEY must be a base64 encoded 32 bytes key");
+        }
+
+        BrokerSecretConfig {
+            secret_key,
+        }
+    }
+
+    /// Loads the key now, so a missing or invalid key stops the server at
+    /// startup instead of failing the first broker connect.
+    pub fn load() {
+        Lazy::force(&BROKER_SECRET_CONFIG);
+    }
+
+    pub fn get_secret_key() -> &'static [u8] {
+        &BROKER_SECRET_CONFIG.secret_key
+    }
+}
+
+static BROKER_SECRET_CONFIG: Lazy<BrokerSecretConfig> = Lazy::new(BrokerSecretConfig::init_broker_secret_config);
+
+/// Encrypts a broker secret with AES-256-GCM.
+/// The stored value is base64(nonce | ciphertext | tag).
+pub fn encrypt_secret(plain: &str) -> Result<String, AppError> {
+
+    let mut nonce = [0u8; NONCE_LEN];
+    rand_bytes(&mut nonce)
+        .map_err(|err| AppError::InternalServerError(format!("file: {}, line: {}, {}", file!(), line!(), err)))?;
+
+    let mut tag = [0u8; TAG_LEN];
+
+    let cipher_text = encrypt_aead(
+        Cipher::aes_256_gcm(),
+        BrokerSecretConfig::get_secret_key(),
+        Some(&nonce),
+        &[],
+        plain.as_bytes(),
+        &mut tag,
+    ).map_err(|err| AppError::InternalServerError(format!("file: {}, line: {}, {}", file!(), line!(), err)))?;
+
+    let mut sealed = Vec::with_capacity(NONCE_LEN + cipher_text.len() + TAG_LEN);
+    sealed.extend_from_slice(&nonce);
+    sealed.extend_from_slice(&cipher_text);
+    sealed.extend_from_slice(&tag);
+
+    Ok(encode_block(&sealed))
+}
+
+pub fn decrypt_secret(sealed: &str) -> Result<String, AppError> {
+
+    let sealed = decode_block(sealed)
+        .map_err(|err| AppError::InternalServerError(format!("file: {}, line: {}, {}", file!(), line!(), err)))?;
+
+    if sealed.len() < NONCE_LEN + TAG_LEN {
+        Err(AppError::InternalServerError(format!("file: {}, line: {}, Invalid broker secret", file!(), line!())))?
+    }
+
+    let (nonce, rest) = sealed.split_at(NONCE_LEN);
+    let (cipher_text, tag) = rest.split_at(rest.len() - TAG_LEN);
+
+    let plain = decrypt_aead(
+        Cipher::aes_256_gcm(),
+        BrokerSecretConfig::get_secret_key(),
+        Some(nonce),
+        &[],
+        cipher_text,
+        tag,
+    ).map_err(|err| AppError::InternalServerError(format!("file: {}, line: {}, {}", file!(), line!(), err)))?;
+
+    String::from_utf8(plain)
+        .map_err(|err| AppError::InternalServerError(format!("file: {}, line: {}, {}", file!(), line!(), err)))
+}
+
+/// Encrypts a secret of a broker update, an empty string is kept as is so
+/// the update clears the stored secret.
+pub fn encrypt_secret_update(plain: &Option<String>) -> Result<Option<String>, AppError> {
+    match plain.as_deref() {
+        Some("") => Ok(Some(String::new())),
+        _ => encrypt_secret_opt(plain),
+    }
+}
+
+pub fn encrypt_secret_opt(plain: &Option<String>) -> Result<Option<String>, AppError> {
+    match plain {
+        Some(plain) => Ok(Some(encrypt_secret(plain)?)),
+        None => Ok(None),
+    }
+}
+
+pub fn decrypt_secret_opt(sealed: &Option<String>) -> Result<Option<String>, AppError> {
+    match sealed {
+        Some(sealed) => Ok(Some(decrypt_secret(sealed)?)),
+        None => Ok(None),
+    }
+}
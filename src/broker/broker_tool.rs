@@ -1,60 +1,198 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::time::Duration;
 use actix_web::web;
-use log::error;
+use log::{error, info};
 use mqtt_device::AsyncClient;
-use mqtt_device::components::will::Will;
-use mqtt_device::create_connection_options::ConnectionOptions;
-use mqtt_device::create_options::Options;
+use openssl::pkey::PKey;
+use openssl::x509::X509;
 use sqlx::PgPool;
+use tokio::sync::oneshot;
 use uuid::Uuid;
-use crate::broker::broker_model::{BrokerCommand, BrokerManager, BrokerResponse};
-use crate::broker::broker_query::{get_broker_with_uuid_query, put_broker_state_query};
-use crate::device::device_message_model::{DeviceMessageSubscribe, MessageReceivePayload, SubscribeTopicQos};
+use crate::broker::broker_model::{BrokerCommand, BrokerManager, BrokerResponse, BrokerSecret};
+use crate::broker::broker_secret::{decrypt_secret_opt, encrypt_secret};
+use crate::broker::broker_query::{get_broker_plaintext_client_keys_query, get_broker_user_uuids_query, get_broker_with_uuid_query, put_broker_client_key_query, put_broker_state_query};
+use crate::device::device_message_model::{DeviceMessageSubscribe, MessageReceivePayload, MessageReceiveProperties, SubscribeTopicQos};
 use crate::error_app::error_app::{AppError, AppMsgError, AppMsgInfError};
+use crate::realtime::realtime_hub::publish_state;
+use crate::realtime::realtime_model::{BrokerState, StateChange};
 
-pub fn create_options(broker: &BrokerResponse) -> Options {
-    Options{
-        server_host: broker.host.clone(),
-        server_port: broker.port as u32,
-        client_id: broker.client_id.clone(),
-        version: broker.version,
+pub fn create_server_uri(broker: &BrokerResponse) -> String {
+    let scheme = if broker.tls_enabled { "ssl" } else { "tcp" };
+    format!("{}://{}:{}", scheme, broker.host, broker.port)
+}
+
+pub fn create_options(broker: &BrokerResponse) -> paho_mqtt::CreateOptions {
+    paho_mqtt::CreateOptionsBuilder::new()
+        .server_uri(create_server_uri(broker))
+        .client_id(broker.client_id.clone())
+        .mqtt_version(broker.version as u32)
+        .finalize()
+}
+
+pub fn create_last_will(broker: &BrokerResponse) -> Option<paho_mqtt::Message> {
+    match (&broker.last_will_topic, &broker.last_will_message) {
+        (Some(topic), Some(payload)) => Some(
+            paho_mqtt::MessageBuilder::new()
+                .topic(topic.clone())
+                .payload(payload.clone())
+                .qos(broker.last_will_qos.unwrap_or(0))
+                .retained(broker.last_will_retain.unwrap_or(false))
+                .finalize()
+        ),
+        _ => None,
     }
 }
 
-pub fn create_last_will(broker: &BrokerResponse) -> Will {
-    Will{
-        topic: broker.last_will_topic.clone().unwrap(),
-        payload: broker.last_will_message.clone().unwrap(),
-        qos: broker.last_will_qos.clone().unwrap(),
-        retained: broker.last_will_retain.clone().unwrap(),
+fn write_tls_file(broker_uuid: &Uuid, name: &str, pem: &str) -> Result<PathBuf, AppError> {
+
+    let dir = std::env::var("BROKER_TLS_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| std::env::temp_dir().join("device_app_tls"));
+
+    let tls_err = |err: std::io::Error| AppError::MqttError(AppMsgInfError {
+        file: file!().to_string(),
+        line: line!(),
+        api_msg_error: "MqttError".into(),
+        log_msg_error: format!("Failed to write tls file {}: {}", name, err),
+    });
+
+    fs::create_dir_all(&dir).map_err(tls_err)?;
+
+    let path = dir.join(format!("{}_{}.pem", broker_uuid, name));
+
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&path)
+        .map_err(tls_err)?;
+
+    file.write_all(pem.as_bytes()).map_err(tls_err)?;
+
+    Ok(path)
+}
+
+pub fn create_ssl_options(
+    broker: &BrokerResponse,
+    secret: &BrokerSecret,
+) -> Result<paho_mqtt::SslOptions, AppError> {
+
+    let ssl_err = |err: paho_mqtt::Error| AppError::MqttError(AppMsgInfError {
+        file: file!().to_string(),
+        line: line!(),
+        api_msg_error: "MqttError".into(),
+        log_msg_error: err.to_string(),
+    });
+
+    let mut ssl = paho_mqtt::SslOptionsBuilder::new();
+
+    if let Some(ca_certificate) = &secret.ca_certificate {
+        let path = write_tls_file(&broker.uuid, "ca", ca_certificate)?;
+        ssl.trust_store(path).map_err(ssl_err)?;
     }
+
+    let client_key = decrypt_secret_opt(&secret.client_key)?;
+
+    if let (Some(client_certificate), Some(client_key)) = (&secret.client_certificate, &client_key) {
+        let cert_path = write_tls_file(&broker.uuid, "cert", client_certificate)?;
+        let key_path = write_tls_file(&broker.uuid, "key", client_key)?;
+        ssl.key_store(cert_path).map_err(ssl_err)?;
+        ssl.private_key(key_path).map_err(ssl_err)?;
+    }
+
+    ssl.enable_server_cert_auth(true);
+    ssl.verify(broker.verify_hostname);
+
+    Ok(ssl.finalize())
+}
+
+pub fn create_v5_properties(broker: &BrokerResponse) -> Result<paho_mqtt::Properties, AppError> {
+
+    let property_err = |err: paho_mqtt::Error| AppError::MqttError(AppMsgInfError {
+        file: file!().to_string(),
+        line: line!(),
+        api_msg_error: "MqttError".into(),
+        log_msg_error: err.to_string(),
+    });
+
+    let mut properties = paho_mqtt::Properties::new();
+
+    if let Some(session_expiry_interval) = broker.session_expiry_interval {
+        properties.push_int(paho_mqtt::PropertyCode::SessionExpiryInterval, session_expiry_interval)
+            .map_err(property_err)?;
+    }
+
+    if let Some(receive_maximum) = broker.receive_maximum {
+        properties.push_int(paho_mqtt::PropertyCode::ReceiveMaximum, receive_maximum)
+            .map_err(property_err)?;
+    }
+
+    if let Some(topic_alias_maximum) = broker.topic_alias_maximum {
+        properties.push_int(paho_mqtt::PropertyCode::TopicAliasMaximum, topic_alias_maximum)
+            .map_err(property_err)?;
+    }
+
+    Ok(properties)
 }
 
 pub async fn create_connection_options(
-    broker: &BrokerResponse
+    broker: &BrokerResponse,
+    secret: Option<&BrokerSecret>,
 ) -> Result<paho_mqtt::ConnectOptions, AppError> {
 
-    let last_will = create_last_will(broker);
-
-    let connection_options = ConnectionOptions{
-        keep_alive: broker.keep_alive as u64,
-        clean_session: broker.clean_session,
-        will: last_will
+    let mut builder = if broker.version == 5 {
+        paho_mqtt::ConnectOptionsBuilder::new_v5()
+    } else {
+        paho_mqtt::ConnectOptionsBuilder::new()
     };
 
-    let connection_options = connection_options.create_connection_options()
-        .map_err(|err| AppError::MqttError(AppMsgInfError {
-            file: file!().to_string(),
-            line: line!(),
-            api_msg_error: "MqttError".into(),
-            log_msg_error: err.to_string(),
-        }))?;
+    builder.keep_alive_interval(Duration::from_secs(broker.keep_alive as u64));
+
+    if broker.version == 5 {
+        builder.clean_start(broker.clean_session);
+        builder.properties(create_v5_properties(broker)?);
+    } else {
+        builder.clean_session(broker.clean_session);
+    }
+
+    if let Some(last_will) = create_last_will(broker) {
+        builder.will_message(last_will);
+    }
+
+    if let Some(username) = &broker.username {
+        builder.user_name(username.clone());
+    }
 
-    Ok(connection_options)
+    if let Some(secret) = secret {
+        if let Some(password) = decrypt_secret_opt(&secret.password)? {
+            builder.password(password);
+        }
+    }
+
+    if broker.tls_enabled {
+        let secret = match secret {
+            Some(secret) => secret,
+            None => Err(AppError::MqttError(AppMsgInfError {
+                file: file!().to_string(),
+                line: line!(),
+                api_msg_error: "Broker tls material not found".into(),
+                log_msg_error: format!("Broker tls enabled without tls material, uuid: {}", broker.uuid),
+            }))?,
+        };
+        builder.ssl_options(create_ssl_options(broker, secret)?);
+    }
+
+    Ok(builder.finalize())
 }
 
-pub async fn create_client(options: Options) -> Result<AsyncClient, AppError> {
+pub async fn create_client(options: paho_mqtt::CreateOptions) -> Result<AsyncClient, AppError> {
 
-    let cli = mqtt_device::create_client::create_async_client(options)
+    let cli = AsyncClient::new(options)
         .map_err(|err| AppError::MqttError(AppMsgInfError {
             file: file!().to_string(),
             line: line!(),
@@ -66,6 +204,83 @@ pub async fn create_client(options: Options) -> Result<AsyncClient, AppError> {
 
 }
 
+/// MQTT v5 connect properties are only sent when the broker speaks v5.
+pub fn validate_broker_v5(
+    version: i32,
+    session_expiry_interval: Option<i32>,
+    receive_maximum: Option<i32>,
+    topic_alias_maximum: Option<i32>,
+) -> Result<(), AppError> {
+
+    let has_v5_properties = session_expiry_interval.is_some()
+        || receive_maximum.is_some()
+        || topic_alias_maximum.is_some();
+
+    if has_v5_properties && version != 5 {
+        Err(AppError::BadRequest("session_expiry_interval, receive_maximum and topic_alias_maximum require version 5".to_string()))?
+    }
+
+    if let Some(session_expiry_interval) = session_expiry_interval {
+        if session_expiry_interval < 0 {
+            Err(AppError::BadRequest("session_expiry_interval must be greater than or equal to 0".to_string()))?
+        }
+    }
+
+    if let Some(receive_maximum) = receive_maximum {
+        if !(1..=65535).contains(&receive_maximum) {
+            Err(AppError::BadRequest("receive_maximum must be between 1 and 65535".to_string()))?
+        }
+    }
+
+    if let Some(topic_alias_maximum) = topic_alias_maximum {
+        if !(0..=65535).contains(&topic_alias_maximum) {
+            Err(AppError::BadRequest("topic_alias_maximum must be between 0 and 65535".to_string()))?
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks that the PEM material sent on broker create/update parses
+/// and that the client key belongs to the client certificate.
+pub fn validate_broker_tls(
+    ca_certificate: &Option<String>,
+    client_certificate: &Option<String>,
+    client_key: &Option<String>,
+) -> Result<(), AppError> {
+
+    if let Some(ca_certificate) = ca_certificate {
+        match X509::stack_from_pem(ca_certificate.as_bytes()) {
+            Ok(certs) if !certs.is_empty() => (),
+            Ok(_) => Err(AppError::BadRequest("Invalid ca_certificate: no certificate found".to_string()))?,
+            Err(err) => Err(AppError::BadRequest(format!("Invalid ca_certificate: {}", err)))?,
+        };
+    }
+
+    match (client_certificate, client_key) {
+        (None, None) => Ok(()),
+
+        (Some(client_certificate), Some(client_key)) => {
+            let cert = X509::from_pem(client_certificate.as_bytes())
+                .map_err(|err| AppError::BadRequest(format!("Invalid client_certificate: {}", err)))?;
+
+            let key = PKey::private_key_from_pem(client_key.as_bytes())
+                .map_err(|err| AppError::BadRequest(format!("Invalid client_key: {}", err)))?;
+
+            let cert_key = cert.public_key()
+                .map_err(|err| AppError::BadRequest(format!("Invalid client_certificate: {}", err)))?;
+
+            if !cert_key.public_eq(&key) {
+                Err(AppError::BadRequest("client_key does not match client_certificate".to_string()))?
+            }
+
+            Ok(())
+        }
+
+        _ => Err(AppError::BadRequest("client_certificate and client_key must be specified together".to_string()))?,
+    }
+}
+
 pub fn build_subscribe_all_topics_qoss(subs: Vec<DeviceMessageSubscribe>) -> SubscribeTopicQos {
     let topics: Vec<String> = subs.iter().map(|s| s.topic.clone()).collect();
     let qoss: Vec<i32> = subs.iter().map(|s| s.qos).collect();
@@ -76,6 +291,16 @@ pub fn build_subscribe_all_topics_qoss(subs: Vec<DeviceMessageSubscribe>) -> Sub
     }
 }
 
+pub fn build_subscribe_map_topics_qoss(subs: &HashMap<String, i32>) -> SubscribeTopicQos {
+    let topics: Vec<String> = subs.keys().cloned().collect();
+    let qoss: Vec<i32> = topics.iter().map(|topic| subs[topic]).collect();
+
+    SubscribeTopicQos {
+        topics,
+        qoss
+    }
+}
+
 pub async fn broker_change_state(
     broker_uuid: &Uuid,
     connected: bool,
@@ -98,6 +323,37 @@ pub async fn broker_change_state(
 
     put_broker_state_query(pool, &broker_uuid, connected).await?;
 
+    let state = if connected { BrokerState::Connected } else { BrokerState::Disconnected };
+    broker_publish_state(broker_uuid, state, pool).await;
+
+    Ok(())
+}
+
+/// Pushes the broker transition to the state feed of the users owning a
+/// device on it, a failed lookup only skips the event.
+pub async fn broker_publish_state(
+    broker_uuid: &Uuid,
+    state: BrokerState,
+    pool: &PgPool,
+) {
+
+    match get_broker_user_uuids_query(pool, broker_uuid).await {
+        Ok(user_uuids) => publish_state(StateChange::BrokerState { broker_uuid: *broker_uuid, state }, user_uuids),
+        Err(err) => error!("file: {}, line: {}, Failed to load broker users: broker_uuid: {}, error: {:?}", file!(), line!(), broker_uuid, err),
+    }
+}
+
+/// Encrypts the client keys stored in plain text before secrets were
+/// encrypted at rest, run at startup before any broker connects.
+pub async fn encrypt_plaintext_client_keys(pool: &PgPool) -> Result<(), AppError> {
+
+    for broker in get_broker_plaintext_client_keys_query(pool).await? {
+        let client_key = encrypt_secret(&broker.client_key)?;
+        put_broker_client_key_query(pool, &broker.uuid, &client_key).await?;
+
+        info!("file: {}, line: {}, Broker client key encrypted: uuid: {}", file!(), line!(), broker.uuid);
+    }
+
     Ok(())
 }
 
@@ -136,6 +392,99 @@ pub async fn build_subscribe_topic_qos(
     Ok(())
 }
 
+pub async fn build_unsubscribe_topic(
+    broker_uuid: Uuid,
+    topic: String,
+    manager: web::Data<BrokerManager>,
+) -> Result<(), AppError>{
+
+    let handle = match manager.get(&broker_uuid).await {
+        Some(handle) => handle,
+        None => Err(AppError::NotFound(AppMsgError {
+            api_msg_error: "Broker not found".to_string(),
+            log_msg_error: format!(
+                "file: {}: line: {}, Broker not found, uuid: {}",
+                file!(),
+                line!(),
+                broker_uuid
+            ),
+        }))?,
+    };
+
+    handle.cmd_tx.send(BrokerCommand::Unsubscribe{topic})
+        .await
+        .map_err(|err|
+            AppError::MqttError(AppMsgInfError {
+                file: file!().to_string(),
+                line: line!(),
+                api_msg_error: "MqttError".into(),
+                log_msg_error: err.to_string(),
+            }))?;
+
+    Ok(())
+}
+
+pub async fn publish_message(
+    broker_uuid: Uuid,
+    message: paho_mqtt::Message,
+    manager: web::Data<BrokerManager>,
+) -> Result<(), AppError>{
+
+    let handle = match manager.get(&broker_uuid).await {
+        Some(handle) => handle,
+        None => Err(AppError::NotFound(AppMsgError {
+            api_msg_error: "Broker not found".to_string(),
+            log_msg_error: format!(
+                "file: {}: line: {}, Broker not found, uuid: {}",
+                file!(),
+                line!(),
+                broker_uuid
+            ),
+        }))?,
+    };
+
+    let (resp_tx, resp_rx) = oneshot::channel();
+
+    handle.cmd_tx.send(BrokerCommand::Publish{message, resp: resp_tx})
+        .await
+        .map_err(|err|
+            AppError::MqttError(AppMsgInfError {
+                file: file!().to_string(),
+                line: line!(),
+                api_msg_error: "MqttError".into(),
+                log_msg_error: err.to_string(),
+            }))?;
+
+    let result = resp_rx.await
+        .map_err(|err|
+            AppError::MqttError(AppMsgInfError {
+                file: file!().to_string(),
+                line: line!(),
+                api_msg_error: "MqttError".into(),
+                log_msg_error: err.to_string(),
+            }))?;
+
+    result.map_err(|err|
+        AppError::MqttError(AppMsgInfError {
+            file: file!().to_string(),
+            line: line!(),
+            api_msg_error: "Failed to publish command".into(),
+            log_msg_error: err,
+        }))
+}
+
+pub fn decode_received_properties(message: &paho_mqtt::Message) -> MessageReceiveProperties {
+
+    let properties = message.properties();
+
+    MessageReceiveProperties {
+        content_type: properties.get_string(paho_mqtt::PropertyCode::ContentType),
+        user_properties: properties.user_iter().collect(),
+        response_topic: properties.get_string(paho_mqtt::PropertyCode::ResponseTopic),
+        correlation_data: properties.get_binary(paho_mqtt::PropertyCode::CorrelationData),
+    }
+}
+
 pub fn decode_received_message(message: &paho_mqtt::Message)->Result<MessageReceivePayload, AppError> {
 
     match std::str::from_utf8(message.payload()) {
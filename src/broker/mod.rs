@@ -2,5 +2,7 @@ pub mod broker_model;
 pub(crate) mod broker_query;
 mod broker_handler;
 pub mod broker_route;
-mod broker_connection;
+pub(crate) mod broker_connection;
 pub mod broker_tool;
+pub(crate) mod broker_secret;
+pub(crate) mod broker_config;
\ No newline at end of file
@@ -0,0 +1,134 @@
+use actix_web::{web, HttpResponse};
+use actix_web_httpauth::extractors::bearer::BearerAuth;
+use log::info;
+use uuid::Uuid;
+use crate::auth::auth_config::AuthConfig;
+use crate::auth::auth_tool::token_info;
+use crate::data_store::data_store_dead_letter_model::{DeadLetterFilter, DeadLetterPurgeFilter, DeadLetterPurgeResponse, DeadLetterReplayResponse, DeadLetterResponse};
+use crate::data_store::data_store_dead_letter_query::{delete_dead_letter_filter_query, delete_dead_letter_query, get_dead_letter_query, get_dead_letter_with_uuid_query, put_dead_letter_replay_failed_query};
+use crate::data_store::data_store_device_handler::put_device_message;
+use crate::error_app::error_app::{AppError, AppMsgError};
+use crate::state::AppState;
+use crate::user::user_query::get_user_by_uuid;
+
+/// Dead letters are scoped to the token user, an admin sees every dead
+/// letter, including those whose topic names no user.
+async fn dead_letter_owner(
+    credentials: &BearerAuth,
+    app_state: &AppState,
+) -> Result<Option<Uuid>, AppError> {
+
+    let token = token_info(credentials.token().to_string()).await?;
+    let user = get_user_by_uuid(&app_state.db, &token.inf.uuid).await?;
+
+    if AuthConfig::is_admin(&user.email) {
+        return Ok(None);
+    }
+
+    Ok(Some(user.uuid))
+}
+
+pub async fn dead_letter_get_filter(
+    filter: web::Query<DeadLetterFilter>,
+    credentials: BearerAuth,
+    app_state: web::Data<AppState>
+) -> Result<HttpResponse, AppError> {
+
+    let owner = dead_letter_owner(&credentials, &app_state).await?;
+
+    get_dead_letter_query(&app_state.mongo, &filter, owner.as_ref())
+        .await
+        .map(|dead_letters| HttpResponse::Ok().json(&dead_letters))
+}
+
+pub async fn dead_letter_get(
+    dead_letter_uuid: web::Path<Uuid>,
+    credentials: BearerAuth,
+    app_state: web::Data<AppState>
+) -> Result<HttpResponse, AppError> {
+
+    let owner = dead_letter_owner(&credentials, &app_state).await?;
+
+    let dead_letter = get_dead_letter_with_uuid_query(&app_state.mongo, &dead_letter_uuid, owner.as_ref()).await?;
+
+    Ok(HttpResponse::Ok().json(DeadLetterResponse::try_from(dead_letter)?))
+}
+
+/// Runs a dead letter through ingestion again, it is removed once stored.
+pub async fn dead_letter_replay(
+    dead_letter_uuid: web::Path<Uuid>,
+    credentials: BearerAuth,
+    app_state: web::Data<AppState>
+) -> Result<HttpResponse, AppError> {
+
+    let owner = dead_letter_owner(&credentials, &app_state).await?;
+
+    let dead_letter_uuid = dead_letter_uuid.into_inner();
+    let dead_letter = get_dead_letter_with_uuid_query(&app_state.mongo, &dead_letter_uuid, owner.as_ref()).await?;
+
+    let message = match dead_letter.message.into_message() {
+        Ok(message) => message,
+        Err(err) => Err(AppError::InternalServerError(
+            format!("file: {}, line: {}, Invalid dead letter message: {}", file!(), line!(), err)
+        ))?
+    };
+
+    match put_device_message(&app_state.db, &app_state.mongo, &message).await {
+        Ok(_) => {
+            delete_dead_letter_query(&app_state.mongo, &dead_letter_uuid, owner.as_ref()).await?;
+
+            info!("file: {}, line: {}, Dead letter {} replayed", file!(), line!(), dead_letter_uuid);
+
+            Ok(HttpResponse::Ok().json(DeadLetterReplayResponse {
+                uuid: dead_letter_uuid,
+                replayed: true,
+            }))
+        }
+        Err(failure) => {
+            if failure.reason.is_none() {
+                return Err(failure.error);
+            }
+
+            let error = format!("{:?}", failure.error);
+            put_dead_letter_replay_failed_query(&app_state.mongo, &dead_letter_uuid, owner.as_ref(), error.clone()).await?;
+
+            Err(AppError::UnprocessableEntity(AppMsgError {
+                api_msg_error: "Dead letter replay failed".into(),
+                log_msg_error: format!("file: {}, line: {}, Dead letter replay failed: uuid: {}, error: {}",
+                    file!(),
+                    line!(),
+                    dead_letter_uuid,
+                    error
+                ),
+            }))?
+        }
+    }
+}
+
+pub async fn dead_letter_delete(
+    dead_letter_uuid: web::Path<Uuid>,
+    credentials: BearerAuth,
+    app_state: web::Data<AppState>
+) -> Result<HttpResponse, AppError> {
+
+    let owner = dead_letter_owner(&credentials, &app_state).await?;
+
+    delete_dead_letter_query(&app_state.mongo, &dead_letter_uuid, owner.as_ref())
+        .await
+        .map(|_| HttpResponse::NoContent().finish())
+}
+
+pub async fn dead_letter_purge(
+    filter: web::Query<DeadLetterPurgeFilter>,
+    credentials: BearerAuth,
+    app_state: web::Data<AppState>
+) -> Result<HttpResponse, AppError> {
+
+    let owner = dead_letter_owner(&credentials, &app_state).await?;
+
+    let deleted_count = delete_dead_letter_filter_query(&app_state.mongo, &filter, owner.as_ref()).await?;
+
+    info!("file: {}, line: {}, Purged {} dead letter(s)", file!(), line!(), deleted_count);
+
+    Ok(HttpResponse::Ok().json(DeadLetterPurgeResponse { deleted_count }))
+}
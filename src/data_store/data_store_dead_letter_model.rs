@@ -0,0 +1,230 @@
+use std::collections::HashMap;
+use std::fmt;
+use chrono::Utc;
+use mongodb::bson::{DateTime as BsonDateTime};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use crate::data_store::data_store_tool::bson_to_chrono;
+use crate::error_app::error_app::AppError;
+use crate::ingestion::ingestion_model::StoredMessage;
+use crate::paginate::paginate_model::{Pagination, PaginationFrom};
+
+/// Why a received message could not be stored in the devices collection.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeadLetterReason {
+    DecodeError,
+    InvalidTopic,
+    DeviceNotFound,
+    InvalidScale,
+}
+
+impl fmt::Display for DeadLetterReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            DeadLetterReason::DecodeError => "decode_error",
+            DeadLetterReason::InvalidTopic => "invalid_topic",
+            DeadLetterReason::DeviceNotFound => "device_not_found",
+            DeadLetterReason::InvalidScale => "invalid_scale",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// Failure while storing a received message, `reason` is set when the
+/// message must go to the dead-letter store instead of being retried.
+#[derive(Debug)]
+pub struct IngestionFailure {
+    pub reason: Option<DeadLetterReason>,
+    pub error: AppError,
+}
+
+impl IngestionFailure {
+    pub fn dead_letter(reason: DeadLetterReason, error: AppError) -> Self {
+        IngestionFailure { reason: Some(reason), error }
+    }
+}
+
+impl From<AppError> for IngestionFailure {
+    fn from(error: AppError) -> Self {
+        IngestionFailure { reason: None, error }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeadLetter {
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub broker_uuid: String,
+    pub reason: DeadLetterReason,
+    pub error: String,
+    pub message: StoredMessage,
+    pub payload_text: Option<String>,
+    pub user_uuid: Option<String>,
+    pub device_uuid: Option<String>,
+    #[serde(default)]
+    pub replay_count: i32,
+    pub last_replayed_at: Option<BsonDateTime>,
+    pub created_at: BsonDateTime,
+}
+
+impl DeadLetter {
+    pub fn new(
+        broker_uuid: &Uuid,
+        message: &paho_mqtt::Message,
+        reason: DeadLetterReason,
+        error: String,
+    ) -> Self {
+        let topic_parts: Vec<&str> = message.topic().split('/').collect();
+        let topic_uuid = |index: usize| topic_parts
+            .get(index)
+            .and_then(|part| Uuid::parse_str(part).ok())
+            .map(|uuid| uuid.to_string());
+
+        DeadLetter {
+            id: Uuid::new_v4().to_string(),
+            broker_uuid: broker_uuid.to_string(),
+            reason,
+            error,
+            message: StoredMessage::from_message(message),
+            payload_text: std::str::from_utf8(message.payload()).ok().map(str::to_string),
+            user_uuid: topic_uuid(0),
+            device_uuid: topic_uuid(1),
+            replay_count: 0,
+            last_replayed_at: None,
+            created_at: BsonDateTime::now(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeadLetterResponse {
+    pub uuid: Uuid,
+    pub broker_uuid: Uuid,
+    pub reason: DeadLetterReason,
+    pub error: String,
+    pub topic: String,
+    pub payload: String,
+    pub payload_text: Option<String>,
+    pub qos: i32,
+    pub retained: bool,
+    pub content_type: Option<String>,
+    pub user_properties: HashMap<String, String>,
+    pub user_uuid: Option<Uuid>,
+    pub device_uuid: Option<Uuid>,
+    pub replay_count: i32,
+    pub last_replayed_at: Option<chrono::DateTime<Utc>>,
+    pub created_at: chrono::DateTime<Utc>,
+}
+
+impl TryFrom<DeadLetter> for DeadLetterResponse {
+    type Error = AppError;
+
+    fn try_from(dead_letter: DeadLetter) -> Result<Self, Self::Error> {
+        let parse_uuid = |value: &str| Uuid::parse_str(value).ok();
+
+        let last_replayed_at = match &dead_letter.last_replayed_at {
+            Some(last_replayed_at) => Some(bson_to_chrono(last_replayed_at)?),
+            None => None,
+        };
+
+        Ok(DeadLetterResponse {
+            uuid: parse_uuid(&dead_letter.id).unwrap_or_default(),
+            broker_uuid: parse_uuid(&dead_letter.broker_uuid).unwrap_or_default(),
+            reason: dead_letter.reason,
+            error: dead_letter.error,
+            topic: dead_letter.message.topic,
+            payload: dead_letter.message.payload,
+            payload_text: dead_letter.payload_text,
+            qos: dead_letter.message.qos,
+            retained: dead_letter.message.retained,
+            content_type: dead_letter.message.content_type,
+            user_properties: dead_letter.message.user_properties,
+            user_uuid: dead_letter.user_uuid.as_deref().and_then(parse_uuid),
+            device_uuid: dead_letter.device_uuid.as_deref().and_then(parse_uuid),
+            replay_count: dead_letter.replay_count,
+            last_replayed_at,
+            created_at: bson_to_chrono(&dead_letter.created_at)?,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeadLetterFilter {
+    pub broker_uuid: Option<Uuid>,
+    pub device_uuid: Option<Uuid>,
+    pub reason: Option<DeadLetterReason>,
+    #[serde(flatten)]
+    pub pagination: Pagination,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeadLetterPurgeFilter {
+    pub broker_uuid: Option<Uuid>,
+    pub device_uuid: Option<Uuid>,
+    pub reason: Option<DeadLetterReason>,
+    pub before: Option<chrono::DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeadLetterPurgeResponse {
+    pub deleted_count: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeadLetterReplayResponse {
+    pub uuid: Uuid,
+    pub replayed: bool,
+}
+
+#[derive(Serialize)]
+pub struct DeadLetterPaginationResponse {
+    pub dead_letters: Vec<DeadLetterResponse>,
+    pub pagination: PaginationFrom,
+    pub total_count: i64,
+    pub total_pages: u32,
+    pub current_page: u32,
+    pub next_page: Option<i64>,
+    pub previous_page: Option<i64>,
+    pub first_page: u32,
+    pub last_page: u32,
+    pub has_next_page: bool,
+}
+
+impl DeadLetterPaginationResponse {
+    pub fn new(
+        dead_letters: Vec<DeadLetterResponse>,
+        total_count: i64,
+        page: u32,
+        page_size: u32,
+    ) -> Self {
+        let total_pages = ((total_count as f64) / (page_size as f64)).ceil() as u32;
+
+        let current_page = page.max(1).min(total_pages.max(1));
+
+        let next_page = if current_page < total_pages {
+            Some((current_page + 1) as i64)
+        } else {
+            None
+        };
+
+        let previous_page = if current_page > 1 {
+            Some((current_page - 1) as i64)
+        } else {
+            None
+        };
+
+        Self {
+            dead_letters,
+            pagination: PaginationFrom{ page: current_page, page_size },
+            total_count,
+            total_pages,
+            current_page,
+            next_page,
+            previous_page,
+            first_page: 1,
+            last_page: total_pages.max(1),
+            has_next_page: next_page.is_some(),
+        }
+    }
+}
@@ -0,0 +1,268 @@
+use futures_util::TryStreamExt;
+use mongodb::{Client, Collection};
+use mongodb::bson::{doc, Document};
+use mongodb::bson::{DateTime as BsonDateTime};
+use mongodb::options::FindOptions;
+use uuid::Uuid;
+use crate::data_store::data_store_dead_letter_model::{DeadLetter, DeadLetterFilter, DeadLetterPaginationResponse, DeadLetterPurgeFilter, DeadLetterResponse};
+use crate::error_app::error_app::{AppError, AppMsgError, AppMsgInfError};
+use crate::paginate::paginate_model::Pagination;
+
+fn dead_letter_collection(client: &Client) -> Collection<DeadLetter> {
+    client.database("devices").collection("dead_letters")
+}
+
+/// Restricts a filter to the dead letters of a user, `None` for an admin.
+fn dead_letter_owner_filter(mut query: Document, user_uuid: Option<&Uuid>) -> Document {
+    if let Some(user_uuid) = user_uuid {
+        query.insert("user_uuid", user_uuid.to_string());
+    }
+
+    query
+}
+
+pub async fn post_dead_letter_query(
+    client: &Client,
+    dead_letter: &DeadLetter,
+) -> Result<(), AppError> {
+
+    dead_letter_collection(client).insert_one(dead_letter).await.map_err(|e| {
+        AppError::MongoDBError(AppMsgInfError {
+            file: file!().into(),
+            line: line!(),
+            api_msg_error: "Internal server error".into(),
+            log_msg_error: e.to_string(),
+        })
+    })?;
+
+    Ok(())
+}
+
+pub async fn get_dead_letter_query(
+    client: &Client,
+    filter: &DeadLetterFilter,
+    user_uuid: Option<&Uuid>,
+) -> Result<DeadLetterPaginationResponse, AppError> {
+
+    let mut query = dead_letter_owner_filter(doc! {}, user_uuid);
+
+    if let Some(broker_uuid) = &filter.broker_uuid {
+        query.insert("broker_uuid", broker_uuid.to_string());
+    }
+
+    if let Some(device_uuid) = &filter.device_uuid {
+        query.insert("device_uuid", device_uuid.to_string());
+    }
+
+    if let Some(reason) = &filter.reason {
+        query.insert("reason", reason.to_string());
+    }
+
+    //Pagination
+    let page: String;
+    let page_size: String;
+
+    if filter.pagination.page.is_empty(){
+        page = "1".to_string();
+    }else{
+        page = filter.pagination.page.clone();
+    };
+
+    if filter.pagination.page_size.is_empty(){
+        page_size = "10".to_string();
+    }else{
+        page_size = filter.pagination.page_size.clone();
+    };
+
+    let pagination = match Pagination::new(
+        page,
+        page_size,
+    ){
+        Ok(result) => result,
+        Err(err) => Err(err)?
+    };
+
+    let offset = (pagination.page.saturating_sub(1) * pagination.page_size) as u64;
+
+    let options = FindOptions::builder()
+        .sort(doc! { "created_at": -1 })
+        .skip(offset)
+        .limit(pagination.page_size as i64)
+        .build();
+
+    let collection = dead_letter_collection(client);
+
+    let cursor = match collection.find(query.clone()).with_options(options).await {
+        Ok(cursor) => cursor,
+        Err(e) => Err(AppError::MongoDBError(AppMsgInfError {
+            file: file!().into(),
+            line: line!(),
+            api_msg_error: "Internal server error".into(),
+            log_msg_error: e.to_string(),
+        }))?,
+    };
+
+    let dead_letters: Vec<DeadLetter> = match cursor.try_collect().await {
+        Ok(dead_letters) => dead_letters,
+        Err(e) => Err(AppError::MongoDBError(AppMsgInfError {
+            file: file!().into(),
+            line: line!(),
+            api_msg_error: "Internal server error".into(),
+            log_msg_error: e.to_string(),
+        }))?,
+    };
+
+    let total_count = match collection.count_documents(query).await {
+        Ok(count) => count as i64,
+        Err(e) => Err(AppError::MongoDBError(AppMsgInfError {
+            file: file!().into(),
+            line: line!(),
+            api_msg_error: "Internal server error".into(),
+            log_msg_error: e.to_string(),
+        }))?,
+    };
+
+    let dead_letters = dead_letters
+        .into_iter()
+        .map(DeadLetterResponse::try_from)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let result = DeadLetterPaginationResponse::new(
+        dead_letters,
+        total_count,
+        pagination.page,
+        pagination.page_size
+    );
+
+    Ok(result)
+}
+
+pub async fn get_dead_letter_with_uuid_query(
+    client: &Client,
+    dead_letter_uuid: &Uuid,
+    user_uuid: Option<&Uuid>,
+) -> Result<DeadLetter, AppError> {
+
+    let query = dead_letter_owner_filter(doc! { "_id": dead_letter_uuid.to_string() }, user_uuid);
+
+    match dead_letter_collection(client).find_one(query).await {
+        Ok(Some(dead_letter)) => Ok(dead_letter),
+
+        Ok(None) => Err(AppError::NotFound(
+            AppMsgError{
+                api_msg_error: "Dead letter not found".into(),
+                log_msg_error: format!("file: {}, line: {}, Dead letter not found: uuid: {}",
+                    file!(),
+                    line!(),
+                    dead_letter_uuid
+                )
+            }))?,
+
+        Err(e) => Err(AppError::MongoDBError(
+            AppMsgInfError{
+                file: file!().to_string(),
+                line: line!(),
+                api_msg_error: "Internal server error".into(),
+                log_msg_error: e.to_string(),
+            }))?,
+    }
+}
+
+pub async fn put_dead_letter_replay_failed_query(
+    client: &Client,
+    dead_letter_uuid: &Uuid,
+    user_uuid: Option<&Uuid>,
+    error: String,
+) -> Result<(), AppError> {
+
+    dead_letter_collection(client).update_one(
+        dead_letter_owner_filter(doc! { "_id": dead_letter_uuid.to_string() }, user_uuid),
+        doc! {
+            "$set": {
+                "error": error,
+                "last_replayed_at": BsonDateTime::now()
+            },
+            "$inc": { "replay_count": 1 }
+        }
+    ).await.map_err(|e| {
+        AppError::MongoDBError(AppMsgInfError {
+            file: file!().into(),
+            line: line!(),
+            api_msg_error: "Internal server error".into(),
+            log_msg_error: e.to_string(),
+        })
+    })?;
+
+    Ok(())
+}
+
+pub async fn delete_dead_letter_query(
+    client: &Client,
+    dead_letter_uuid: &Uuid,
+    user_uuid: Option<&Uuid>,
+) -> Result<(), AppError> {
+
+    let result = dead_letter_collection(client)
+        .delete_one(dead_letter_owner_filter(doc! { "_id": dead_letter_uuid.to_string() }, user_uuid))
+        .await
+        .map_err(|e| {
+            AppError::MongoDBError(AppMsgInfError {
+                file: file!().into(),
+                line: line!(),
+                api_msg_error: "Internal server error".into(),
+                log_msg_error: e.to_string(),
+            })
+        })?;
+
+    if result.deleted_count == 0 {
+        Err(AppError::NotFound(AppMsgError {
+            api_msg_error: "Dead letter not found".into(),
+            log_msg_error: format!("file: {}, line: {}, Dead letter not found: uuid: {}",
+                file!(),
+                line!(),
+                dead_letter_uuid
+            ),
+        }))?;
+    }
+
+    Ok(())
+}
+
+pub async fn delete_dead_letter_filter_query(
+    client: &Client,
+    filter: &DeadLetterPurgeFilter,
+    user_uuid: Option<&Uuid>,
+) -> Result<u64, AppError> {
+
+    let mut query = dead_letter_owner_filter(Document::new(), user_uuid);
+
+    if let Some(broker_uuid) = &filter.broker_uuid {
+        query.insert("broker_uuid", broker_uuid.to_string());
+    }
+
+    if let Some(device_uuid) = &filter.device_uuid {
+        query.insert("device_uuid", device_uuid.to_string());
+    }
+
+    if let Some(reason) = &filter.reason {
+        query.insert("reason", reason.to_string());
+    }
+
+    if let Some(before) = &filter.before {
+        query.insert("created_at", doc! { "$lt": BsonDateTime::from_millis(before.timestamp_millis()) });
+    }
+
+    let result = dead_letter_collection(client)
+        .delete_many(query)
+        .await
+        .map_err(|e| {
+            AppError::MongoDBError(AppMsgInfError {
+                file: file!().into(),
+                line: line!(),
+                api_msg_error: "Internal server error".into(),
+                log_msg_error: e.to_string(),
+            })
+        })?;
+
+    Ok(result.deleted_count)
+}
@@ -0,0 +1,16 @@
+use actix_web::middleware::from_fn;
+use actix_web::web;
+use crate::auth;
+use crate::data_store::data_store_dead_letter_handler::{dead_letter_delete, dead_letter_get, dead_letter_get_filter, dead_letter_purge, dead_letter_replay};
+
+pub fn data_store_dead_letter_cfg(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/dead_letter")
+            .wrap(from_fn(auth::auth_midlleware::auth_middleware))
+            .route("", web::get().to(dead_letter_get_filter))
+            .route("", web::delete().to(dead_letter_purge))
+            .route("/{uuid}", web::get().to(dead_letter_get))
+            .route("/{uuid}", web::delete().to(dead_letter_delete))
+            .route("/{uuid}/replay", web::post().to(dead_letter_replay))
+    );
+}
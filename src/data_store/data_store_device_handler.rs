@@ -1,24 +1,41 @@
+use std::collections::HashMap;
+use std::str::FromStr;
 use actix_web::{web, HttpResponse};
+use chrono::{DateTime, Duration, Utc};
 use actix_web_httpauth::extractors::bearer::BearerAuth;
 use log::{error, info};
 use mongodb::bson::{DateTime as BsonDateTime};
 use mongodb::Client;
+use sqlx::PgPool;
 use uuid::Uuid;
 use crate::auth::auth_tool::token_info;
-use crate::broker::broker_tool::decode_received_message;
-use crate::data_store::data_store_device_model::{DeviceData, DeviceDataStoreResponse};
-use crate::data_store::data_store_device_query::{get_device_with_uuid_data_store_query, post_device_data_store_query, update_device_messages_query};
+use crate::broker::broker_tool::decode_received_properties;
+use crate::data_store::data_store_dead_letter_model::{DeadLetter, DeadLetterReason, IngestionFailure};
+use crate::data_store::data_store_dead_letter_query::post_dead_letter_query;
+use crate::data_store::data_store_device_model::{AggregateWindow, DeviceData, ReadingValue, DeviceDataStoreResponse, ExportFormat, ReadingAggregateFilter, ReadingExportFilter, ReadingAggregateResponse, ReadingFilter, AGGREGATE_MAX_WINDOWS};
+use crate::data_store::data_store_device_query::{get_device_decoder_data_store_query, get_device_readings_export_cursor_query, get_device_uuids_owned_data_store_query, get_device_readings_aggregate_data_store_query, get_device_readings_data_store_query, get_device_with_uuid_data_store_query, post_device_data_store_query, update_device_command_response_query, update_device_messages_query};
+use crate::data_store::data_store_export_tool::export_readings_stream;
 use crate::data_store::data_store_tool::bson_to_chrono;
+use crate::device::device_message_query::get_device_scale_units_query;
 use crate::error_app::error_app::AppError;
+use crate::ingestion::ingestion_decoder::decode_readings;
+use crate::ingestion::ingestion_model::DeviceDecoder;
+use crate::ingestion::ingestion_validation::validate_readings;
+use crate::realtime::realtime_hub::{publish_reading, publish_state};
+use crate::realtime::realtime_model::{ReadingEvent, StateChange};
 use crate::state::AppState;
+use crate::timezone::timezone_tool::parse_timezone;
 use crate::user::user_query::get_user_by_uuid;
-use crate::device::device_adoption_tool::device_decompose_topic;
+use crate::device::device_adoption_tool::{device_decompose_topic, DecomposeTopic, COMMAND_RESPONSE_SUFFIX};
+use crate::device::device_message_model::{MessageReceivePayload, MessageReceiveProperties};
+use crate::ingestion::ingestion_derived::derive_readings;
 
 pub async fn create_device_collection(
     app_state: web::Data<AppState>,
     device_uuid: &Uuid,
     user_uuid: &Uuid,
-    topic: &String
+    topic: &String,
+    decoder: DeviceDecoder,
 ) -> Result<(), AppError> {
 
     info!("file: {}, lime: {}, device_uuid: {}, user_uuid: {}",
@@ -33,7 +50,8 @@ pub async fn create_device_collection(
         device_uuid: device_uuid.to_string(),
         user_uuid: user_uuid.to_string(),
         topic: topic.into(),
-        messages: vec![],
+        blocked: false,
+        decoder,
         created_at: BsonDateTime::now(),
         updated_at: None,
         deleted_at: None,
@@ -89,32 +107,379 @@ pub async fn get_device_collection(
 
 }
 
+pub async fn get_device_readings(
+    credentials: BearerAuth,
+    app_state: web::Data<AppState>,
+    device_uuid: web::Path<Uuid>,
+    filter: web::Query<ReadingFilter>,
+) -> Result<HttpResponse, AppError> {
+
+    let device_uuid = device_uuid.into_inner();
+    let filter = filter.into_inner();
+
+    let token = token_info(credentials.token().to_string()).await?;
+    let user = get_user_by_uuid(&app_state.db, &token.inf.uuid).await?;
+
+    // ownership: the device collection belongs to the token user
+    let _ = get_device_with_uuid_data_store_query(
+        &app_state.mongo,
+        &device_uuid,
+        &user.uuid
+    ).await?;
+
+    if let (Some(from), Some(to)) = (&filter.from, &filter.to) {
+        if from >= to {
+            Err(AppError::BadRequest(format!("Invalid time range, from: {} must be before to: {}", from, to)))?
+        }
+    }
+
+    let result = get_device_readings_data_store_query(&app_state.mongo, &device_uuid, &filter).await?;
+
+    Ok(HttpResponse::Ok().json(result))
+}
+
+pub async fn get_device_readings_aggregate(
+    credentials: BearerAuth,
+    app_state: web::Data<AppState>,
+    device_uuid: web::Path<Uuid>,
+    filter: web::Query<ReadingAggregateFilter>,
+) -> Result<HttpResponse, AppError> {
+
+    let device_uuid = device_uuid.into_inner();
+    let filter = filter.into_inner();
+
+    let token = token_info(credentials.token().to_string()).await?;
+    let user = get_user_by_uuid(&app_state.db, &token.inf.uuid).await?;
+
+    let _ = get_device_with_uuid_data_store_query(
+        &app_state.mongo,
+        &device_uuid,
+        &user.uuid
+    ).await?;
+
+    let window = AggregateWindow::from_str(&filter.window)?;
+    let timezone = parse_timezone(filter.timezone.as_deref())?;
+
+    // defaults to the last day
+    let to = filter.to.unwrap_or_else(Utc::now);
+    let from = filter.from.unwrap_or(to - Duration::days(1));
+
+    if from >= to {
+        Err(AppError::BadRequest(format!("Invalid time range, from: {} must be before to: {}", from, to)))?
+    }
+
+    if (to - from).num_milliseconds() / window.millis() > AGGREGATE_MAX_WINDOWS {
+        Err(AppError::BadRequest(format!("Too many windows, at most {} {} windows per request", AGGREGATE_MAX_WINDOWS, window)))?
+    }
+
+    let series = get_device_readings_aggregate_data_store_query(
+        &app_state.mongo,
+        &device_uuid,
+        filter.metric.as_deref(),
+        from,
+        to,
+        window,
+        timezone
+    ).await?;
+
+    let result = ReadingAggregateResponse {
+        device_uuid,
+        window,
+        timezone: timezone.name().to_string(),
+        from: from.with_timezone(&timezone).fixed_offset(),
+        to: to.with_timezone(&timezone).fixed_offset(),
+        series,
+    };
+
+    Ok(HttpResponse::Ok().json(result))
+}
+
+pub async fn get_device_readings_export(
+    credentials: BearerAuth,
+    app_state: web::Data<AppState>,
+    device_uuid: web::Path<Uuid>,
+    filter: web::Query<ReadingExportFilter>,
+) -> Result<HttpResponse, AppError> {
+
+    let device_uuid = device_uuid.into_inner();
+
+    let token = token_info(credentials.token().to_string()).await?;
+    let user = get_user_by_uuid(&app_state.db, &token.inf.uuid).await?;
+
+    let _ = get_device_with_uuid_data_store_query(
+        &app_state.mongo,
+        &device_uuid,
+        &user.uuid
+    ).await?;
+
+    export_readings(&app_state, vec![device_uuid], &filter.into_inner(), &device_uuid.to_string()).await
+}
+
+/// Bulk export of the readings of every device owned by the user.
+pub async fn get_devices_readings_export(
+    credentials: BearerAuth,
+    app_state: web::Data<AppState>,
+    filter: web::Query<ReadingExportFilter>,
+) -> Result<HttpResponse, AppError> {
+
+    let token = token_info(credentials.token().to_string()).await?;
+    let user = get_user_by_uuid(&app_state.db, &token.inf.uuid).await?;
+
+    let device_uuids = get_device_uuids_owned_data_store_query(&app_state.mongo, &user.uuid).await?;
+
+    export_readings(&app_state, device_uuids, &filter.into_inner(), "devices").await
+}
+
+async fn export_readings(
+    app_state: &web::Data<AppState>,
+    device_uuids: Vec<Uuid>,
+    filter: &ReadingExportFilter,
+    file_name: &str,
+) -> Result<HttpResponse, AppError> {
+
+    let format = match &filter.format {
+        Some(format) => ExportFormat::from_str(format)?,
+        None => ExportFormat::default(),
+    };
+    let timezone = parse_timezone(filter.timezone.as_deref())?;
+
+    if let (Some(from), Some(to)) = (&filter.from, &filter.to) {
+        if from >= to {
+            Err(AppError::BadRequest(format!("Invalid time range, from: {} must be before to: {}", from, to)))?
+        }
+    }
+
+    let units: HashMap<(String, String), String> = get_device_scale_units_query(&app_state.db, &device_uuids)
+        .await?
+        .into_iter()
+        .map(|scale| ((scale.device_uuid.to_string(), scale.metric), scale.unit))
+        .collect();
+
+    let cursor = get_device_readings_export_cursor_query(
+        &app_state.mongo,
+        &device_uuids,
+        &filter.metrics(),
+        filter.from,
+        filter.to
+    ).await?;
+
+    let stream = match export_readings_stream(cursor, format, units, timezone) {
+        Ok(stream) => stream,
+        Err(err) => Err(AppError::InternalServerError(format!("file: {}, line: {}, error: {}", file!(), line!(), err)))?,
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type(format.content_type())
+        .insert_header(("Content-Disposition", format!("attachment; filename=\"{}_readings.{}\"", file_name, format)))
+        .streaming(stream))
+}
+
+/// Stores a message received from a broker, messages that can never be
+/// stored as they are go to the dead-letter collection with the failure.
 pub async fn put_device_collection(
+    pool: &PgPool,
     client: Client,
+    broker_uuid: &Uuid,
     message: &paho_mqtt::Message
-) {
-    let decode_message = match decode_received_message(message){
-        Ok(decode) => decode,
+) -> Result<(), AppError> {
+
+    match put_device_message(pool, &client, message).await {
+        Ok(_) => Ok(()),
+        Err(IngestionFailure { reason: Some(reason), error }) => {
+            let dead_letter = DeadLetter::new(broker_uuid, message, reason, format!("{:?}", error));
+
+            if let Err(err) = post_dead_letter_query(&client, &dead_letter).await {
+                error!("file: {}, line: {}, Failed to store dead letter: {:?}", file!(), line!(), err);
+            } else {
+                info!("file: {}, line: {}, Message dead-lettered: {}, reason: {}, topic: {}",
+                    file!(),
+                    line!(),
+                    dead_letter.id,
+                    reason,
+                    message.topic()
+                );
+            }
+
+            Err(error)
+        }
+        Err(failure) => Err(failure.error),
+    }
+}
+
+/// Decodes and stores a received message, also used to replay dead letters.
+pub async fn put_device_message(
+    pool: &PgPool,
+    client: &Client,
+    message: &paho_mqtt::Message
+) -> Result<(), IngestionFailure> {
+    let properties = decode_received_properties(message);
+
+    if message.topic().ends_with(COMMAND_RESPONSE_SUFFIX) {
+        if let Some(correlation_data) = &properties.correlation_data {
+            return put_device_command_response(client.clone(), message, correlation_data).await;
+        }
+    }
+
+    let decoder = match device_decompose_topic(message.topic()) {
+        Ok(decompose) => get_device_decoder_data_store_query(client, &decompose.device_uuid).await?,
+        Err(_) => DeviceDecoder::default(),
+    };
+
+    let readings = match decode_readings(pool, message, &properties, &decoder).await{
+        Ok(readings) => readings,
         Err(err) => {
             error!("file: {}, line: {}, Failed to decode message: {:?}", file!(), line!(), err);
-            return;
+            return Err(match err {
+                AppError::NotFound(_) => IngestionFailure::dead_letter(DeadLetterReason::DeviceNotFound, err),
+                err => IngestionFailure::dead_letter(DeadLetterReason::DecodeError, err),
+            });
         }
     };
 
-    let decompose_topic = match device_decompose_topic(&decode_message.topic){
-        Ok(decompose) => decompose,
+    let mut readings = match readings
+        .into_iter()
+        .map(|reading| device_decompose_topic(&reading.topic).map(|decompose| (decompose, reading)))
+        .collect::<Result<Vec<_>, _>>(){
+        Ok(readings) => readings,
         Err(err) => {
             error!("file: {}, line: {}, Failed to decode message: {:?}", file!(), line!(), err);
-            return;
+            return Err(IngestionFailure::dead_letter(DeadLetterReason::InvalidTopic, err));
         }
     };
 
-    match update_device_messages_query(client, &decode_message, &decompose_topic).await{
+    let values = validate_readings(pool, decoder.scale_validation, &mut readings).await?;
+
+    let mut stored = Vec::with_capacity(values.len());
+
+    for ((decompose_topic, decode_message), value) in readings.into_iter().zip(values) {
+        match update_device_messages_query(client.clone(), &decode_message, value.clone(), &properties, &decompose_topic, false).await{
+            Ok(_) => {
+                publish_stored_reading(&decompose_topic, &decode_message, value.clone());
+                stored.push((decompose_topic, decode_message, value));
+            }
+            Err(err) => {
+                error!("file: {}, line: {}, Failed to update device messages: {:?}", file!(), line!(), err);
+                return Err(match err {
+                    AppError::NotFound(_) => IngestionFailure::dead_letter(DeadLetterReason::DeviceNotFound, err),
+                    AppError::BadRequest(_) => IngestionFailure::dead_letter(DeadLetterReason::DecodeError, err),
+                    err => IngestionFailure::from(err),
+                });
+            }
+        };
+    }
+
+    put_derived_readings(pool, client, &properties, &stored).await;
+
+    Ok(())
+}
+
+/// Stores the readings of the derived metrics of each device of the stored
+/// readings. The raw readings are kept whatever happens here, so failures are
+/// only logged, a retry of the message would store them twice.
+async fn put_derived_readings(
+    pool: &PgPool,
+    client: &Client,
+    properties: &MessageReceiveProperties,
+    stored: &[(DecomposeTopic, MessageReceivePayload, ReadingValue)],
+) {
+    let mut device_uuids: Vec<Uuid> = stored.iter().map(|(decompose_topic, _, _)| decompose_topic.device_uuid).collect();
+    device_uuids.sort();
+    device_uuids.dedup();
+
+    for device_uuid in device_uuids {
+        let device_readings: Vec<&(DecomposeTopic, MessageReceivePayload, ReadingValue)> = stored
+            .iter()
+            .filter(|(decompose_topic, _, _)| decompose_topic.device_uuid == device_uuid)
+            .collect();
+
+        let inputs: Vec<(&MessageReceivePayload, &ReadingValue)> = device_readings
+            .iter()
+            .map(|(_, reading, value)| (reading, value))
+            .collect();
+
+        let derived_readings = match derive_readings(pool, client, &device_uuid, &inputs).await {
+            Ok(derived_readings) => derived_readings,
+            Err(err) => {
+                error!("file: {}, line: {}, Failed to derive readings: device_uuid: {}, error: {:?}", file!(), line!(), device_uuid, err);
+                continue;
+            }
+        };
+
+        let (decompose_topic, source, _) = device_readings[0];
+
+        for derived in derived_readings {
+            let message = MessageReceivePayload {
+                topic: source.topic.clone(),
+                payload: derived.value.to_string(),
+                metric: derived.metric,
+                scale: derived.unit,
+                timestamp: derived.timestamp,
+            };
+            let value = ReadingValue::Double(derived.value);
+
+            match update_device_messages_query(client.clone(), &message, value.clone(), properties, decompose_topic, true).await {
+                Ok(_) => publish_stored_reading(decompose_topic, &message, value),
+                Err(err) => error!("file: {}, line: {}, Failed to store derived reading: device_uuid: {}, metric: {}, error: {:?}",
+                    file!(),
+                    line!(),
+                    device_uuid,
+                    message.metric,
+                    err
+                ),
+            }
+        }
+    }
+}
+
+fn publish_stored_reading(decompose_topic: &DecomposeTopic, message: &MessageReceivePayload, value: ReadingValue) {
+    if let Ok(timestamp) = DateTime::parse_from_rfc3339(&message.timestamp) {
+        publish_reading(ReadingEvent {
+            device_uuid: decompose_topic.device_uuid,
+            user_uuid: decompose_topic.user_uuid,
+            metric: message.metric.clone(),
+            value,
+            scale: message.scale.clone(),
+            timestamp,
+        });
+    }
+}
+
+/// Records the reply of an actuator to a command sent with an MQTT v5
+/// response topic, matched by its correlation data.
+async fn put_device_command_response(
+    client: Client,
+    message: &paho_mqtt::Message,
+    correlation_data: &[u8],
+) -> Result<(), IngestionFailure> {
+
+    let topic = message.topic().trim_end_matches(COMMAND_RESPONSE_SUFFIX);
+
+    let decompose_topic = match device_decompose_topic(topic){
+        Ok(decompose) => decompose,
+        Err(err) => {
+            error!("file: {}, line: {}, Failed to decode command response topic: {:?}", file!(), line!(), err);
+            return Err(IngestionFailure::dead_letter(DeadLetterReason::InvalidTopic, err));
+        }
+    };
+
+    let correlation_id = String::from_utf8_lossy(correlation_data).to_string();
+    let response = String::from_utf8_lossy(message.payload()).to_string();
+
+    match update_device_command_response_query(&client, &decompose_topic.device_uuid, &correlation_id, response.clone()).await{
         Ok(data) => data,
         Err(err) => {
-            error!("file: {}, line: {}, Failed to update device messages: {:?}", file!(), line!(), err);
-            return;
+            error!("file: {}, line: {}, Failed to update device command response: {:?}", file!(), line!(), err);
+            return Err(IngestionFailure::from(err));
         }
     };
 
+    // the topic user is trusted only when it owns the device
+    if get_device_with_uuid_data_store_query(&client, &decompose_topic.device_uuid, &decompose_topic.user_uuid).await.is_ok() {
+        publish_state(
+            StateChange::CommandAck { device_uuid: decompose_topic.device_uuid, correlation_id, response },
+            vec![decompose_topic.user_uuid],
+        );
+    }
+
+    Ok(())
 }
\ No newline at end of file
@@ -1,8 +1,13 @@
 use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
 use chrono::{FixedOffset, Utc};
 use serde::{Deserialize, Serialize};
 use mongodb::bson::{DateTime as BsonDateTime};
 use uuid::Uuid;
+use crate::device::device_message_model::ScaleDataType;
+use crate::error_app::error_app::AppError;
+use crate::ingestion::ingestion_model::DeviceDecoder;
 
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -12,11 +17,242 @@ pub enum MessageDirection {
     Sent,
 }
 
+/// Reading value stored as typed BSON (double, int64, bool or string).
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum ReadingValue {
+    Bool(bool),
+    Int(i64),
+    Double(f64),
+    Text(String),
+}
+
+impl ReadingValue {
+    /// Parses a decoded value by the metric declared data type, without one
+    /// the type is inferred: bool literals, then finite numbers, then text.
+    pub fn parse(value: &str, data_type: Option<ScaleDataType>) -> Result<Self, String> {
+        let trimmed = value.trim();
+
+        match data_type {
+            Some(ScaleDataType::Double) => match trimmed.parse::<f64>() {
+                Ok(number) if number.is_finite() => Ok(ReadingValue::Double(number)),
+                _ => Err(format!("Value is not a double: {}", value)),
+            },
+            Some(ScaleDataType::Int) => match trimmed.parse::<i64>() {
+                Ok(number) => Ok(ReadingValue::Int(number)),
+                Err(_) => match trimmed.parse::<f64>() {
+                    Ok(number) if number.fract() == 0.0 && number.abs() < i64::MAX as f64 => Ok(ReadingValue::Int(number as i64)),
+                    _ => Err(format!("Value is not an int: {}", value)),
+                },
+            },
+            Some(ScaleDataType::Bool) => match parse_bool(trimmed) {
+                Some(flag) => Ok(ReadingValue::Bool(flag)),
+                None => Err(format!("Value is not a bool: {}", value)),
+            },
+            Some(ScaleDataType::String) => Ok(ReadingValue::Text(value.to_string())),
+            None => {
+                if trimmed.eq_ignore_ascii_case("true") || trimmed.eq_ignore_ascii_case("false") {
+                    return Ok(ReadingValue::Bool(trimmed.eq_ignore_ascii_case("true")));
+                }
+
+                match trimmed.parse::<f64>() {
+                    Ok(number) if number.is_finite() => Ok(ReadingValue::Double(number)),
+                    _ => Ok(ReadingValue::Text(value.to_string())),
+                }
+            }
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            ReadingValue::Int(number) => Some(*number as f64),
+            ReadingValue::Double(number) => Some(*number),
+            ReadingValue::Bool(flag) => Some(if *flag { 1.0 } else { 0.0 }),
+            ReadingValue::Text(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for ReadingValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReadingValue::Bool(flag) => write!(f, "{}", flag),
+            ReadingValue::Int(number) => write!(f, "{}", number),
+            ReadingValue::Double(number) => write!(f, "{}", number),
+            ReadingValue::Text(text) => write!(f, "{}", text),
+        }
+    }
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value.to_lowercase().as_str() {
+        "true" | "1" | "on" => Some(true),
+        "false" | "0" | "off" => Some(false),
+        _ => None,
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct DeviceMessageReceived {
-    pub value: String,
+    pub value: ReadingValue,
+    pub scale: String,
+    pub timestamp: chrono::DateTime<FixedOffset>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_properties: Option<HashMap<String, String>>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub derived: bool,
+}
+
+/// Readings of a device are stored one document per device, metric and hour
+/// in the `readings` collection, so no document approaches the size limit.
+pub const READING_BUCKET_MILLIS: i64 = 3_600_000;
+
+/// Readings per bucket, a device reporting faster opens further buckets of
+/// the same hour, numbered by `seq`.
+pub const READING_BUCKET_MAX_READINGS: i64 = 1_000;
+
+/// Tries to push a reading before giving up on buckets filled concurrently.
+pub const READING_BUCKET_PUSH_ATTEMPTS: usize = 5;
+
+pub fn reading_bucket_start(at: &BsonDateTime) -> BsonDateTime {
+    let millis = at.timestamp_millis();
+    BsonDateTime::from_millis(millis - millis.rem_euclid(READING_BUCKET_MILLIS))
+}
+
+/// A reading as stored in a bucket, `at` is the timestamp as a BSON date used
+/// for sorting and range queries.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct StoredReading {
+    pub value: ReadingValue,
     pub scale: String,
     pub timestamp: chrono::DateTime<FixedOffset>,
+    pub at: BsonDateTime,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_properties: Option<HashMap<String, String>>,
+    /// Computed at ingestion by a derived metric of the device.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub derived: bool,
+}
+
+impl From<DeviceMessageReceived> for StoredReading {
+    fn from(reading: DeviceMessageReceived) -> Self {
+        StoredReading {
+            at: BsonDateTime::from_millis(reading.timestamp.timestamp_millis()),
+            value: reading.value,
+            scale: reading.scale,
+            timestamp: reading.timestamp,
+            content_type: reading.content_type,
+            user_properties: reading.user_properties,
+            derived: reading.derived,
+        }
+    }
+}
+
+impl From<StoredReading> for DeviceMessageReceived {
+    fn from(reading: StoredReading) -> Self {
+        DeviceMessageReceived {
+            value: reading.value,
+            scale: reading.scale,
+            timestamp: reading.timestamp,
+            content_type: reading.content_type,
+            user_properties: reading.user_properties,
+            derived: reading.derived,
+        }
+    }
+}
+
+/// Latest reading of one metric of a device, kept up to date by ingestion in
+/// the `latest_readings` collection under the `device_uuid:metric` id.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LatestReading {
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub device_uuid: String,
+    pub user_uuid: String,
+    pub metric: String,
+    pub value: ReadingValue,
+    pub scale: String,
+    pub timestamp: chrono::DateTime<FixedOffset>,
+    pub at: BsonDateTime,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_properties: Option<HashMap<String, String>>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub derived: bool,
+}
+
+impl LatestReading {
+    pub fn new(device_uuid: &Uuid, user_uuid: &Uuid, metric: &str, reading: StoredReading) -> Self {
+        LatestReading {
+            id: format!("{}:{}", device_uuid, metric),
+            device_uuid: device_uuid.to_string(),
+            user_uuid: user_uuid.to_string(),
+            metric: metric.to_string(),
+            value: reading.value,
+            scale: reading.scale,
+            timestamp: reading.timestamp,
+            at: reading.at,
+            content_type: reading.content_type,
+            user_properties: reading.user_properties,
+            derived: reading.derived,
+        }
+    }
+}
+
+impl From<LatestReading> for DeviceMessageReceived {
+    fn from(reading: LatestReading) -> Self {
+        DeviceMessageReceived {
+            value: reading.value,
+            scale: reading.scale,
+            timestamp: reading.timestamp,
+            content_type: reading.content_type,
+            user_properties: reading.user_properties,
+            derived: reading.derived,
+        }
+    }
+}
+
+/// Readings of one metric of a device within one bucket.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReadingBucket {
+    pub device_uuid: String,
+    pub user_uuid: String,
+    pub metric: String,
+    pub bucket_start: BsonDateTime,
+    /// Position of the bucket within its hour, missing on buckets written
+    /// before buckets were capped.
+    #[serde(default)]
+    pub seq: i64,
+    pub first_at: BsonDateTime,
+    pub last_at: BsonDateTime,
+    pub count: i64,
+    #[serde(default)]
+    pub readings: Vec<StoredReading>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DeviceCommandSent {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command_id: Option<String>,
+    pub value: i32,
+    pub direction: MessageDirection,
+    pub timestamp: chrono::DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response_topic: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub responded_at: Option<chrono::DateTime<Utc>>,
+    /// Set when the publish failed, the command never reached the broker.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub publish_error: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -27,7 +263,9 @@ pub struct DeviceData {
     pub user_uuid: String,
     pub topic: String,
     #[serde(default)]
-    pub messages: Vec<DeviceMessageReceived>,
+    pub blocked: bool,
+    #[serde(flatten)]
+    pub decoder: DeviceDecoder,
     pub created_at: BsonDateTime,
     pub updated_at: Option<BsonDateTime>,
     pub deleted_at: Option<BsonDateTime>,
@@ -47,4 +285,288 @@ pub struct DeviceDataStoreResponse {
 pub struct DeviceMessagesOwned {
     pub device_uuid: String,
     pub messages: HashMap<String, DeviceMessageReceived>,
-}
\ No newline at end of file
+}
+pub const READINGS_DEFAULT_LIMIT: i64 = 100;
+pub const READINGS_MAX_LIMIT: i64 = 1000;
+
+#[derive(Debug, Deserialize)]
+pub struct ReadingFilter {
+    pub metric: Option<String>,
+    pub from: Option<chrono::DateTime<Utc>>,
+    pub to: Option<chrono::DateTime<Utc>>,
+    pub limit: Option<i64>,
+    pub cursor: Option<String>,
+}
+
+/// Position after the last reading of a page: its timestamp, metric and index
+/// in the bucket, encoded as an opaque hex string.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReadingCursor {
+    pub at: i64,
+    pub metric: String,
+    pub index: i64,
+}
+
+impl ReadingCursor {
+    pub fn encode(&self) -> String {
+        format!("{}:{}:{}", self.at, self.index, self.metric)
+            .bytes()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    pub fn decode(cursor: &str) -> Option<Self> {
+        if cursor.len() % 2 != 0 {
+            return None;
+        }
+
+        let bytes = (0..cursor.len())
+            .step_by(2)
+            .map(|i| cursor.get(i..i + 2).and_then(|hex| u8::from_str_radix(hex, 16).ok()))
+            .collect::<Option<Vec<u8>>>()?;
+
+        let text = String::from_utf8(bytes).ok()?;
+        let mut parts = text.splitn(3, ':');
+
+        Some(ReadingCursor {
+            at: parts.next()?.parse().ok()?,
+            index: parts.next()?.parse().ok()?,
+            metric: parts.next()?.to_string(),
+        })
+    }
+}
+
+/// A reading unwound from its bucket, with its index in the bucket.
+#[derive(Debug, Deserialize)]
+pub struct ReadingRow {
+    pub metric: String,
+    pub index: i64,
+    pub reading: StoredReading,
+}
+
+impl From<ReadingRow> for ReadingResponse {
+    fn from(row: ReadingRow) -> Self {
+        ReadingResponse {
+            metric: row.metric,
+            value: row.reading.value,
+            scale: row.reading.scale,
+            timestamp: row.reading.timestamp,
+            content_type: row.reading.content_type,
+            user_properties: row.reading.user_properties,
+            derived: row.reading.derived,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReadingResponse {
+    pub metric: String,
+    pub value: ReadingValue,
+    pub scale: String,
+    pub timestamp: chrono::DateTime<FixedOffset>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_properties: Option<HashMap<String, String>>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub derived: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReadingPaginationResponse {
+    pub device_uuid: Uuid,
+    pub readings: Vec<ReadingResponse>,
+    pub limit: i64,
+    pub next_cursor: Option<String>,
+    pub has_next_page: bool,
+}
+
+/// Maximum number of windows of one metric in an aggregation.
+pub const AGGREGATE_MAX_WINDOWS: i64 = 10_000;
+
+/// Fixed window of a readings aggregation.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum AggregateWindow {
+    #[serde(rename = "1m")]
+    OneMinute,
+    #[serde(rename = "5m")]
+    FiveMinutes,
+    #[serde(rename = "1h")]
+    OneHour,
+    #[serde(rename = "1d")]
+    OneDay,
+}
+
+impl AggregateWindow {
+    /// `$dateTrunc` unit and bin size of the window.
+    pub fn date_trunc(&self) -> (&'static str, i32) {
+        match self {
+            AggregateWindow::OneMinute => ("minute", 1),
+            AggregateWindow::FiveMinutes => ("minute", 5),
+            AggregateWindow::OneHour => ("hour", 1),
+            AggregateWindow::OneDay => ("day", 1),
+        }
+    }
+
+    pub fn millis(&self) -> i64 {
+        match self {
+            AggregateWindow::OneMinute => 60_000,
+            AggregateWindow::FiveMinutes => 300_000,
+            AggregateWindow::OneHour => 3_600_000,
+            AggregateWindow::OneDay => 86_400_000,
+        }
+    }
+}
+
+impl FromStr for AggregateWindow {
+    type Err = AppError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "1m" => Ok(AggregateWindow::OneMinute),
+            "5m" => Ok(AggregateWindow::FiveMinutes),
+            "1h" => Ok(AggregateWindow::OneHour),
+            "1d" => Ok(AggregateWindow::OneDay),
+            _ => Err(AppError::BadRequest(format!("Invalid window: {}, expected 1m, 5m, 1h or 1d", s)))?
+        }
+    }
+}
+
+impl fmt::Display for AggregateWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            AggregateWindow::OneMinute => "1m",
+            AggregateWindow::FiveMinutes => "5m",
+            AggregateWindow::OneHour => "1h",
+            AggregateWindow::OneDay => "1d",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReadingAggregateFilter {
+    pub metric: Option<String>,
+    pub from: Option<chrono::DateTime<Utc>>,
+    pub to: Option<chrono::DateTime<Utc>>,
+    pub window: String,
+    pub timezone: Option<String>,
+}
+
+/// Statistics of one window, min, max, avg and sum only take numeric values.
+#[derive(Debug, Serialize)]
+pub struct ReadingAggregateWindow {
+    pub window_start: chrono::DateTime<FixedOffset>,
+    pub count: i64,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    pub avg: Option<f64>,
+    pub sum: Option<f64>,
+    pub first: Option<ReadingValue>,
+    pub last: Option<ReadingValue>,
+}
+
+/// One metric window as returned by the aggregation pipeline.
+#[derive(Debug, Deserialize)]
+pub struct ReadingAggregateRow {
+    pub metric: String,
+    pub window_start: BsonDateTime,
+    pub count: i64,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    pub avg: Option<f64>,
+    pub sum: Option<f64>,
+    pub first: Option<ReadingValue>,
+    pub last: Option<ReadingValue>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReadingAggregateSeries {
+    pub metric: String,
+    pub windows: Vec<ReadingAggregateWindow>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReadingAggregateResponse {
+    pub device_uuid: Uuid,
+    pub window: AggregateWindow,
+    pub timezone: String,
+    pub from: chrono::DateTime<FixedOffset>,
+    pub to: chrono::DateTime<FixedOffset>,
+    pub series: Vec<ReadingAggregateSeries>,
+}
+
+/// Format of a readings export.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    Csv,
+    Parquet,
+    Ndjson,
+}
+
+impl ExportFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+            ExportFormat::Parquet => "application/vnd.apache.parquet",
+            ExportFormat::Ndjson => "application/x-ndjson",
+        }
+    }
+}
+
+impl FromStr for ExportFormat {
+    type Err = AppError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "csv" => Ok(ExportFormat::Csv),
+            "parquet" => Ok(ExportFormat::Parquet),
+            "ndjson" => Ok(ExportFormat::Ndjson),
+            _ => Err(AppError::BadRequest(format!("Invalid export format: {}, expected csv, parquet or ndjson", s)))?
+        }
+    }
+}
+
+impl fmt::Display for ExportFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Parquet => "parquet",
+            ExportFormat::Ndjson => "ndjson",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// `metrics` is a comma separated list of metrics, all metrics when missing.
+#[derive(Debug, Deserialize)]
+pub struct ReadingExportFilter {
+    pub format: Option<String>,
+    pub from: Option<chrono::DateTime<Utc>>,
+    pub to: Option<chrono::DateTime<Utc>>,
+    pub metrics: Option<String>,
+    pub timezone: Option<String>,
+}
+
+impl ReadingExportFilter {
+    pub fn metrics(&self) -> Vec<String> {
+        match &self.metrics {
+            Some(metrics) => metrics
+                .split(',')
+                .map(|metric| metric.trim().to_string())
+                .filter(|metric| !metric.is_empty())
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+/// A reading as read by the export pipeline.
+#[derive(Debug, Deserialize)]
+pub struct ReadingExportRow {
+    pub device_uuid: String,
+    pub metric: String,
+    pub value: ReadingValue,
+    pub scale: String,
+    pub at: BsonDateTime,
+}
@@ -1,14 +1,19 @@
+use std::collections::HashMap;
 use log::{error, info};
-use mongodb::{Client, Collection};
+use mongodb::{Client, Collection, Cursor};
 use mongodb::bson::{doc, from_document, to_document, Bson};
+use mongodb::error::{ErrorKind, WriteFailure};
 use mongodb::options::FindOneOptions;
 use mongodb::bson::{DateTime as BsonDateTime};
 use uuid::Uuid;
-use crate::data_store::data_store_device_model::{DeviceData, DeviceMessageReceived, DeviceMessagesOwned};
+use crate::data_store::data_store_device_model::{reading_bucket_start, READING_BUCKET_MAX_READINGS, READING_BUCKET_PUSH_ATTEMPTS, AggregateWindow, ReadingAggregateRow, ReadingAggregateSeries, ReadingAggregateWindow, DeviceCommandSent, DeviceData, DeviceMessageReceived, DeviceMessagesOwned, LatestReading, ReadingCursor, ReadingFilter, ReadingPaginationResponse, ReadingResponse, ReadingRow, ReadingValue, StoredReading, READINGS_DEFAULT_LIMIT, READINGS_MAX_LIMIT};
 use crate::device::device_adoption_tool::DecomposeTopic;
-use crate::device::device_message_model::MessageReceivePayload;
+use crate::device::device_message_model::{MessageReceivePayload, MessageReceiveProperties};
 use crate::error_app::error_app::{AppError, AppMsgError, AppMsgInfError};
-use chrono::DateTime;
+use crate::ingestion::ingestion_model::{DeviceDecoder, ScaleValidation};
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use crate::data_store::data_store_tool::bson_to_chrono;
 use futures_util::TryStreamExt;
 
 pub async fn post_device_data_store_query(
@@ -19,8 +24,7 @@ pub async fn post_device_data_store_query(
     let database = client.database("devices");
     let collection: Collection<mongodb::bson::Document> = database.collection("devices");
 
-    // Converter DeviceData para Document, mas garantir que messages seja objeto {} em vez de array []
-    let mut doc = match to_document(&device) {
+    let doc = match to_document(&device) {
         Ok(doc) => doc,
         Err(e) => {
             return Err(AppError::MongoDBError(AppMsgInfError {
@@ -32,9 +36,6 @@ pub async fn post_device_data_store_query(
         }
     };
 
-    // Garantir que messages seja sempre um objeto vazio {}, não array []
-    doc.insert("messages", mongodb::bson::Bson::Document(mongodb::bson::Document::new()));
-
     collection.insert_one(doc).await.map_err(|e| AppError::MongoDBError(AppMsgInfError {
         file: file!().to_string(),
         line: line!(),
@@ -97,7 +98,10 @@ pub async fn get_device_with_uuid_data_store_query(
 pub async fn update_device_messages_query(
     client: Client,
     message: &MessageReceivePayload,
-    decompose_topic: &DecomposeTopic
+    value: ReadingValue,
+    properties: &MessageReceiveProperties,
+    decompose_topic: &DecomposeTopic,
+    derived: bool,
 ) -> Result<(), AppError> {
     info!(
         "file: {}, line: {}, message: {:?}",
@@ -109,9 +113,12 @@ pub async fn update_device_messages_query(
     let database = client.database("devices");
     let collection: Collection<mongodb::bson::Document> = database.collection("devices");
 
+    // deleted or blocked devices stop ingesting
     let filter = doc! {
         "_id": decompose_topic.device_uuid.to_string(),
         "user_uuid": decompose_topic.user_uuid.to_string(),
+        "deleted_at": Bson::Null,
+        "blocked": { "$ne": true },
     };
 
     let dt = match DateTime::parse_from_rfc3339(&message.timestamp){
@@ -123,12 +130,20 @@ pub async fn update_device_messages_query(
     };
 
     let message_received = DeviceMessageReceived {
-        value: message.payload.clone(),
+        value,
         scale: message.scale.clone(),
         timestamp: dt,
+        content_type: properties.content_type.clone(),
+        user_properties: if properties.user_properties.is_empty() {
+            None
+        } else {
+            Some(properties.user_properties.clone())
+        },
+        derived,
     };
 
-    let message_bson = match mongodb::bson::to_bson(&message_received) {
+    let reading = StoredReading::from(message_received);
+    let reading_bson = match mongodb::bson::to_bson(&reading) {
         Ok(bson) => bson,
         Err(error) => {
             Err(AppError::MongoDBError(AppMsgInfError {
@@ -140,34 +155,316 @@ pub async fn update_device_messages_query(
         }
     };
 
-    let push_path = format!("messages.{}", message.metric);
-
-    let _ = collection.update_one(
-        doc! {
-            "_id": decompose_topic.device_uuid.to_string(),
-            "user_uuid": decompose_topic.user_uuid.to_string(),
-            "$or": [
-                { "messages": { "$exists": false } },
-                { "messages": { "$type": "array" } }
-            ]
-        },
+    let result = collection.update_one(
+        filter,
         doc! {
             "$set": {
-                "messages": {}
+                "updated_at": BsonDateTime::now()
             }
         }
-    ).await;
+    ).await.map_err(|e| {
+        AppError::MongoDBError(AppMsgInfError {
+            file: file!().into(),
+            line: line!(),
+            api_msg_error: "Internal server error".into(),
+            log_msg_error: e.to_string(),
+        })
+    })?;
+
+    if result.matched_count == 0 {
+        Err(AppError::NotFound(AppMsgError {
+            api_msg_error: "Device not found".into(),
+            log_msg_error: format!(
+                "file: {}, line: {}, Device not found: device_uuid: {}, user_uuid: {}",
+                file!(),
+                line!(),
+                decompose_topic.device_uuid,
+                decompose_topic.user_uuid,
+            ),
+        }))?;
+    }
+
+    let readings: Collection<mongodb::bson::Document> = database.collection("readings");
+
+    put_reading_bucket_query(
+        &readings,
+        &decompose_topic.device_uuid.to_string(),
+        &decompose_topic.user_uuid.to_string(),
+        &message.metric,
+        &reading,
+        reading_bson,
+    ).await?;
+
+    // the reading is stored, a failed cache update must not ingest it twice
+    let latest = LatestReading::new(&decompose_topic.device_uuid, &decompose_topic.user_uuid, &message.metric, reading);
+
+    if let Err(err) = put_latest_reading_query(&database, latest).await {
+        error!("file: {}, line: {}, Failed to update latest reading: {:?}", file!(), line!(), err);
+    }
+
+    Ok(())
+}
+
+/// Pushes the reading to an open bucket of its hour. A full bucket rolls over
+/// to the next `seq` of the hour, two writers opening the same one collide on
+/// the unique bucket index and the loser pushes again.
+async fn put_reading_bucket_query(
+    readings: &Collection<mongodb::bson::Document>,
+    device_uuid: &str,
+    user_uuid: &str,
+    metric: &str,
+    reading: &StoredReading,
+    reading_bson: Bson,
+) -> Result<(), AppError> {
+
+    let bucket_start = reading_bucket_start(&reading.at);
+
+    let update = doc! {
+        "$push": { "readings": reading_bson },
+        "$inc": { "count": 1_i64 },
+        "$min": { "first_at": reading.at },
+        "$max": { "last_at": reading.at },
+        "$setOnInsert": { "user_uuid": user_uuid },
+    };
+
+    let mongo_error = |line: u32, error: mongodb::error::Error| AppError::MongoDBError(AppMsgInfError {
+        file: file!().into(),
+        line,
+        api_msg_error: "Internal server error".into(),
+        log_msg_error: error.to_string(),
+    });
+
+    for _ in 0..READING_BUCKET_PUSH_ATTEMPTS {
+        let result = readings.update_one(
+            doc! {
+                "device_uuid": device_uuid,
+                "metric": metric,
+                "bucket_start": bucket_start,
+                "count": { "$lt": READING_BUCKET_MAX_READINGS },
+            },
+            update.clone(),
+        ).await.map_err(|e| mongo_error(line!(), e))?;
+
+        if result.matched_count > 0 {
+            return Ok(());
+        }
+
+        let last = readings
+            .find_one(doc! { "device_uuid": device_uuid, "metric": metric, "bucket_start": bucket_start })
+            .sort(doc! { "seq": -1 })
+            .projection(doc! { "seq": 1 })
+            .await
+            .map_err(|e| mongo_error(line!(), e))?;
+
+        // buckets written before the rollover have no seq, they count as 0
+        let seq = match last {
+            Some(last) => last.get_i64("seq").unwrap_or(0) + 1,
+            None => 0,
+        };
+
+        let result = readings.update_one(
+            doc! {
+                "device_uuid": device_uuid,
+                "metric": metric,
+                "bucket_start": bucket_start,
+                "seq": seq,
+                "count": { "$lt": READING_BUCKET_MAX_READINGS },
+            },
+            update.clone(),
+        ).upsert(true).await;
+
+        match result {
+            Ok(_) => return Ok(()),
+            Err(e) if is_duplicate_key(&e) => continue,
+            Err(e) => Err(mongo_error(line!(), e))?,
+        }
+    }
+
+    Err(AppError::MongoDBError(AppMsgInfError {
+        file: file!().into(),
+        line: line!(),
+        api_msg_error: "Internal server error".into(),
+        log_msg_error: format!("No open reading bucket after {} attempts: device_uuid: {}, metric: {}", READING_BUCKET_PUSH_ATTEMPTS, device_uuid, metric),
+    }))
+}
+
+fn is_duplicate_key(error: &mongodb::error::Error) -> bool {
+    matches!(
+        &*error.kind,
+        ErrorKind::Write(WriteFailure::WriteError(write_error)) if write_error.code == 11000
+    )
+}
+
+/// Replaces the cached latest reading of the metric unless the cached one is
+/// newer, readings arriving out of order keep the most recent value.
+async fn put_latest_reading_query(
+    database: &mongodb::Database,
+    latest: LatestReading,
+) -> Result<(), AppError> {
+
+    let collection: Collection<mongodb::bson::Document> = database.collection("latest_readings");
+
+    let latest_doc = match to_document(&latest) {
+        Ok(doc) => doc,
+        Err(error) => {
+            Err(AppError::MongoDBError(AppMsgInfError {
+                file: file!().into(),
+                line: line!(),
+                api_msg_error: "Internal server error".into(),
+                log_msg_error: format!("file: {}, line: {}, error: {}", file!(), line!(), error)
+            }))?
+        }
+    };
+
+    // $literal keeps string values starting with `$` from reading as field paths
+    collection.update_one(
+        doc! { "_id": latest.id.as_str() },
+        vec![doc! {
+            "$replaceWith": {
+                "$cond": [
+                    { "$gt": [{ "$ifNull": ["$at", BsonDateTime::MIN] }, latest.at] },
+                    "$$ROOT",
+                    { "$literal": latest_doc }
+                ]
+            }
+        }]
+    ).upsert(true).await.map_err(|e| {
+        AppError::MongoDBError(AppMsgInfError {
+            file: file!().into(),
+            line: line!(),
+            api_msg_error: "Internal server error".into(),
+            log_msg_error: e.to_string(),
+        })
+    })?;
+
+    Ok(())
+}
+
+pub async fn post_device_command_query(
+    client: &Client,
+    device_uuid: &Uuid,
+    command: &DeviceCommandSent,
+) -> Result<(), AppError> {
+
+    let database = client.database("devices");
+    let collection: Collection<mongodb::bson::Document> = database.collection("devices");
+
+    let command_bson = match mongodb::bson::to_bson(command) {
+        Ok(bson) => bson,
+        Err(error) => {
+            Err(AppError::MongoDBError(AppMsgInfError {
+                file: file!().into(),
+                line: line!(),
+                api_msg_error: "Internal server error".into(),
+                log_msg_error: format!("file: {}, line: {}, error: {}", file!(), line!(), error)
+            }))?
+        }
+    };
 
     let update = doc! {
         "$push": {
-            &push_path: message_bson
+            "commands": command_bson
         },
         "$set": {
             "updated_at": BsonDateTime::now()
         }
     };
 
-    let result = collection.update_one(filter, update).await.map_err(|e| {
+    let result = collection.update_one(doc! { "_id": device_uuid.to_string() }, update)
+        .await
+        .map_err(|e| {
+            AppError::MongoDBError(AppMsgInfError {
+                file: file!().into(),
+                line: line!(),
+                api_msg_error: "Internal server error".into(),
+                log_msg_error: e.to_string(),
+            })
+        })?;
+
+    if result.matched_count == 0 {
+        Err(AppError::NotFound(AppMsgError {
+            api_msg_error: "Device not found".into(),
+            log_msg_error: format!(
+                "file: {}, line: {}, Device not found: device_uuid: {}",
+                file!(),
+                line!(),
+                device_uuid,
+            ),
+        }))?;
+    }
+
+    Ok(())
+}
+
+/// Marks a command stored before its publish as failed.
+pub async fn put_device_command_failed_query(
+    client: &Client,
+    device_uuid: &Uuid,
+    command_id: &str,
+    error: String,
+) -> Result<(), AppError> {
+
+    let database = client.database("devices");
+    let collection: Collection<mongodb::bson::Document> = database.collection("devices");
+
+    collection.update_one(
+        doc! {
+            "_id": device_uuid.to_string(),
+            "commands.command_id": command_id,
+        },
+        doc! {
+            "$set": {
+                "commands.$.publish_error": error,
+                "updated_at": BsonDateTime::now()
+            }
+        }
+    ).await.map_err(|e| {
+        AppError::MongoDBError(AppMsgInfError {
+            file: file!().into(),
+            line: line!(),
+            api_msg_error: "Internal server error".into(),
+            log_msg_error: e.to_string(),
+        })
+    })?;
+
+    Ok(())
+}
+
+pub async fn update_device_command_response_query(
+    client: &Client,
+    device_uuid: &Uuid,
+    correlation_id: &str,
+    response: String,
+) -> Result<(), AppError> {
+
+    let database = client.database("devices");
+    let collection: Collection<mongodb::bson::Document> = database.collection("devices");
+
+    let responded_at = match mongodb::bson::to_bson(&Utc::now()) {
+        Ok(bson) => bson,
+        Err(error) => {
+            Err(AppError::MongoDBError(AppMsgInfError {
+                file: file!().into(),
+                line: line!(),
+                api_msg_error: "Internal server error".into(),
+                log_msg_error: format!("file: {}, line: {}, error: {}", file!(), line!(), error)
+            }))?
+        }
+    };
+
+    let result = collection.update_one(
+        doc! {
+            "_id": device_uuid.to_string(),
+            "commands.correlation_id": correlation_id,
+        },
+        doc! {
+            "$set": {
+                "commands.$.response": response,
+                "commands.$.responded_at": responded_at,
+                "updated_at": BsonDateTime::now()
+            }
+        }
+    ).await.map_err(|e| {
         AppError::MongoDBError(AppMsgInfError {
             file: file!().into(),
             line: line!(),
@@ -178,13 +475,13 @@ pub async fn update_device_messages_query(
 
     if result.matched_count == 0 {
         Err(AppError::NotFound(AppMsgError {
-            api_msg_error: "Device not found".into(),
+            api_msg_error: "Command not found".into(),
             log_msg_error: format!(
-                "file: {}, line: {}, Device not found: device_uuid: {}, user_uuid: {}",
+                "file: {}, line: {}, Command not found: device_uuid: {}, correlation_id: {}",
                 file!(),
                 line!(),
-                decompose_topic.device_uuid,
-                decompose_topic.user_uuid,
+                device_uuid,
+                correlation_id,
             ),
         }))?;
     }
@@ -192,122 +489,451 @@ pub async fn update_device_messages_query(
     Ok(())
 }
 
+pub async fn delete_device_data_store_query(
+    client: &Client,
+    device_uuid: &Uuid,
+) -> Result<(), AppError> {
+
+    let database = client.database("devices");
+    let collection: Collection<mongodb::bson::Document> = database.collection("devices");
+
+    let now = BsonDateTime::now();
+
+    collection.update_one(
+        doc! { "_id": device_uuid.to_string() },
+        doc! {
+            "$set": {
+                "deleted_at": now,
+                "updated_at": now
+            }
+        }
+    ).await.map_err(|e| {
+        AppError::MongoDBError(AppMsgInfError {
+            file: file!().into(),
+            line: line!(),
+            api_msg_error: "Internal server error".into(),
+            log_msg_error: e.to_string(),
+        })
+    })?;
+
+    // deleted devices keep their buckets until pruned, not their current state
+    let latest: Collection<mongodb::bson::Document> = database.collection("latest_readings");
+
+    latest.delete_many(
+        doc! { "device_uuid": device_uuid.to_string() }
+    ).await.map_err(|e| {
+        AppError::MongoDBError(AppMsgInfError {
+            file: file!().into(),
+            line: line!(),
+            api_msg_error: "Internal server error".into(),
+            log_msg_error: e.to_string(),
+        })
+    })?;
+
+    Ok(())
+}
+
+pub async fn put_device_data_store_blocked_query(
+    client: &Client,
+    device_uuid: &Uuid,
+    blocked: bool,
+) -> Result<(), AppError> {
+
+    let database = client.database("devices");
+    let collection: Collection<mongodb::bson::Document> = database.collection("devices");
+
+    collection.update_one(
+        doc! { "_id": device_uuid.to_string() },
+        doc! {
+            "$set": {
+                "blocked": blocked,
+                "updated_at": BsonDateTime::now()
+            }
+        }
+    ).await.map_err(|e| {
+        AppError::MongoDBError(AppMsgInfError {
+            file: file!().into(),
+            line: line!(),
+            api_msg_error: "Internal server error".into(),
+            log_msg_error: e.to_string(),
+        })
+    })?;
+
+    Ok(())
+}
+
+/// Decoder of a device, a missing device falls back to the default decoder
+/// and is reported as not found once the reading is stored.
+pub async fn get_device_decoder_data_store_query(
+    client: &Client,
+    device_uuid: &Uuid,
+) -> Result<DeviceDecoder, AppError> {
+
+    let database = client.database("devices");
+    let collection: Collection<DeviceDecoder> = database.collection("devices");
+
+    let field_response = FindOneOptions::builder()
+        .projection(doc! { "_id": 0, "payload_format": 1, "payload_metric": 1, "scale_validation": 1 })
+        .build();
+
+    match collection.find_one(doc! { "_id": device_uuid.to_string() }).with_options(field_response).await {
+        Ok(decoder) => Ok(decoder.unwrap_or_default()),
+        Err(e) => Err(AppError::MongoDBError(
+            AppMsgInfError{
+                file: file!().to_string(),
+                line: line!(),
+                api_msg_error: "Internal server error".into(),
+                log_msg_error: e.to_string(),
+            }))?,
+    }
+}
+
+pub async fn put_device_decoder_data_store_query(
+    client: &Client,
+    device_uuid: &Uuid,
+    decoder: &DeviceDecoder,
+) -> Result<(), AppError> {
+
+    let database = client.database("devices");
+    let collection: Collection<mongodb::bson::Document> = database.collection("devices");
+
+    collection.update_one(
+        doc! { "_id": device_uuid.to_string() },
+        doc! {
+            "$set": {
+                "payload_format": decoder.payload_format.to_string(),
+                "payload_metric": decoder.payload_metric.clone(),
+                "updated_at": BsonDateTime::now()
+            }
+        }
+    ).await.map_err(|e| {
+        AppError::MongoDBError(AppMsgInfError {
+            file: file!().into(),
+            line: line!(),
+            api_msg_error: "Internal server error".into(),
+            log_msg_error: e.to_string(),
+        })
+    })?;
+
+    Ok(())
+}
+
+pub async fn put_device_scale_validation_data_store_query(
+    client: &Client,
+    device_uuid: &Uuid,
+    scale_validation: &ScaleValidation,
+) -> Result<(), AppError> {
+
+    let database = client.database("devices");
+    let collection: Collection<mongodb::bson::Document> = database.collection("devices");
+
+    collection.update_one(
+        doc! { "_id": device_uuid.to_string() },
+        doc! {
+            "$set": {
+                "scale_validation": scale_validation.to_string(),
+                "updated_at": BsonDateTime::now()
+            }
+        }
+    ).await.map_err(|e| {
+        AppError::MongoDBError(AppMsgInfError {
+            file: file!().into(),
+            line: line!(),
+            api_msg_error: "Internal server error".into(),
+            log_msg_error: e.to_string(),
+        })
+    })?;
+
+    Ok(())
+}
+
+/// Latest reading of each metric of the devices, read from the cache kept by
+/// ingestion, devices without readings are left out.
 pub async fn get_message_data_store_query(
     client: &Client,
     device_uuids: Vec<Uuid>,
 )-> Result<Vec<DeviceMessagesOwned>, AppError>{
 
     let database = client.database("devices");
+    let collection: Collection<LatestReading> = database.collection("latest_readings");
 
     let devices_uuid: Vec<String> = device_uuids
         .iter()
         .map(|u| u.to_string())
         .collect();
 
-    let pipeline = vec![
-        //match - user device_uuid or _id fallback
-        doc! {
-            "$match": {
-                "$or": [
-                    { "device_uuid": { "$in": &devices_uuid } },
-                    { "_id": { "$in": &devices_uuid } }
-                ],
-                "deleted_at": Bson::Null
-            }
-        },
+    let cursor = match collection
+        .find(doc! { "device_uuid": { "$in": &devices_uuid } })
+        .await{
+            Ok(cursor) => cursor,
+            Err(e) => Err(AppError::MongoDBError(
+                AppMsgInfError{
+                    file: file!().to_string(),
+                    line: line!(),
+                    api_msg_error: "Internal server error".into(),
+                    log_msg_error: e.to_string(),
+                }))?,
+        };
 
-        //use device_uuid if exists, or _id
-        doc! {
-            "$project": {
-                "device_uuid": {
-                    "$ifNull": ["$device_uuid", "$_id"]
-                },
-                "messages": {
-                    "$ifNull": ["$messages", {}]
-                }
-            }
+    let results: Vec<LatestReading> =
+        match cursor.try_collect().await{
+            Ok(docs) => docs,
+            Err(e) => Err(AppError::MongoDBError(
+                AppMsgInfError{
+                    file: file!().to_string(),
+                    line: line!(),
+                    api_msg_error: "Internal server error".into(),
+                    log_msg_error: e.to_string(),
+                }))?,
+        };
+
+    let mut messages: HashMap<String, HashMap<String, DeviceMessageReceived>> = HashMap::with_capacity(devices_uuid.len());
+
+    for latest in results {
+        messages
+            .entry(latest.device_uuid.clone())
+            .or_default()
+            .insert(latest.metric.clone(), DeviceMessageReceived::from(latest));
+    }
+
+    Ok(devices_uuid
+        .into_iter()
+        .filter_map(|device_uuid| messages.remove(&device_uuid).map(|messages| DeviceMessagesOwned {
+            device_uuid,
+            messages,
+        }))
+        .collect())
+}
+
+/// Raw readings of a device in time order, a page of `limit` readings after
+/// the cursor. `from` is inclusive and `to` exclusive.
+pub async fn get_device_readings_data_store_query(
+    client: &Client,
+    device_uuid: &Uuid,
+    filter: &ReadingFilter,
+) -> Result<ReadingPaginationResponse, AppError> {
+
+    let limit = filter.limit.unwrap_or(READINGS_DEFAULT_LIMIT).clamp(1, READINGS_MAX_LIMIT);
+
+    let cursor = match &filter.cursor {
+        Some(cursor) => match ReadingCursor::decode(cursor) {
+            Some(cursor) => Some(cursor),
+            None => Err(AppError::BadRequest(format!("Invalid cursor: {}", cursor)))?,
         },
+        None => None,
+    };
+
+    let from = filter.from.map(|from| BsonDateTime::from_millis(from.timestamp_millis()));
+    let to = filter.to.map(|to| BsonDateTime::from_millis(to.timestamp_millis()));
+
+    let mut bucket_match = doc! { "device_uuid": device_uuid.to_string() };
+    let mut reading_match = doc! {};
+    let mut at_range = doc! {};
+    // readings of a bucket counted as within the page, never more than match
+    let mut counted = Vec::new();
+
+    if let Some(metric) = &filter.metric {
+        bucket_match.insert("metric", metric.as_str());
+    }
+
+    if let Some(from) = from {
+        bucket_match.insert("last_at", doc! { "$gte": from });
+        at_range.insert("$gte", from);
+        counted.push(doc! { "$gte": ["$$this.at", from] });
+    }
+
+    if let Some(to) = to {
+        bucket_match.insert("first_at", doc! { "$lt": to });
+        at_range.insert("$lt", to);
+        counted.push(doc! { "$lt": ["$$this.at", to] });
+    }
+
+    if !at_range.is_empty() {
+        reading_match.insert("readings.at", at_range);
+    }
+
+    if let Some(cursor) = &cursor {
+        let cursor_at = BsonDateTime::from_millis(cursor.at);
+
+        bucket_match.insert("$and", vec![doc! { "last_at": { "$gte": cursor_at } }]);
+        reading_match.insert("$or", vec![
+            doc! { "readings.at": { "$gt": cursor_at } },
+            doc! { "readings.at": cursor_at, "metric": { "$gt": cursor.metric.as_str() } },
+            doc! { "readings.at": cursor_at, "metric": cursor.metric.as_str(), "index": { "$gt": cursor.index } },
+        ]);
+        // readings at the cursor time may be past it too, they are not counted
+        counted.push(doc! { "$gt": ["$$this.at", cursor_at] });
+    }
 
+    let matched = if counted.is_empty() {
+        Bson::String("$count".into())
+    } else {
+        Bson::Document(doc! { "$size": { "$filter": { "input": "$readings", "cond": { "$and": counted } } } })
+    };
+
+    // only the buckets of the hours up to the one where the page is full are
+    // unwound, every bucket of that hour is kept since their readings interleave
+    let pipeline = vec![
+        doc! { "$match": bucket_match },
         doc! {
-            "$addFields": {
-                "messages": {
-                    "$cond": {
-                        "if": { "$isArray": "$messages" },
-                        "then": {},  // Se for array, usar objeto vazio
-                        "else": "$messages"  // Se for objeto, usar diretamente
+            "$setWindowFields": {
+                "sortBy": { "bucket_start": 1 },
+                "output": {
+                    "covered": {
+                        "$sum": matched,
+                        "window": { "range": ["unbounded", -1], "unit": "millisecond" }
                     }
                 }
             }
         },
+        doc! { "$match": { "covered": { "$lt": limit + 1 } } },
+        doc! { "$unwind": { "path": "$readings", "includeArrayIndex": "index" } },
+        // buckets rolled over within an hour continue the index of the previous one
+        doc! { "$set": { "index": { "$add": [{ "$multiply": [{ "$ifNull": ["$seq", 0_i64] }, READING_BUCKET_MAX_READINGS] }, "$index"] } } },
+        doc! { "$match": reading_match },
+        doc! { "$sort": { "readings.at": 1, "metric": 1, "index": 1 } },
+        doc! { "$limit": limit + 1 },
+        doc! { "$project": { "_id": 0, "metric": 1, "index": 1, "reading": "$readings" } },
+    ];
 
-        doc! {
-            "$project": {
-                "device_uuid": 1,
-                "metrics": { "$objectToArray": "$messages" }
-            }
-        },
+    let database = client.database("devices");
+    let collection: Collection<mongodb::bson::Document> = database.collection("readings");
 
-        // Unwind para ter uma linha por métrica
-        doc! {
-            "$unwind": "$metrics"
-        },
+    let cursor = match collection
+        .aggregate(pipeline)
+        .await{
+            Ok(cursor) => cursor,
+            Err(e) => Err(AppError::MongoDBError(
+                AppMsgInfError{
+                    file: file!().to_string(),
+                    line: line!(),
+                    api_msg_error: "Internal server error".into(),
+                    log_msg_error: e.to_string(),
+                }))?,
+        };
 
-        // Unwind o array de mensagens de cada métrica
-        doc! {
-            "$unwind": "$metrics.v"
-        },
+    let results: Vec<mongodb::bson::Document> =
+        match cursor.try_collect().await{
+            Ok(docs) => docs,
+            Err(e) => Err(AppError::MongoDBError(
+                AppMsgInfError{
+                    file: file!().to_string(),
+                    line: line!(),
+                    api_msg_error: "Internal server error".into(),
+                    log_msg_error: e.to_string(),
+                }))?,
+        };
 
-        //sort for timestamp desc
-        doc! {
-            "$sort": {
-                "metrics.v.timestamp": -1
-            }
-        },
+    let mut rows = Vec::with_capacity(results.len());
 
-        //group for a device + type - pegar apenas o último valor (mais recente)
-        doc! {
-            "$group": {
-                "_id": {
-                    "device_uuid": "$device_uuid",
-                    "type": "$metrics.k"
-                },
-                "last_value": { "$first": "$metrics.v" }
-            }
-        },
+    for doc in results {
+        match from_document::<ReadingRow>(doc) {
+            Ok(row) => rows.push(row),
+            Err(e) => Err(AppError::MongoDBError(
+                AppMsgInfError{
+                    file: file!().to_string(),
+                    line: line!(),
+                    api_msg_error: "Internal server error".into(),
+                    log_msg_error: format!("Error convert document: {}", e),
+                }))?,
+        }
+    }
 
-        doc! {
-            "$match": {
-                "_id.type": { "$ne": Bson::Null },
-                "last_value": { "$ne": Bson::Null }
-            }
-        },
+    let has_next_page = rows.len() as i64 > limit;
+    rows.truncate(limit as usize);
+
+    let next_cursor = match rows.last() {
+        Some(row) if has_next_page => Some(ReadingCursor {
+            at: row.reading.at.timestamp_millis(),
+            metric: row.metric.clone(),
+            index: row.index,
+        }.encode()),
+        _ => None,
+    };
+
+    Ok(ReadingPaginationResponse {
+        device_uuid: *device_uuid,
+        readings: rows.into_iter().map(ReadingResponse::from).collect(),
+        limit,
+        next_cursor,
+        has_next_page,
+    })
+}
+
+/// Readings of a device aggregated per metric over fixed windows aligned to
+/// the timezone, computed in the pipeline so only the windows are returned.
+pub async fn get_device_readings_aggregate_data_store_query(
+    client: &Client,
+    device_uuid: &Uuid,
+    metric: Option<&str>,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    window: AggregateWindow,
+    timezone: Tz,
+) -> Result<Vec<ReadingAggregateSeries>, AppError> {
+
+    let from = BsonDateTime::from_millis(from.timestamp_millis());
+    let to = BsonDateTime::from_millis(to.timestamp_millis());
+    let (unit, bin_size) = window.date_trunc();
+
+    let mut bucket_match = doc! {
+        "device_uuid": device_uuid.to_string(),
+        "last_at": { "$gte": from },
+        "first_at": { "$lt": to },
+    };
+
+    if let Some(metric) = metric {
+        bucket_match.insert("metric", metric);
+    }
+
+    let numeric = doc! { "$cond": [{ "$isNumber": "$readings.value" }, "$readings.value", Bson::Null] };
 
+    let pipeline = vec![
+        doc! { "$match": bucket_match },
+        doc! { "$unwind": "$readings" },
+        doc! { "$match": { "readings.at": { "$gte": from, "$lt": to } } },
         doc! {
             "$group": {
-                "_id": "$_id.device_uuid",
-                "messages": {
-                    "$push": {
-                        "k": "$_id.type",
-                        "v": {
-                            "value": "$last_value.value",
-                            "scale": "$last_value.scale",
-                            "timestamp": "$last_value.timestamp"
+                "_id": {
+                    "metric": "$metric",
+                    "window_start": {
+                        "$dateTrunc": {
+                            "date": "$readings.at",
+                            "unit": unit,
+                            "binSize": bin_size,
+                            "timezone": timezone.name()
                         }
                     }
-                }
+                },
+                "count": { "$sum": 1_i64 },
+                "numeric_count": { "$sum": { "$cond": [{ "$isNumber": "$readings.value" }, 1, 0] } },
+                "min": { "$min": numeric.clone() },
+                "max": { "$max": numeric.clone() },
+                "avg": { "$avg": numeric.clone() },
+                "sum": { "$sum": numeric },
+                "first": { "$top": { "sortBy": { "readings.at": 1 }, "output": "$readings.value" } },
+                "last": { "$bottom": { "sortBy": { "readings.at": 1 }, "output": "$readings.value" } }
             }
         },
-        //project final
+        doc! { "$sort": { "_id.metric": 1, "_id.window_start": 1 } },
         doc! {
             "$project": {
                 "_id": 0,
-                "device_uuid": "$_id",
-                "messages": { "$arrayToObject": "$messages" }
+                "metric": "$_id.metric",
+                "window_start": "$_id.window_start",
+                "count": 1,
+                "min": { "$toDouble": "$min" },
+                "max": { "$toDouble": "$max" },
+                "avg": { "$toDouble": "$avg" },
+                "sum": { "$cond": [{ "$gt": ["$numeric_count", 0] }, { "$toDouble": "$sum" }, Bson::Null] },
+                "first": 1,
+                "last": 1
             }
         },
     ];
 
-    let collection: Collection<mongodb::bson::Document> = database.collection("devices");
+    let database = client.database("devices");
+    let collection: Collection<mongodb::bson::Document> = database.collection("readings");
 
     let cursor = match collection
         .aggregate(pipeline)
@@ -334,22 +960,138 @@ pub async fn get_message_data_store_query(
                 }))?,
         };
 
+    let mut series: Vec<ReadingAggregateSeries> = Vec::new();
 
-    let mut device_messages = Vec::with_capacity(results.len());
-    device_messages.extend(
-        results
-            .into_iter()
-            .filter_map(|doc| {
-                match from_document::<DeviceMessagesOwned>(doc) {
-                    Ok(device_msg) => Some(device_msg),
-                    Err(e) => {
-                        error!("file: {}, line: {}, Error convert document: {}", file!(), line!(), e);
-                        None
-                    }
-                }
-            }
-        )
-    );
+    for doc in results {
+        let row = match from_document::<ReadingAggregateRow>(doc) {
+            Ok(row) => row,
+            Err(e) => Err(AppError::MongoDBError(
+                AppMsgInfError{
+                    file: file!().to_string(),
+                    line: line!(),
+                    api_msg_error: "Internal server error".into(),
+                    log_msg_error: format!("Error convert document: {}", e),
+                }))?,
+        };
+
+        let window_start = bson_to_chrono(&row.window_start)?.with_timezone(&timezone).fixed_offset();
+
+        let aggregate = ReadingAggregateWindow {
+            window_start,
+            count: row.count,
+            min: row.min,
+            max: row.max,
+            avg: row.avg,
+            sum: row.sum,
+            first: row.first,
+            last: row.last,
+        };
+
+        // rows are sorted by metric
+        match series.last_mut() {
+            Some(last) if last.metric == row.metric => last.windows.push(aggregate),
+            _ => series.push(ReadingAggregateSeries {
+                metric: row.metric,
+                windows: vec![aggregate],
+            }),
+        }
+    }
+
+    Ok(series)
+}
+
+/// Cursor over the readings of the devices in time order, per device, for
+/// exports streamed without loading the readings in memory.
+pub async fn get_device_readings_export_cursor_query(
+    client: &Client,
+    device_uuids: &[Uuid],
+    metrics: &[String],
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+) -> Result<Cursor<mongodb::bson::Document>, AppError> {
+
+    let devices_uuid: Vec<String> = device_uuids
+        .iter()
+        .map(|u| u.to_string())
+        .collect();
+
+    let mut bucket_match = doc! { "device_uuid": { "$in": devices_uuid } };
+    let mut at_range = doc! {};
+
+    if !metrics.is_empty() {
+        bucket_match.insert("metric", doc! { "$in": metrics.to_vec() });
+    }
+
+    if let Some(from) = from {
+        let from = BsonDateTime::from_millis(from.timestamp_millis());
+        bucket_match.insert("last_at", doc! { "$gte": from });
+        at_range.insert("$gte", from);
+    }
+
+    if let Some(to) = to {
+        let to = BsonDateTime::from_millis(to.timestamp_millis());
+        bucket_match.insert("first_at", doc! { "$lt": to });
+        at_range.insert("$lt", to);
+    }
+
+    let mut pipeline = vec![
+        doc! { "$match": bucket_match },
+        doc! { "$unwind": "$readings" },
+    ];
+
+    if !at_range.is_empty() {
+        pipeline.push(doc! { "$match": { "readings.at": at_range } });
+    }
 
-    Ok(device_messages)
-}
\ No newline at end of file
+    pipeline.push(doc! { "$sort": { "device_uuid": 1, "readings.at": 1, "metric": 1 } });
+    pipeline.push(doc! {
+        "$project": {
+            "_id": 0,
+            "device_uuid": 1,
+            "metric": 1,
+            "value": "$readings.value",
+            "scale": "$readings.scale",
+            "at": "$readings.at"
+        }
+    });
+
+    let database = client.database("devices");
+    let collection: Collection<mongodb::bson::Document> = database.collection("readings");
+
+    match collection.aggregate(pipeline).allow_disk_use(true).await {
+        Ok(cursor) => Ok(cursor),
+        Err(e) => Err(AppError::MongoDBError(
+            AppMsgInfError{
+                file: file!().to_string(),
+                line: line!(),
+                api_msg_error: "Internal server error".into(),
+                log_msg_error: e.to_string(),
+            }))?,
+    }
+}
+
+/// Devices of a user that are not deleted.
+pub async fn get_device_uuids_owned_data_store_query(
+    client: &Client,
+    user_uuid: &Uuid,
+) -> Result<Vec<Uuid>, AppError> {
+
+    let database = client.database("devices");
+    let collection: Collection<mongodb::bson::Document> = database.collection("devices");
+
+    match collection
+        .distinct("_id", doc! { "user_uuid": user_uuid.to_string(), "deleted_at": Bson::Null })
+        .await{
+            Ok(ids) => Ok(ids
+                .into_iter()
+                .filter_map(|id| id.as_str().and_then(|id| Uuid::parse_str(id).ok()))
+                .collect()),
+            Err(e) => Err(AppError::MongoDBError(
+                AppMsgInfError{
+                    file: file!().to_string(),
+                    line: line!(),
+                    api_msg_error: "Internal server error".into(),
+                    log_msg_error: e.to_string(),
+                }))?,
+    }
+}
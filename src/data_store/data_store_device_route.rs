@@ -1,12 +1,16 @@
 use actix_web::middleware::from_fn;
 use actix_web::web;
 use crate::auth;
-use crate::data_store::data_store_device_handler::get_device_collection;
+use crate::data_store::data_store_device_handler::{get_device_collection, get_device_readings, get_device_readings_aggregate, get_device_readings_export, get_devices_readings_export};
 
 pub fn data_store_device_cfg(cfg: &mut web::ServiceConfig) {
     cfg.service(
         web::scope("/device_data_store")
             .wrap(from_fn(auth::auth_midlleware::auth_middleware))
+            .route("/export", web::get().to(get_devices_readings_export))
             .route("/{uuid}", web::get().to(get_device_collection))
+            .route("/{uuid}/readings", web::get().to(get_device_readings))
+            .route("/{uuid}/readings/aggregate", web::get().to(get_device_readings_aggregate))
+            .route("/{uuid}/export", web::get().to(get_device_readings_export))
     );
 }
\ No newline at end of file
@@ -0,0 +1,292 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use actix_web::web::Bytes;
+use arrow_array::{ArrayRef, Float64Array, RecordBatch, StringArray, TimestampMillisecondArray};
+use arrow_schema::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use futures::stream::{self, Stream};
+use futures_util::TryStreamExt;
+use log::error;
+use mongodb::bson::{from_document, Document};
+use mongodb::Cursor;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+use serde_json::json;
+use crate::data_store::data_store_device_model::{ExportFormat, ReadingExportRow, ReadingValue};
+
+/// Rows read from the cursor per chunk of the response.
+const EXPORT_CHUNK_ROWS: usize = 1000;
+
+/// Rows buffered before a parquet row group is written out.
+const EXPORT_PARQUET_ROW_GROUP: usize = 10_000;
+
+/// A reading ready to be written, with its unit and local timestamp.
+struct ExportRecord {
+    device_uuid: String,
+    metric: String,
+    value: ReadingValue,
+    unit: String,
+    timestamp: DateTime<Tz>,
+}
+
+enum ExportEncoder {
+    Csv,
+    Ndjson,
+    Parquet(ParquetEncoder),
+}
+
+impl ExportEncoder {
+    fn new(format: ExportFormat, timezone: Tz) -> Result<Self, String> {
+        match format {
+            ExportFormat::Csv => Ok(ExportEncoder::Csv),
+            ExportFormat::Ndjson => Ok(ExportEncoder::Ndjson),
+            ExportFormat::Parquet => Ok(ExportEncoder::Parquet(ParquetEncoder::new(timezone)?)),
+        }
+    }
+
+    fn header(&self) -> Option<Bytes> {
+        match self {
+            ExportEncoder::Csv => Some(Bytes::from_static(b"device_uuid,metric,value,unit,timestamp\n")),
+            _ => None,
+        }
+    }
+
+    fn encode(&mut self, records: &[ExportRecord]) -> Result<Bytes, String> {
+        match self {
+            ExportEncoder::Csv => {
+                let mut out = String::new();
+
+                for record in records {
+                    out.push_str(&format!("{},{},{},{},{}\n",
+                        csv_field(&record.device_uuid),
+                        csv_field(&record.metric),
+                        csv_field(&record.value.to_string()),
+                        csv_field(&record.unit),
+                        record.timestamp.to_rfc3339()
+                    ));
+                }
+
+                Ok(Bytes::from(out))
+            }
+            ExportEncoder::Ndjson => {
+                let mut out = String::new();
+
+                for record in records {
+                    let line = json!({
+                        "device_uuid": record.device_uuid,
+                        "metric": record.metric,
+                        "value": record.value,
+                        "unit": record.unit,
+                        "timestamp": record.timestamp.to_rfc3339(),
+                    });
+                    out.push_str(&line.to_string());
+                    out.push('\n');
+                }
+
+                Ok(Bytes::from(out))
+            }
+            ExportEncoder::Parquet(encoder) => encoder.encode(records),
+        }
+    }
+
+    fn finish(self) -> Result<Bytes, String> {
+        match self {
+            ExportEncoder::Parquet(encoder) => encoder.finish(),
+            _ => Ok(Bytes::new()),
+        }
+    }
+}
+
+/// Quotes a CSV field holding a separator, a quote or a line break.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Output of the parquet writer, drained after each row group so the file is
+/// streamed instead of built in memory.
+#[derive(Clone, Default)]
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl SharedBuffer {
+    fn take(&self) -> Vec<u8> {
+        // a poisoned lock only means a panic elsewhere, the bytes are still usable
+        std::mem::take(&mut *self.0.lock().unwrap_or_else(|err| err.into_inner()))
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap_or_else(|err| err.into_inner()).extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Parquet columns: numeric and bool values in `value`, text in `value_text`.
+struct ParquetEncoder {
+    schema: SchemaRef,
+    timezone: Arc<str>,
+    buffer: SharedBuffer,
+    writer: ArrowWriter<SharedBuffer>,
+}
+
+impl ParquetEncoder {
+    fn new(timezone: Tz) -> Result<Self, String> {
+        let timezone: Arc<str> = timezone.name().into();
+        let schema: SchemaRef = Arc::new(Schema::new(vec![
+            Field::new("device_uuid", DataType::Utf8, false),
+            Field::new("metric", DataType::Utf8, false),
+            Field::new("value", DataType::Float64, true),
+            Field::new("value_text", DataType::Utf8, true),
+            Field::new("unit", DataType::Utf8, false),
+            Field::new("timestamp", DataType::Timestamp(TimeUnit::Millisecond, Some(timezone.clone())), false),
+        ]));
+
+        let properties = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .build();
+
+        let buffer = SharedBuffer::default();
+
+        let writer = ArrowWriter::try_new(buffer.clone(), schema.clone(), Some(properties))
+            .map_err(|e| e.to_string())?;
+
+        Ok(ParquetEncoder { schema, timezone, buffer, writer })
+    }
+
+    fn encode(&mut self, records: &[ExportRecord]) -> Result<Bytes, String> {
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(StringArray::from_iter_values(records.iter().map(|record| record.device_uuid.as_str()))),
+            Arc::new(StringArray::from_iter_values(records.iter().map(|record| record.metric.as_str()))),
+            Arc::new(Float64Array::from(records.iter().map(|record| record.value.as_f64()).collect::<Vec<_>>())),
+            Arc::new(StringArray::from(records.iter().map(|record| match &record.value {
+                ReadingValue::Text(text) => Some(text.as_str()),
+                _ => None,
+            }).collect::<Vec<_>>())),
+            Arc::new(StringArray::from_iter_values(records.iter().map(|record| record.unit.as_str()))),
+            Arc::new(TimestampMillisecondArray::from(records.iter().map(|record| record.timestamp.timestamp_millis()).collect::<Vec<_>>())
+                .with_timezone(self.timezone.clone())),
+        ];
+
+        let batch = RecordBatch::try_new(self.schema.clone(), columns).map_err(|e| e.to_string())?;
+
+        self.writer.write(&batch).map_err(|e| e.to_string())?;
+
+        if self.writer.in_progress_rows() >= EXPORT_PARQUET_ROW_GROUP {
+            self.writer.flush().map_err(|e| e.to_string())?;
+        }
+
+        Ok(Bytes::from(self.buffer.take()))
+    }
+
+    fn finish(self) -> Result<Bytes, String> {
+        self.writer.close().map_err(|e| e.to_string())?;
+        Ok(Bytes::from(self.buffer.take()))
+    }
+}
+
+struct ExportState {
+    cursor: Cursor<Document>,
+    encoder: Option<ExportEncoder>,
+    units: HashMap<(String, String), String>,
+    timezone: Tz,
+    header_sent: bool,
+}
+
+/// Streams the readings of the cursor encoded in the format, a chunk per
+/// `EXPORT_CHUNK_ROWS` readings. `units` maps (device_uuid, metric) to the
+/// registered unit, readings of unregistered metrics keep their own.
+pub fn export_readings_stream(
+    cursor: Cursor<Document>,
+    format: ExportFormat,
+    units: HashMap<(String, String), String>,
+    timezone: Tz,
+) -> Result<impl Stream<Item = Result<Bytes, actix_web::Error>>, String> {
+
+    let state = ExportState {
+        cursor,
+        encoder: Some(ExportEncoder::new(format, timezone)?),
+        units,
+        timezone,
+        header_sent: false,
+    };
+
+    Ok(stream::unfold(state, |mut state| async move {
+        let encoder = state.encoder.as_ref()?;
+
+        if !state.header_sent {
+            state.header_sent = true;
+
+            if let Some(header) = encoder.header() {
+                return Some((Ok(header), state));
+            }
+        }
+
+        loop {
+            let mut records = Vec::with_capacity(EXPORT_CHUNK_ROWS);
+
+            while records.len() < EXPORT_CHUNK_ROWS {
+                match state.cursor.try_next().await {
+                    Ok(Some(doc)) => match from_document::<ReadingExportRow>(doc) {
+                        Ok(row) => records.push(export_record(row, &state.units, state.timezone)),
+                        Err(e) => error!("file: {}, line: {}, Error convert document: {}", file!(), line!(), e),
+                    },
+                    Ok(None) => break,
+                    Err(e) => {
+                        state.encoder = None;
+                        error!("file: {}, line: {}, Export cursor failed: {}", file!(), line!(), e);
+                        return Some((Err(actix_web::error::ErrorInternalServerError("Export failed")), state));
+                    }
+                }
+            }
+
+            let result = if records.is_empty() {
+                match state.encoder.take()?.finish() {
+                    Ok(bytes) if bytes.is_empty() => return None,
+                    result => result,
+                }
+            } else {
+                state.encoder.as_mut()?.encode(&records)
+            };
+
+            match result {
+                Ok(bytes) if bytes.is_empty() => continue,
+                Ok(bytes) => return Some((Ok(bytes), state)),
+                Err(e) => {
+                    state.encoder = None;
+                    error!("file: {}, line: {}, Export encoding failed: {}", file!(), line!(), e);
+                    return Some((Err(actix_web::error::ErrorInternalServerError("Export failed")), state));
+                }
+            }
+        }
+    }))
+}
+
+fn export_record(row: ReadingExportRow, units: &HashMap<(String, String), String>, timezone: Tz) -> ExportRecord {
+    let unit = units
+        .get(&(row.device_uuid.clone(), row.metric.clone()))
+        .cloned()
+        .unwrap_or(row.scale);
+
+    let timestamp = DateTime::<Utc>::from_timestamp_millis(row.at.timestamp_millis())
+        .unwrap_or_default()
+        .with_timezone(&timezone);
+
+    ExportRecord {
+        device_uuid: row.device_uuid,
+        metric: row.metric,
+        value: row.value,
+        unit,
+        timestamp,
+    }
+}
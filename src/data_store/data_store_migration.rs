@@ -0,0 +1,323 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use chrono::{DateTime, Utc};
+use futures_util::TryStreamExt;
+use log::{error, info};
+use mongodb::bson::{doc, Bson, DateTime as BsonDateTime, Document};
+use mongodb::error::ErrorKind;
+use mongodb::{Client, Collection};
+use sqlx::PgPool;
+use uuid::Uuid;
+use crate::data_store::data_store_device_model::reading_bucket_start;
+use crate::device::device_message_model::ScaleDataType;
+use crate::device::device_message_query::get_device_scale_with_device_uuid_query;
+use crate::error_app::error_app::{AppError, AppMsgInfError};
+
+/// Data store migrations, applied in order once and recorded by name in the
+/// `data_store_migrations` collection.
+const DATA_STORE_MIGRATIONS: &[&str] = &[
+    "20251222_typed_reading_values",
+    "20251224_reading_buckets",
+    "20251228_latest_readings",
+    "20251229_reading_bucket_seq",
+];
+
+pub async fn run_data_store_migrations(pool: &PgPool, client: &Client, db_name: &str) -> Result<(), AppError> {
+    let migrations: Collection<Document> = client.database(db_name).collection("data_store_migrations");
+
+    for name in DATA_STORE_MIGRATIONS {
+        let applied = migrations
+            .find_one(doc! { "_id": *name })
+            .await
+            .map_err(|e| mongo_error(line!(), e.to_string()))?;
+
+        if applied.is_some() {
+            continue;
+        }
+
+        info!("file: {}, line: {}, Running data store migration: {}", file!(), line!(), name);
+
+        match *name {
+            "20251222_typed_reading_values" => migrate_typed_reading_values(pool, client, db_name).await?,
+            "20251224_reading_buckets" => migrate_reading_buckets(client, db_name).await?,
+            "20251228_latest_readings" => migrate_latest_readings(client, db_name).await?,
+            "20251229_reading_bucket_seq" => migrate_reading_bucket_seq(client, db_name).await?,
+            _ => Err(AppError::InternalServerError(format!("Unknown data store migration: {}", name)))?,
+        }
+
+        migrations
+            .insert_one(doc! {
+                "_id": *name,
+                "applied_at": BsonDateTime::from_millis(Utc::now().timestamp_millis()),
+            })
+            .await
+            .map_err(|e| mongo_error(line!(), e.to_string()))?;
+
+        info!("file: {}, line: {}, Data store migration applied: {}", file!(), line!(), name);
+    }
+
+    Ok(())
+}
+
+/// Converts reading values stored as strings to typed BSON, by the metric
+/// data type or inferred. Each metric is rewritten by an update pipeline so
+/// readings pushed meanwhile are kept, values that do not convert stay strings.
+async fn migrate_typed_reading_values(pool: &PgPool, client: &Client, db_name: &str) -> Result<(), AppError> {
+    let collection: Collection<Document> = client.database(db_name).collection("devices");
+
+    let pipeline = vec![
+        doc! { "$match": { "messages": { "$type": "object" } } },
+        doc! { "$project": {
+            "metrics": { "$map": { "input": { "$objectToArray": "$messages" }, "in": "$$this.k" } }
+        }},
+    ];
+
+    let devices: Vec<Document> = collection
+        .aggregate(pipeline)
+        .await
+        .map_err(|e| mongo_error(line!(), e.to_string()))?
+        .try_collect()
+        .await
+        .map_err(|e| mongo_error(line!(), e.to_string()))?;
+
+    for device in devices {
+        let device_id = match device.get_str("_id") {
+            Ok(device_id) => device_id.to_string(),
+            Err(_) => continue,
+        };
+
+        let data_types: HashMap<String, Option<ScaleDataType>> = match Uuid::parse_str(&device_id) {
+            Ok(device_uuid) => get_device_scale_with_device_uuid_query(pool, &device_uuid)
+                .await?
+                .into_iter()
+                .map(|scale| {
+                    let data_type = scale.data_type
+                        .as_deref()
+                        .and_then(|data_type| ScaleDataType::from_str(data_type).ok());
+                    (scale.metric, data_type)
+                })
+                .collect(),
+            Err(_) => HashMap::new(),
+        };
+
+        let metrics = match device.get_array("metrics") {
+            Ok(metrics) => metrics.iter().filter_map(|metric| metric.as_str().map(String::from)).collect::<Vec<_>>(),
+            Err(_) => continue,
+        };
+
+        for metric in metrics {
+            let data_type = data_types.get(&metric).copied().flatten();
+
+            if data_type == Some(ScaleDataType::String) {
+                continue;
+            }
+
+            let field = format!("messages.{}", metric);
+            let update = vec![doc! { "$set": {
+                field.clone(): { "$map": {
+                    "input": format!("${}", field),
+                    "as": "m",
+                    "in": { "$mergeObjects": ["$$m", {
+                        "value": { "$cond": [
+                            { "$eq": [{ "$type": "$$m.value" }, "string"] },
+                            typed_value_expression(data_type),
+                            "$$m.value"
+                        ]}
+                    }]}
+                }}
+            }}];
+
+            if let Err(e) = collection
+                .update_one(doc! { "_id": device_id.as_str(), field.as_str(): { "$type": "array" } }, update)
+                .await {
+                error!("file: {}, line: {}, Failed to migrate values: device: {}, metric: {}, error: {}", file!(), line!(), device_id, metric, e);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Moves the readings kept in `messages.<metric>` of the device documents to
+/// the bucketed `readings` collection, then drops them from the device. The
+/// readings are added as a set, so a device interrupted midway is not duplicated.
+async fn migrate_reading_buckets(client: &Client, db_name: &str) -> Result<(), AppError> {
+    let database = client.database(db_name);
+    let devices: Collection<Document> = database.collection("devices");
+    let readings: Collection<Document> = database.collection("readings");
+
+    let mut cursor = devices
+        .find(doc! { "messages": { "$type": "object" } })
+        .projection(doc! { "_id": 1, "user_uuid": 1, "messages": 1 })
+        .await
+        .map_err(|e| mongo_error(line!(), e.to_string()))?;
+
+    while let Some(device) = cursor.try_next().await.map_err(|e| mongo_error(line!(), e.to_string()))? {
+        let (device_id, user_uuid, messages) = match (device.get_str("_id"), device.get_str("user_uuid"), device.get_document("messages")) {
+            (Ok(device_id), Ok(user_uuid), Ok(messages)) => (device_id.to_string(), user_uuid.to_string(), messages.clone()),
+            _ => continue,
+        };
+
+        for (metric, values) in messages.iter() {
+            let values = match values.as_array() {
+                Some(values) => values,
+                None => continue,
+            };
+
+            let mut buckets: HashMap<i64, Vec<Document>> = HashMap::new();
+
+            for value in values {
+                let mut reading = match value.as_document() {
+                    Some(reading) => reading.clone(),
+                    None => continue,
+                };
+
+                let at = match reading.get_str("timestamp").ok().and_then(|ts| DateTime::parse_from_rfc3339(ts).ok()) {
+                    Some(ts) => BsonDateTime::from_millis(ts.timestamp_millis()),
+                    None => {
+                        error!("file: {}, line: {}, Reading without a valid timestamp skipped: device: {}, metric: {}", file!(), line!(), device_id, metric);
+                        continue;
+                    }
+                };
+
+                reading.insert("at", at);
+                buckets.entry(reading_bucket_start(&at).timestamp_millis()).or_default().push(reading);
+            }
+
+            for (bucket_start, bucket_readings) in buckets {
+                let filter = doc! {
+                    "device_uuid": device_id.as_str(),
+                    "metric": metric.as_str(),
+                    "bucket_start": BsonDateTime::from_millis(bucket_start),
+                };
+
+                let first_at = bucket_readings.iter().filter_map(|reading| reading.get_datetime("at").ok()).min().copied();
+                let last_at = bucket_readings.iter().filter_map(|reading| reading.get_datetime("at").ok()).max().copied();
+
+                readings
+                    .update_one(filter.clone(), doc! {
+                        "$addToSet": { "readings": { "$each": bucket_readings } },
+                        "$min": { "first_at": first_at },
+                        "$max": { "last_at": last_at },
+                        "$setOnInsert": { "user_uuid": user_uuid.as_str() },
+                    })
+                    .upsert(true)
+                    .await
+                    .map_err(|e| mongo_error(line!(), e.to_string()))?;
+
+                readings
+                    .update_one(filter, vec![doc! { "$set": { "count": { "$toLong": { "$size": "$readings" } } } }])
+                    .await
+                    .map_err(|e| mongo_error(line!(), e.to_string()))?;
+            }
+        }
+
+        devices
+            .update_one(doc! { "_id": device_id.as_str() }, doc! { "$unset": { "messages": "" } })
+            .await
+            .map_err(|e| mongo_error(line!(), e.to_string()))?;
+
+        info!("file: {}, line: {}, Readings moved to buckets: device: {}", file!(), line!(), device_id);
+    }
+
+    Ok(())
+}
+
+/// Seeds the `latest_readings` cache with the latest reading of each metric
+/// of the non-deleted devices, found in their latest bucket.
+async fn migrate_latest_readings(client: &Client, db_name: &str) -> Result<(), AppError> {
+    let database = client.database(db_name);
+    let devices: Collection<Document> = database.collection("devices");
+    let readings: Collection<Document> = database.collection("readings");
+
+    let device_uuids: Vec<Bson> = devices
+        .distinct("_id", doc! { "deleted_at": Bson::Null })
+        .await
+        .map_err(|e| mongo_error(line!(), e.to_string()))?;
+
+    let pipeline = vec![
+        doc! { "$match": { "device_uuid": { "$in": device_uuids } } },
+        doc! { "$sort": { "device_uuid": 1, "metric": 1, "bucket_start": -1 } },
+        doc! { "$group": {
+            "_id": { "device_uuid": "$device_uuid", "metric": "$metric" },
+            "user_uuid": { "$first": "$user_uuid" },
+            "readings": { "$first": "$readings" }
+        }},
+        doc! { "$project": {
+            "user_uuid": 1,
+            "last": { "$first": { "$sortArray": { "input": "$readings", "sortBy": { "at": -1 } } } }
+        }},
+        doc! { "$match": { "last": { "$ne": Bson::Null } } },
+        doc! { "$replaceWith": { "$mergeObjects": [
+            "$last",
+            {
+                "_id": { "$concat": ["$_id.device_uuid", ":", "$_id.metric"] },
+                "device_uuid": "$_id.device_uuid",
+                "user_uuid": "$user_uuid",
+                "metric": "$_id.metric"
+            }
+        ]}},
+        doc! { "$merge": {
+            "into": "latest_readings",
+            "on": "_id",
+            "whenMatched": "replace",
+            "whenNotMatched": "insert"
+        }},
+    ];
+
+    readings.aggregate(pipeline).allow_disk_use(true).await.map_err(|e| mongo_error(line!(), e.to_string()))?;
+
+    Ok(())
+}
+
+/// Aggregation expression converting the string `$$m.value`, falling back to
+/// the original string when it does not convert.
+fn typed_value_expression(data_type: Option<ScaleDataType>) -> Bson {
+    let trimmed = doc! { "$trim": { "input": "$$m.value" } };
+    let lowered = doc! { "$toLower": trimmed.clone() };
+    let convert = |to: &str| Bson::Document(doc! {
+        "$convert": { "input": trimmed.clone(), "to": to, "onError": "$$m.value", "onNull": "$$m.value" }
+    });
+
+    match data_type {
+        Some(ScaleDataType::Double) => convert("double"),
+        Some(ScaleDataType::Int) => convert("long"),
+        Some(ScaleDataType::Bool) => Bson::Document(doc! { "$switch": {
+            "branches": [
+                { "case": { "$in": [lowered.clone(), ["true", "1", "on"]] }, "then": true },
+                { "case": { "$in": [lowered, ["false", "0", "off"]] }, "then": false },
+            ],
+            "default": "$$m.value"
+        }}),
+        Some(ScaleDataType::String) => Bson::String("$$m.value".into()),
+        None => Bson::Document(doc! { "$switch": {
+            "branches": [
+                { "case": { "$eq": [lowered.clone(), "true"] }, "then": true },
+                { "case": { "$eq": [lowered, "false"] }, "then": false },
+            ],
+            "default": convert("double")
+        }}),
+    }
+}
+
+/// Drops the unique bucket index without `seq`, it would keep a full bucket
+/// from rolling over. The index with `seq` is created at startup.
+async fn migrate_reading_bucket_seq(client: &Client, db_name: &str) -> Result<(), AppError> {
+    let readings: Collection<Document> = client.database(db_name).collection("readings");
+
+    match readings.drop_index("device_uuid_1_metric_1_bucket_start_-1").await {
+        Ok(_) => Ok(()),
+        // IndexNotFound, a fresh data store never had it
+        Err(e) if matches!(&*e.kind, ErrorKind::Command(command_error) if command_error.code == 27) => Ok(()),
+        Err(e) => Err(mongo_error(line!(), e.to_string())),
+    }
+}
+
+fn mongo_error(line: u32, log_msg_error: String) -> AppError {
+    AppError::MongoDBError(AppMsgInfError {
+        file: file!().to_string(),
+        line,
+        api_msg_error: "Internal server error".into(),
+        log_msg_error,
+    })
+}
@@ -2,4 +2,10 @@ pub mod data_store_device_model;
 pub mod data_store_device_handler;
 pub mod data_store_device_query;
 pub mod data_store_device_route;
-pub mod data_store_tool;
\ No newline at end of file
+pub mod data_store_tool;
+pub mod data_store_dead_letter_model;
+pub mod data_store_dead_letter_handler;
+pub mod data_store_dead_letter_query;
+pub mod data_store_dead_letter_route;
+pub mod data_store_migration;
+pub mod data_store_export_tool;
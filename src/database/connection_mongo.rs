@@ -3,7 +3,8 @@ use log::info;
 use mongodb::{Collection, IndexModel};
 use mongodb::bson::doc;
 use mongodb::options::IndexOptions;
-use crate::data_store::data_store_device_model::DeviceData;
+use crate::data_store::data_store_dead_letter_model::DeadLetter;
+use crate::data_store::data_store_device_model::{DeviceData, LatestReading, ReadingBucket};
 use crate::error_app::error_app::{AppError, AppMsgInfError};
 use crate::state::AppState;
 
@@ -48,8 +49,119 @@ pub async fn init_devices_collection(app_state: web::Data<AppState>, db_name: &s
         IndexModel::builder()
             .keys(doc! { "user_uuid": 1 })
             .build(),
+    ];
+
+    coll.create_indexes(indexes).await.map_err(|e| {
+        AppError::MongoDBError(
+            AppMsgInfError{
+                file: file!().to_string(),
+                line: line!(),
+                api_msg_error: "Internal server error".into(),
+                log_msg_error: e.to_string()
+            })
+    })?;
+
+    Ok(())
+}
+
+pub async fn init_readings_collection(app_state: web::Data<AppState>, db_name: &str) -> Result<(), AppError> {
+    let db = app_state.mongo.database(db_name);
+    let coll: Collection<ReadingBucket> = db.collection("readings");
+
+    let indexes = vec![
+        IndexModel::builder()
+            .keys(doc! { "device_uuid": 1, "metric": 1, "bucket_start": -1, "seq": -1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build(),
+        IndexModel::builder()
+            .keys(doc! { "user_uuid": 1 })
+            .build(),
+        IndexModel::builder()
+            .keys(doc! { "bucket_start": 1 })
+            .build(),
+        IndexModel::builder()
+            .keys(doc! { "last_at": 1 })
+            .build(),
+    ];
+
+    coll.create_indexes(indexes).await.map_err(|e| {
+        AppError::MongoDBError(
+            AppMsgInfError{
+                file: file!().to_string(),
+                line: line!(),
+                api_msg_error: "Internal server error".into(),
+                log_msg_error: e.to_string()
+            })
+    })?;
+
+    Ok(())
+}
+
+pub async fn init_rollups_collection(app_state: web::Data<AppState>, db_name: &str) -> Result<(), AppError> {
+    let db = app_state.mongo.database(db_name);
+    let coll: Collection<mongodb::bson::Document> = db.collection("rollups");
+
+    let indexes = vec![
+        IndexModel::builder()
+            .keys(doc! { "device_uuid": 1, "metric": 1, "window_start": -1 })
+            .build(),
+        IndexModel::builder()
+            .keys(doc! { "window_start": 1 })
+            .build(),
+    ];
+
+    coll.create_indexes(indexes).await.map_err(|e| {
+        AppError::MongoDBError(
+            AppMsgInfError{
+                file: file!().to_string(),
+                line: line!(),
+                api_msg_error: "Internal server error".into(),
+                log_msg_error: e.to_string()
+            })
+    })?;
+
+    Ok(())
+}
+
+pub async fn init_latest_readings_collection(app_state: web::Data<AppState>, db_name: &str) -> Result<(), AppError> {
+    let db = app_state.mongo.database(db_name);
+    let coll: Collection<LatestReading> = db.collection("latest_readings");
+
+    let indexes = vec![
+        IndexModel::builder()
+            .keys(doc! { "device_uuid": 1 })
+            .build(),
+        IndexModel::builder()
+            .keys(doc! { "user_uuid": 1 })
+            .build(),
+    ];
+
+    coll.create_indexes(indexes).await.map_err(|e| {
+        AppError::MongoDBError(
+            AppMsgInfError{
+                file: file!().to_string(),
+                line: line!(),
+                api_msg_error: "Internal server error".into(),
+                log_msg_error: e.to_string()
+            })
+    })?;
+
+    Ok(())
+}
+
+pub async fn init_dead_letters_collection(app_state: web::Data<AppState>, db_name: &str) -> Result<(), AppError> {
+    let db = app_state.mongo.database(db_name);
+    let coll: Collection<DeadLetter> = db.collection("dead_letters");
+
+    let indexes = vec![
+        IndexModel::builder()
+            .keys(doc! { "broker_uuid": 1, "created_at": -1 })
+            .build(),
+        IndexModel::builder()
+            .keys(doc! { "device_uuid": 1 })
+            .build(),
         IndexModel::builder()
-            .keys(doc! { "messages.timestamp": 1 })
+            .keys(doc! { "reason": 1 })
             .build(),
     ];
 
@@ -8,6 +8,12 @@ pub struct DecomposeTopic {
     pub device_name: String,
 }
 
+pub const COMMAND_RESPONSE_SUFFIX: &str = "/response";
+
+pub fn device_compose_response_topic(topic: &str) -> String {
+    format!("{}{}", topic, COMMAND_RESPONSE_SUFFIX)
+}
+
 pub fn device_compose_topic(user_uuid: &Uuid, device_uuid: &Uuid, device_name: &str) -> String{
     format!("{}/{}/{}", user_uuid, device_uuid, device_name)
 }
@@ -0,0 +1,412 @@
+use std::collections::HashMap;
+use std::fmt;
+
+/// Longest expression accepted, in characters.
+pub const DERIVED_EXPRESSION_MAX_LEN: usize = 512;
+
+/// Deepest nesting of parentheses, calls and unary minus accepted.
+const DERIVED_EXPRESSION_MAX_DEPTH: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Pow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Function {
+    Sqrt,
+    Exp,
+    Ln,
+    Log10,
+    Abs,
+    Round,
+    Floor,
+    Ceil,
+    Min,
+    Max,
+    Pow,
+}
+
+impl Function {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "sqrt" => Some(Function::Sqrt),
+            "exp" => Some(Function::Exp),
+            "ln" => Some(Function::Ln),
+            "log10" => Some(Function::Log10),
+            "abs" => Some(Function::Abs),
+            "round" => Some(Function::Round),
+            "floor" => Some(Function::Floor),
+            "ceil" => Some(Function::Ceil),
+            "min" => Some(Function::Min),
+            "max" => Some(Function::Max),
+            "pow" => Some(Function::Pow),
+            _ => None,
+        }
+    }
+
+    fn arity(&self) -> usize {
+        match self {
+            Function::Min | Function::Max | Function::Pow => 2,
+            _ => 1,
+        }
+    }
+
+    fn apply(&self, args: &[f64]) -> f64 {
+        match self {
+            Function::Sqrt => args[0].sqrt(),
+            Function::Exp => args[0].exp(),
+            Function::Ln => args[0].ln(),
+            Function::Log10 => args[0].log10(),
+            Function::Abs => args[0].abs(),
+            Function::Round => args[0].round(),
+            Function::Floor => args[0].floor(),
+            Function::Ceil => args[0].ceil(),
+            Function::Min => args[0].min(args[1]),
+            Function::Max => args[0].max(args[1]),
+            Function::Pow => args[0].powf(args[1]),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    Number(f64),
+    Variable(String),
+    Neg(Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    Call(Function, Vec<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Ident(String),
+    Op(char),
+    LParen,
+    RParen,
+    Comma,
+}
+
+/// An arithmetic expression over the metrics of a device, e.g.
+/// `temperature * 9 / 5 + 32`. Numbers, metric names, `+ - * / ^`,
+/// parentheses and the functions `sqrt exp ln log10 abs round floor ceil`
+/// (one argument) and `min max pow` (two arguments) are accepted, nothing
+/// else, so a definition can neither loop nor reach outside its readings.
+#[derive(Debug, Clone)]
+pub struct DerivedExpression {
+    expr: Expr,
+    variables: Vec<String>,
+}
+
+impl DerivedExpression {
+    pub fn parse(source: &str) -> Result<Self, String> {
+        if source.trim().is_empty() {
+            return Err("Expression is empty".into());
+        }
+
+        if source.chars().count() > DERIVED_EXPRESSION_MAX_LEN {
+            return Err(format!("Expression longer than {} characters", DERIVED_EXPRESSION_MAX_LEN));
+        }
+
+        let mut parser = Parser {
+            tokens: tokenize(source)?,
+            position: 0,
+            depth: 0,
+            variables: Vec::new(),
+        };
+
+        let expr = parser.expression()?;
+
+        if let Some(token) = parser.peek() {
+            return Err(format!("Unexpected {} at token {}", token, parser.position + 1));
+        }
+
+        Ok(DerivedExpression { expr, variables: parser.variables })
+    }
+
+    /// Metrics the expression reads, in order of first use.
+    pub fn variables(&self) -> &[String] {
+        &self.variables
+    }
+
+    /// Evaluates the expression, every variable must have a value and the
+    /// result must be a finite number.
+    pub fn evaluate(&self, values: &HashMap<String, f64>) -> Result<f64, String> {
+        let result = evaluate(&self.expr, values)?;
+
+        if !result.is_finite() {
+            return Err(format!("Result is not a finite number: {}", result));
+        }
+
+        Ok(result)
+    }
+}
+
+fn evaluate(expr: &Expr, values: &HashMap<String, f64>) -> Result<f64, String> {
+    match expr {
+        Expr::Number(number) => Ok(*number),
+        Expr::Variable(name) => values
+            .get(name)
+            .copied()
+            .ok_or_else(|| format!("No value for metric: {}", name)),
+        Expr::Neg(operand) => Ok(-evaluate(operand, values)?),
+        Expr::Binary(op, left, right) => {
+            let left = evaluate(left, values)?;
+            let right = evaluate(right, values)?;
+
+            Ok(match op {
+                BinaryOp::Add => left + right,
+                BinaryOp::Sub => left - right,
+                BinaryOp::Mul => left * right,
+                BinaryOp::Div => left / right,
+                BinaryOp::Pow => left.powf(right),
+            })
+        }
+        Expr::Call(function, args) => {
+            let args = args
+                .iter()
+                .map(|arg| evaluate(arg, values))
+                .collect::<Result<Vec<f64>, String>>()?;
+
+            Ok(function.apply(&args))
+        }
+    }
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Number(number) => write!(f, "number {}", number),
+            Token::Ident(name) => write!(f, "name {}", name),
+            Token::Op(op) => write!(f, "operator {}", op),
+            Token::LParen => write!(f, "("),
+            Token::RParen => write!(f, ")"),
+            Token::Comma => write!(f, ","),
+        }
+    }
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+
+        if c.is_ascii_digit() || c == '.' {
+            let start = i;
+
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1;
+            }
+
+            // exponent, e.g. 1.5e-3
+            if i < chars.len() && (chars[i] == 'e' || chars[i] == 'E') {
+                let mut end = i + 1;
+
+                if end < chars.len() && (chars[end] == '+' || chars[end] == '-') {
+                    end += 1;
+                }
+
+                if end < chars.len() && chars[end].is_ascii_digit() {
+                    i = end;
+
+                    while i < chars.len() && chars[i].is_ascii_digit() {
+                        i += 1;
+                    }
+                }
+            }
+
+            let text: String = chars[start..i].iter().collect();
+
+            match text.parse::<f64>() {
+                Ok(number) => tokens.push(Token::Number(number)),
+                Err(_) => return Err(format!("Invalid number: {}", text)),
+            }
+
+            continue;
+        }
+
+        if c.is_ascii_alphabetic() || c == '_' {
+            let start = i;
+
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+
+            tokens.push(Token::Ident(chars[start..i].iter().collect()));
+            continue;
+        }
+
+        let token = match c {
+            '+' | '-' | '*' | '/' | '^' => Token::Op(c),
+            '(' => Token::LParen,
+            ')' => Token::RParen,
+            ',' => Token::Comma,
+            _ => return Err(format!("Unexpected character: {}", c)),
+        };
+
+        tokens.push(token);
+        i += 1;
+    }
+
+    Ok(tokens)
+}
+
+/// Recursive descent, from the lowest precedence: `+ -`, `* /`, unary `-`,
+/// then `^` binding to the right.
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+    depth: usize,
+    variables: Vec<String>,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<(), String> {
+        match self.next() {
+            Some(token) if token == expected => Ok(()),
+            Some(token) => Err(format!("Expected {}, found {}", expected, token)),
+            None => Err(format!("Expected {}, found end of expression", expected)),
+        }
+    }
+
+    fn enter(&mut self) -> Result<(), String> {
+        self.depth += 1;
+
+        if self.depth > DERIVED_EXPRESSION_MAX_DEPTH {
+            return Err(format!("Expression nested deeper than {}", DERIVED_EXPRESSION_MAX_DEPTH));
+        }
+
+        Ok(())
+    }
+
+    fn expression(&mut self) -> Result<Expr, String> {
+        let mut left = self.term()?;
+
+        while let Some(Token::Op(op @ ('+' | '-'))) = self.peek().cloned() {
+            self.position += 1;
+            let right = self.term()?;
+            let op = if op == '+' { BinaryOp::Add } else { BinaryOp::Sub };
+            left = Expr::Binary(op, Box::new(left), Box::new(right));
+        }
+
+        Ok(left)
+    }
+
+    fn term(&mut self) -> Result<Expr, String> {
+        let mut left = self.unary()?;
+
+        while let Some(Token::Op(op @ ('*' | '/'))) = self.peek().cloned() {
+            self.position += 1;
+            let right = self.unary()?;
+            let op = if op == '*' { BinaryOp::Mul } else { BinaryOp::Div };
+            left = Expr::Binary(op, Box::new(left), Box::new(right));
+        }
+
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        match self.peek() {
+            Some(Token::Op('-')) => {
+                self.position += 1;
+                self.enter()?;
+                let operand = self.unary()?;
+                self.depth -= 1;
+                Ok(Expr::Neg(Box::new(operand)))
+            }
+            Some(Token::Op('+')) => {
+                self.position += 1;
+                self.enter()?;
+                let operand = self.unary()?;
+                self.depth -= 1;
+                Ok(operand)
+            }
+            _ => self.power(),
+        }
+    }
+
+    fn power(&mut self) -> Result<Expr, String> {
+        let base = self.primary()?;
+
+        if let Some(Token::Op('^')) = self.peek() {
+            self.position += 1;
+            self.enter()?;
+            let exponent = self.unary()?;
+            self.depth -= 1;
+            return Ok(Expr::Binary(BinaryOp::Pow, Box::new(base), Box::new(exponent)));
+        }
+
+        Ok(base)
+    }
+
+    fn primary(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Token::Number(number)) => Ok(Expr::Number(number)),
+            Some(Token::Ident(name)) => {
+                if self.peek() != Some(&Token::LParen) {
+                    if !self.variables.contains(&name) {
+                        self.variables.push(name.clone());
+                    }
+
+                    return Ok(Expr::Variable(name));
+                }
+
+                let function = match Function::from_name(&name) {
+                    Some(function) => function,
+                    None => return Err(format!("Unknown function: {}", name)),
+                };
+
+                self.position += 1;
+                self.enter()?;
+
+                let mut args = vec![self.expression()?];
+
+                while self.peek() == Some(&Token::Comma) {
+                    self.position += 1;
+                    args.push(self.expression()?);
+                }
+
+                self.expect(Token::RParen)?;
+                self.depth -= 1;
+
+                if args.len() != function.arity() {
+                    return Err(format!("{} takes {} argument(s), found {}", name, function.arity(), args.len()));
+                }
+
+                Ok(Expr::Call(function, args))
+            }
+            Some(Token::LParen) => {
+                self.enter()?;
+                let expr = self.expression()?;
+                self.expect(Token::RParen)?;
+                self.depth -= 1;
+                Ok(expr)
+            }
+            Some(token) => Err(format!("Unexpected {}", token)),
+            None => Err("Unexpected end of expression".into()),
+        }
+    }
+}
@@ -0,0 +1,36 @@
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct DerivedMetric {
+    pub id: i32,
+    pub uuid: Uuid,
+    pub device_id: i32,
+    pub metric: String,
+    pub expression: String,
+    pub unit: String,
+    pub created_at: Option<chrono::DateTime<Utc>>,
+    pub updated_at: Option<chrono::DateTime<Utc>>,
+    pub deleted_at: Option<chrono::DateTime<Utc>>,
+}
+
+/// `expression` reads the other metrics of the device by name, e.g.
+/// `temperature * 9 / 5 + 32` with unit `°F`.
+#[derive(Debug, Deserialize)]
+pub struct DerivedMetricCreate {
+    pub metric: String,
+    pub expression: String,
+    pub unit: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DerivedMetricResponse {
+    pub uuid: Uuid,
+    pub device_uuid: Uuid,
+    pub metric: String,
+    pub expression: String,
+    pub unit: String,
+    pub variables: Vec<String>,
+    pub created_at: Option<chrono::DateTime<Utc>>,
+}
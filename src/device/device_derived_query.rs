@@ -0,0 +1,125 @@
+use sqlx::PgPool;
+use uuid::Uuid;
+use crate::device::device_derived_model::{DerivedMetric, DerivedMetricCreate};
+use crate::error_app::error_app::{AppError, AppMsgError};
+
+pub async fn post_derived_metric_query(
+    pool: &PgPool,
+    derived_uuid: &Uuid,
+    device_id: i32,
+    derived: &DerivedMetricCreate,
+) -> Result<DerivedMetric, AppError> {
+
+    match sqlx::query_as!(
+        DerivedMetric,
+        r#"
+        INSERT INTO derived_metrics(
+            uuid,
+            device_id,
+            metric,
+            expression,
+            unit
+        )
+        VALUES ($1, $2, $3, $4, $5)
+        RETURNING
+            id,
+            uuid,
+            device_id,
+            metric,
+            expression,
+            unit,
+            created_at,
+            updated_at,
+            deleted_at
+        "#,
+        derived_uuid,
+        device_id,
+        derived.metric,
+        derived.expression,
+        derived.unit,
+    ).fetch_one(pool).await{
+        Ok(result) => Ok(result),
+        Err(err) => Err(AppError::DBError(format!("file: {}, line: {}, error: {}", file!(), line!(), err)))?,
+    }
+}
+
+pub async fn get_derived_metrics_with_device_uuid_query(
+    pool: &PgPool,
+    device_uuid: &Uuid,
+) -> Result<Vec<DerivedMetric>, AppError> {
+
+    match sqlx::query_as!(
+        DerivedMetric,
+        r#"
+        SELECT
+            m.id,
+            m.uuid,
+            m.device_id,
+            m.metric,
+            m.expression,
+            m.unit,
+            m.created_at,
+            m.updated_at,
+            m.deleted_at
+        FROM derived_metrics m
+        INNER JOIN devices d ON m.device_id = d.id
+        WHERE d.uuid = $1
+        AND m.deleted_at IS NULL
+        ORDER BY m.metric ASC
+        "#,
+        device_uuid
+    ).fetch_all(pool).await{
+        Ok(result) => Ok(result),
+        Err(err) => Err(AppError::DBError(format!("file: {}, line: {}, error: {}", file!(), line!(), err)))?,
+    }
+}
+
+pub async fn get_derived_metric_with_uuid_query(
+    pool: &PgPool,
+    device_id: i32,
+    derived_uuid: &Uuid,
+) -> Result<DerivedMetric, AppError> {
+
+    match sqlx::query_as!(
+        DerivedMetric,
+        r#"
+        SELECT
+            id,
+            uuid,
+            device_id,
+            metric,
+            expression,
+            unit,
+            created_at,
+            updated_at,
+            deleted_at
+        FROM derived_metrics
+        WHERE uuid = $1
+        AND device_id = $2
+        AND deleted_at IS NULL
+        "#,
+        derived_uuid,
+        device_id
+    ).fetch_optional(pool).await{
+        Ok(Some(result)) => Ok(result),
+        Ok(None) => Err(AppError::NotFound(AppMsgError {
+            api_msg_error: "Derived metric not found".into(),
+            log_msg_error: format!("file: {}, line: {}, Derived metric not found: uuid: {}, device_id: {}", file!(), line!(), derived_uuid, device_id),
+        }))?,
+        Err(err) => Err(AppError::DBError(format!("file: {}, line: {}, error: {}", file!(), line!(), err)))?,
+    }
+}
+
+pub async fn delete_derived_metric_query(
+    pool: &PgPool,
+    derived_uuid: &Uuid,
+) -> Result<(), AppError> {
+
+    match sqlx::query!(
+        "UPDATE derived_metrics SET deleted_at = NOW() WHERE uuid = $1 AND deleted_at IS NULL",
+        derived_uuid
+    ).execute(pool).await{
+        Ok(_) => Ok(()),
+        Err(err) => Err(AppError::DBError(format!("file: {}, line: {}, error: {}", file!(), line!(), err)))?,
+    }
+}
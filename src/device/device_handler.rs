@@ -4,19 +4,32 @@ use uuid::Uuid;
 use web::Json;
 use crate::auth::auth_tool::token_info;
 use crate::broker::broker_model::BrokerManager;
-use crate::broker::broker_query::{get_broker_connected_query};
-use crate::broker::broker_tool::build_subscribe_topic_qos;
+use crate::broker::broker_query::{get_broker_with_device_id_query, get_broker_with_uuid_query};
+use crate::broker::broker_tool::{build_subscribe_topic_qos, build_unsubscribe_topic, publish_message};
 use crate::data_store::data_store_device_handler::create_device_collection;
-use crate::data_store::data_store_device_query::get_message_data_store_query;
-use crate::device::device_adoption_tool::device_compose_topic;
-use crate::device::device_model::{DeviceAndMessageResponse, DeviceCreate, DeviceCreateRequest, DeviceCreateResponse, DeviceFilter, DevicePaginationFilter, DevicePaginationResponse};
-use crate::device::device_query::{get_device_filter, post_device_message_query, get_devices_owned_by_user, get_device_count_total_owned_user, get_device_topic_filter_query};
-use crate::error_app::error_app::{AppError, AppMsgError};
+use crate::data_store::data_store_device_query::{delete_device_data_store_query, get_message_data_store_query, post_device_command_query, put_device_command_failed_query, put_device_data_store_blocked_query, put_device_decoder_data_store_query, put_device_scale_validation_data_store_query};
+use crate::device::device_adoption_tool::{device_compose_response_topic, device_compose_topic};
+use crate::device::device_model::{Device, DeviceAndMessageResponse, DeviceCondition, DeviceConditionRequest, DeviceCreate, DeviceDecoderRequest, DeviceCreateRequest, DeviceCreateResponse, DeviceFilter, DeviceScaleValidationRequest, DevicePaginationFilter, DevicePaginationResponse, DeviceRetentionRequest};
+use crate::device::device_query::{delete_device_query, get_device_filter, put_device_condition_query, put_device_decoder_query, put_device_scale_validation_query, put_device_retention_query, post_device_message_query, get_devices_owned_by_user, get_device_count_total_owned_user, get_device_topic_filter_query};
+use crate::error_app::error_app::{AppError, AppMsgError, AppMsgInfError};
+use crate::ingestion::ingestion_model::{DeviceDecoder, PayloadFormat, ScaleValidation};
+use crate::payload_schema::payload_schema_tool::get_payload_schema_descriptor;
+use crate::realtime::realtime_hub::publish_state;
+use crate::realtime::realtime_model::StateChange;
 use crate::state::AppState;
 use crate::user::user_query::get_user_by_uuid;
-use crate::device::device_message_model::{DeviceMessageCreateResponse, DeviceScaleCreateResponse};
+use crate::device::device_message_model::{DeviceCommandPublishResponse, DeviceCommandRequest, DeviceMessageCreateResponse, DeviceScaleCreateResponse, MessageCommandPayload};
+use crate::device::device_message_query::{get_device_scale_with_device_uuid_query, put_device_message_command_query};
+use crate::device::device_derived_expression::DerivedExpression;
+use crate::device::device_derived_model::{DerivedMetric, DerivedMetricCreate, DerivedMetricResponse};
+use crate::device::device_derived_query::{delete_derived_metric_query, get_derived_metric_with_uuid_query, get_derived_metrics_with_device_uuid_query, post_derived_metric_query};
+use crate::ingestion::ingestion_derived::invalidate_derived_metrics;
+use crate::device::device_type_model::DeviceType;
 use std::collections::HashMap;
-use crate::data_store::data_store_device_model::DeviceMessagesOwned;
+use std::str::FromStr;
+use sqlx::PgPool;
+use crate::data_store::data_store_device_model::{DeviceCommandSent, DeviceMessagesOwned, MessageDirection};
+use chrono::Utc;
 
 pub async fn device_create(
     device: Json<DeviceCreateRequest>,
@@ -29,8 +42,8 @@ pub async fn device_create(
 
     let user = get_user_by_uuid(&app_state.db, &token.inf.uuid).await?;
 
-    let device = device.into_inner();
-    let device = DeviceCreate::new(&device, user.id).await?;
+    let device_request = device.into_inner();
+    let device = DeviceCreate::new(&device_request, user.id).await?;
 
     let device_filter = DeviceFilter{
         uuid: None,
@@ -50,39 +63,69 @@ pub async fn device_create(
         )?
     };
 
-    let broker = match get_broker_connected_query(&app_state.db).await{
-        Ok(broker)=> {
-            match broker { 
-                Some(broker) => broker,
-                None => Err(
+    // without a broker uuid the device goes to the only live broker
+    let broker_uuid = match device_request.get_broker_uuid() {
+        Some(broker_uuid) => broker_uuid,
+        None => {
+            let live = manager.list().await;
+
+            match live.as_slice() {
+                [(broker_uuid, _)] => *broker_uuid,
+                [] => Err(
                     AppError::NotFound(
                         AppMsgError {
                             api_msg_error: "Broker not connected. Connect to an MQTT broker before registering a device".into(),
-                            log_msg_error: "Broker not connected. Connect to an MQTT broker before registering a device".into(),
+                            log_msg_error: "No live broker to bind the device to".into(),
                         }
                     )
-                )?
+                )?,
+                _ => Err(AppError::BadRequest("More than one broker is connected, broker_uuid must be specified".to_string()))?,
             }
-        } 
-        Err(err) => Err(
+        }
+    };
+
+    let broker = match get_broker_with_uuid_query(&app_state.db, &broker_uuid).await? {
+        Some(broker) => broker,
+        None => Err(
             AppError::NotFound(
                 AppMsgError {
-                    api_msg_error: format!("Broker not connected. Connect to an MQTT broker before registering a device, error: {}", err),
-                    log_msg_error: "Broker not connected. Connect to an MQTT broker before registering a device".into(),
+                    api_msg_error: "Broker not found".into(),
+                    log_msg_error: format!("Broker not found, uuid: {}", broker_uuid),
                 }
             )
         )?
     };
-    
+
+    if manager.get(&broker.uuid).await.is_none() {
+        Err(
+            AppError::NotFound(
+                AppMsgError {
+                    api_msg_error: "Broker not connected. Connect to an MQTT broker before registering a device".into(),
+                    log_msg_error: format!("Broker not connected in BrokerManager, uuid: {}", broker.uuid),
+                }
+            )
+        )?
+    }
+
+    let decoder = DeviceDecoder {
+        payload_format: device.payload_format,
+        payload_metric: device.payload_metric.clone(),
+        scale_validation: device.scale_validation,
+    };
+
+    validate_device_payload_schema(&app_state.db, &decoder, &device.sensor_type).await?;
+
     let topic_compose = device_compose_topic(&user.uuid, &device.uuid, &device.name);
 
     mqtt_device::components::topic::valid_topic(&topic_compose)
         .map_err(|err| AppError::BadRequest(err.to_string()))?;
 
-    let (result_device, result_message, result_scale) = post_device_message_query(&app_state.db, &device, topic_compose.clone()).await?;
+    let (result_device, result_message, result_scale) = post_device_message_query(&app_state.db, &device, topic_compose.clone(), &broker.uuid).await?;
     
     if device.device_type_int == 0 {
         let _ = build_subscribe_topic_qos(broker.uuid, topic_compose.clone(), device.message.qos,  manager.clone()).await?;
+    } else if broker.version == 5 {
+        build_subscribe_topic_qos(broker.uuid, device_compose_response_topic(&topic_compose), device.message.qos, manager.clone()).await?;
     }
     
     let broker_url = format!("{}:{}", broker.host, broker.port);
@@ -122,10 +165,12 @@ pub async fn device_create(
             device_id: scale.device_id,
             metric: scale.metric.clone(),
             unit: scale.unit.clone(),
+            data_type: scale.data_type.clone(),
             created_at: scale.created_at,
             updated_at: scale.updated_at,
             deleted_at: scale.deleted_at,
         }).collect(),
+        broker_uuid: broker.uuid,
         broker_url
     };
 
@@ -133,7 +178,8 @@ pub async fn device_create(
         app_state,
         &result.uuid,
         &result.user_uuid,
-        &topic_compose
+        &topic_compose,
+        decoder
     ).await?;
 
     Ok(HttpResponse::Ok().json(&result))
@@ -264,4 +310,493 @@ pub async fn devices_owned_by_user(
     );
 
     Ok(HttpResponse::Ok().json(&result))
+}
+
+pub async fn device_command(
+    device_uuid: web::Path<Uuid>,
+    command: Json<DeviceCommandRequest>,
+    credentials: BearerAuth,
+    app_state: web::Data<AppState>,
+    manager: web::Data<BrokerManager>
+)-> Result<HttpResponse, AppError>{
+
+    let token = token_info(credentials.token().to_string()).await?;
+    let user = get_user_by_uuid(&app_state.db, &token.inf.uuid).await?;
+
+    let device_uuid = device_uuid.into_inner();
+    let command = command.into_inner();
+
+    let device = get_owned_device(&app_state.db, &device_uuid, user.id).await?;
+
+    if device.device_type_int != DeviceType::Actuator.as_int() {
+        Err(AppError::BadRequest("Commands can only be sent to actuator devices".to_string()))?
+    }
+
+    if device.device_condition_int != DeviceCondition::Adopted.as_int() {
+        Err(AppError::BadRequest(format!("Device condition must be 'adopted', condition: {}", device.device_condition_text)))?
+    }
+
+    let message = get_device_message(&app_state.db, &device).await?;
+
+    if let Some(command_start) = message.command_start {
+        if command.value < command_start {
+            Err(AppError::BadRequest(format!("Command value must be greater than or equal to {}", command_start)))?
+        }
+    }
+
+    if let Some(command_end) = message.command_end {
+        if command.value > command_end {
+            Err(AppError::BadRequest(format!("Command value must be less than or equal to {}", command_end)))?
+        }
+    }
+
+    let broker = match get_broker_with_device_id_query(&app_state.db, device.id).await? {
+        Some(broker) => broker,
+        None => Err(
+            AppError::NotFound(
+                AppMsgError {
+                    api_msg_error: "Device is not bound to a broker".into(),
+                    log_msg_error: format!("file: {}, line: {}, Device is not bound to a broker: device_uuid: {}", file!(), line!(), device.uuid),
+                }
+            )
+        )?
+    };
+
+    let now = Utc::now();
+
+    let payload = MessageCommandPayload {
+        topic: message.topic.clone(),
+        payload: command.value.to_string(),
+        timestamp: now.to_rfc3339(),
+    };
+
+    let payload = serde_json::to_string(&payload)
+        .map_err(|err| AppError::InternalServerError(err.to_string()))?;
+
+    let mut mqtt_message = paho_mqtt::MessageBuilder::new()
+        .topic(message.topic.clone())
+        .payload(payload)
+        .qos(message.qos)
+        .retained(message.retained);
+
+    // MQTT v5 brokers get request/response, the actuator replies on the
+    // response topic echoing the correlation data
+    let mut response_topic = None;
+    let mut correlation_id = None;
+
+    if broker.version == 5 {
+        // subscribed once per device on create and adopt, not per command
+        let topic = device_compose_response_topic(&message.topic);
+        let cid = Uuid::new_v4();
+
+        let property_err = |err: paho_mqtt::Error| AppError::MqttError(AppMsgInfError {
+            file: file!().to_string(),
+            line: line!(),
+            api_msg_error: "MqttError".into(),
+            log_msg_error: err.to_string(),
+        });
+
+        let mut properties = paho_mqtt::Properties::new();
+        properties.push_string(paho_mqtt::PropertyCode::ResponseTopic, &topic).map_err(property_err)?;
+        properties.push_binary(paho_mqtt::PropertyCode::CorrelationData, cid.to_string().into_bytes()).map_err(property_err)?;
+        properties.push_string(paho_mqtt::PropertyCode::ContentType, "application/json").map_err(property_err)?;
+
+        mqtt_message = mqtt_message.properties(properties);
+        response_topic = Some(topic);
+        correlation_id = Some(cid);
+    }
+
+    // stored before the publish, a fast actuator may reply before it returns
+    let command_id = Uuid::new_v4().to_string();
+
+    let command_sent = DeviceCommandSent {
+        command_id: Some(command_id.clone()),
+        value: command.value,
+        direction: MessageDirection::Sent,
+        timestamp: now,
+        correlation_id: correlation_id.map(|cid| cid.to_string()),
+        response_topic: response_topic.clone(),
+        response: None,
+        responded_at: None,
+        publish_error: None,
+    };
+
+    post_device_command_query(&app_state.mongo, &device.uuid, &command_sent).await?;
+
+    if let Err(err) = publish_message(broker.uuid, mqtt_message.finalize(), manager.clone()).await {
+        put_device_command_failed_query(&app_state.mongo, &device.uuid, &command_id, format!("{:?}", err)).await?;
+        Err(err)?
+    }
+
+    let result = put_device_message_command_query(&app_state.db, &message.uuid, command.value).await?;
+
+    let result = DeviceCommandPublishResponse {
+        command: result,
+        response_topic,
+        correlation_id,
+    };
+
+    Ok(HttpResponse::Ok().json(&result))
+}
+
+pub async fn device_delete(
+    device_uuid: web::Path<Uuid>,
+    credentials: BearerAuth,
+    app_state: web::Data<AppState>,
+    manager: web::Data<BrokerManager>
+)-> Result<HttpResponse, AppError>{
+
+    let token = token_info(credentials.token().to_string()).await?;
+    let user = get_user_by_uuid(&app_state.db, &token.inf.uuid).await?;
+
+    let device = get_owned_device(&app_state.db, &device_uuid.into_inner(), user.id).await?;
+    let message = get_device_message(&app_state.db, &device).await?;
+
+    device_unsubscribe(&app_state.db, &device, &message.topic, manager.clone()).await?;
+
+    delete_device_query(&app_state.db, device.id).await?;
+
+    delete_device_data_store_query(&app_state.mongo, &device.uuid).await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub async fn device_condition_update(
+    device_uuid: web::Path<Uuid>,
+    condition: Json<DeviceConditionRequest>,
+    credentials: BearerAuth,
+    app_state: web::Data<AppState>,
+    manager: web::Data<BrokerManager>
+)-> Result<HttpResponse, AppError>{
+
+    let token = token_info(credentials.token().to_string()).await?;
+    let user = get_user_by_uuid(&app_state.db, &token.inf.uuid).await?;
+
+    let condition = DeviceCondition::from_str(&condition.condition)?;
+
+    let device = get_owned_device(&app_state.db, &device_uuid.into_inner(), user.id).await?;
+    let message = get_device_message(&app_state.db, &device).await?;
+
+    if condition == DeviceCondition::Adopted {
+        if let Some(broker) = get_broker_with_device_id_query(&app_state.db, device.id).await? {
+            if manager.get(&broker.uuid).await.is_some() {
+                if device.device_type_int == DeviceType::Sensor.as_int() {
+                    build_subscribe_topic_qos(broker.uuid, message.topic.clone(), message.qos, manager.clone()).await?;
+                } else if broker.version == 5 {
+                    build_subscribe_topic_qos(broker.uuid, device_compose_response_topic(&message.topic), message.qos, manager.clone()).await?;
+                }
+            }
+        }
+    } else {
+        device_unsubscribe(&app_state.db, &device, &message.topic, manager.clone()).await?;
+    }
+
+    let result = put_device_condition_query(&app_state.db, device.id, &condition).await?;
+
+    put_device_data_store_blocked_query(
+        &app_state.mongo,
+        &device.uuid,
+        condition != DeviceCondition::Adopted
+    ).await?;
+
+    publish_state(StateChange::DeviceCondition { device_uuid: device.uuid, condition: condition.to_string() }, vec![user.uuid]);
+
+    Ok(HttpResponse::Ok().json(&result))
+}
+
+/// Selects the payload decoder used to ingest the device readings.
+pub async fn device_decoder_update(
+    device_uuid: web::Path<Uuid>,
+    decoder: Json<DeviceDecoderRequest>,
+    credentials: BearerAuth,
+    app_state: web::Data<AppState>,
+)-> Result<HttpResponse, AppError>{
+
+    let token = token_info(credentials.token().to_string()).await?;
+    let user = get_user_by_uuid(&app_state.db, &token.inf.uuid).await?;
+
+    let device = get_owned_device(&app_state.db, &device_uuid.into_inner(), user.id).await?;
+
+    let decoder = decoder.into_inner();
+    let decoder = DeviceDecoder {
+        payload_format: PayloadFormat::from_str(&decoder.payload_format)?,
+        payload_metric: decoder.payload_metric,
+        scale_validation: ScaleValidation::from_str(&device.scale_validation)?,
+    };
+
+    validate_device_payload_schema(&app_state.db, &decoder, &device.sensor_type).await?;
+
+    let result = put_device_decoder_query(&app_state.db, device.id, &decoder).await?;
+
+    put_device_decoder_data_store_query(&app_state.mongo, &device.uuid, &decoder).await?;
+
+    Ok(HttpResponse::Ok().json(&result))
+}
+
+/// Selects how readings are checked against the device scales.
+pub async fn device_scale_validation_update(
+    device_uuid: web::Path<Uuid>,
+    scale_validation: Json<DeviceScaleValidationRequest>,
+    credentials: BearerAuth,
+    app_state: web::Data<AppState>,
+)-> Result<HttpResponse, AppError>{
+
+    let token = token_info(credentials.token().to_string()).await?;
+    let user = get_user_by_uuid(&app_state.db, &token.inf.uuid).await?;
+
+    let scale_validation = ScaleValidation::from_str(&scale_validation.scale_validation)?;
+
+    let device = get_owned_device(&app_state.db, &device_uuid.into_inner(), user.id).await?;
+
+    let result = put_device_scale_validation_query(&app_state.db, device.id, &scale_validation).await?;
+
+    put_device_scale_validation_data_store_query(&app_state.mongo, &device.uuid, &scale_validation).await?;
+
+    Ok(HttpResponse::Ok().json(&result))
+}
+
+pub async fn device_retention_update(
+    device_uuid: web::Path<Uuid>,
+    retention: Json<DeviceRetentionRequest>,
+    credentials: BearerAuth,
+    app_state: web::Data<AppState>,
+)-> Result<HttpResponse, AppError>{
+
+    let token = token_info(credentials.token().to_string()).await?;
+    let user = get_user_by_uuid(&app_state.db, &token.inf.uuid).await?;
+
+    let retention = retention.into_inner();
+
+    if retention.raw_days.is_some_and(|days| days < 0) || retention.rollup_days.is_some_and(|days| days < 0) {
+        Err(AppError::BadRequest("Retention days must not be negative".into()))?
+    }
+
+    let device = get_owned_device(&app_state.db, &device_uuid.into_inner(), user.id).await?;
+
+    let result = put_device_retention_query(&app_state.db, device.id, &retention).await?;
+
+    Ok(HttpResponse::Ok().json(&result))
+}
+
+/// Latest reading of each metric of the device.
+pub async fn device_state(
+    device_uuid: web::Path<Uuid>,
+    credentials: BearerAuth,
+    app_state: web::Data<AppState>,
+)-> Result<HttpResponse, AppError>{
+
+    let token = token_info(credentials.token().to_string()).await?;
+    let user = get_user_by_uuid(&app_state.db, &token.inf.uuid).await?;
+
+    let device = get_owned_device(&app_state.db, &device_uuid.into_inner(), user.id).await?;
+
+    let result = match get_message_data_store_query(&app_state.mongo, vec![device.uuid]).await?.pop() {
+        Some(messages) => messages,
+        None => DeviceMessagesOwned {
+            device_uuid: device.uuid.to_string(),
+            messages: HashMap::new(),
+        },
+    };
+
+    Ok(HttpResponse::Ok().json(&result))
+}
+
+/// Defines a metric computed at ingestion from the other metrics of the
+/// device. It cannot shadow a registered metric nor read another derived one.
+pub async fn device_derived_metric_create(
+    device_uuid: web::Path<Uuid>,
+    derived: Json<DerivedMetricCreate>,
+    credentials: BearerAuth,
+    app_state: web::Data<AppState>,
+)-> Result<HttpResponse, AppError>{
+
+    let token = token_info(credentials.token().to_string()).await?;
+    let user = get_user_by_uuid(&app_state.db, &token.inf.uuid).await?;
+
+    let device = get_owned_device(&app_state.db, &device_uuid.into_inner(), user.id).await?;
+
+    let mut derived = derived.into_inner();
+    derived.metric = derived.metric.trim().to_string();
+    derived.unit = derived.unit.trim().to_string();
+
+    if derived.metric.is_empty() {
+        Err(AppError::BadRequest("Derived metric name must not be empty".into()))?
+    }
+
+    let expression = match DerivedExpression::parse(&derived.expression) {
+        Ok(expression) => expression,
+        Err(err) => Err(AppError::BadRequest(format!("Invalid expression: {}", err)))?,
+    };
+
+    let existing = get_derived_metrics_with_device_uuid_query(&app_state.db, &device.uuid).await?;
+    let scales = get_device_scale_with_device_uuid_query(&app_state.db, &device.uuid).await?;
+
+    if existing.iter().any(|metric| metric.metric == derived.metric) || scales.iter().any(|scale| scale.metric == derived.metric) {
+        return Err(AppError::ConstraintViolation(
+            AppMsgError{
+                api_msg_error: "Metric already defined for device".to_string(),
+                log_msg_error: format!("Metric already defined, device_uuid: {}, metric: {}", device.uuid, derived.metric)
+            }
+        ))?
+    }
+
+    for variable in expression.variables() {
+        if *variable == derived.metric || existing.iter().any(|metric| metric.metric == *variable) {
+            Err(AppError::BadRequest(format!("Expression cannot read a derived metric: {}", variable)))?
+        }
+    }
+
+    let result = post_derived_metric_query(&app_state.db, &Uuid::new_v4(), device.id, &derived).await?;
+
+    invalidate_derived_metrics(&device.uuid);
+
+    Ok(HttpResponse::Ok().json(derived_metric_response(&device.uuid, result)))
+}
+
+pub async fn device_derived_metric_get(
+    device_uuid: web::Path<Uuid>,
+    credentials: BearerAuth,
+    app_state: web::Data<AppState>,
+)-> Result<HttpResponse, AppError>{
+
+    let token = token_info(credentials.token().to_string()).await?;
+    let user = get_user_by_uuid(&app_state.db, &token.inf.uuid).await?;
+
+    let device = get_owned_device(&app_state.db, &device_uuid.into_inner(), user.id).await?;
+
+    let result: Vec<DerivedMetricResponse> = get_derived_metrics_with_device_uuid_query(&app_state.db, &device.uuid)
+        .await?
+        .into_iter()
+        .map(|derived| derived_metric_response(&device.uuid, derived))
+        .collect();
+
+    Ok(HttpResponse::Ok().json(result))
+}
+
+/// Stops computing the metric, its stored readings are kept.
+pub async fn device_derived_metric_delete(
+    path: web::Path<(Uuid, Uuid)>,
+    credentials: BearerAuth,
+    app_state: web::Data<AppState>,
+)-> Result<HttpResponse, AppError>{
+
+    let token = token_info(credentials.token().to_string()).await?;
+    let user = get_user_by_uuid(&app_state.db, &token.inf.uuid).await?;
+
+    let (device_uuid, derived_uuid) = path.into_inner();
+
+    let device = get_owned_device(&app_state.db, &device_uuid, user.id).await?;
+    let derived = get_derived_metric_with_uuid_query(&app_state.db, device.id, &derived_uuid).await?;
+
+    delete_derived_metric_query(&app_state.db, &derived.uuid).await?;
+
+    invalidate_derived_metrics(&device.uuid);
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+fn derived_metric_response(device_uuid: &Uuid, derived: DerivedMetric) -> DerivedMetricResponse {
+    let variables = match DerivedExpression::parse(&derived.expression) {
+        Ok(expression) => expression.variables().to_vec(),
+        Err(_) => Vec::new(),
+    };
+
+    DerivedMetricResponse {
+        uuid: derived.uuid,
+        device_uuid: *device_uuid,
+        metric: derived.metric,
+        expression: derived.expression,
+        unit: derived.unit,
+        variables,
+        created_at: derived.created_at,
+    }
+}
+
+/// Protobuf decoding needs a schema registered for the device sensor type.
+async fn validate_device_payload_schema(
+    pool: &PgPool,
+    decoder: &DeviceDecoder,
+    sensor_type: &Option<String>,
+) -> Result<(), AppError> {
+
+    if decoder.payload_format != PayloadFormat::Protobuf {
+        return Ok(());
+    }
+
+    let has_schema = match sensor_type {
+        Some(sensor_type) => get_payload_schema_descriptor(pool, sensor_type).await?.is_some(),
+        None => false,
+    };
+
+    if !has_schema {
+        Err(AppError::BadRequest(format!("No payload schema registered for sensor type: {:?}", sensor_type)))?
+    }
+
+    Ok(())
+}
+
+async fn get_owned_device(
+    pool: &PgPool,
+    device_uuid: &Uuid,
+    user_id: i32,
+) -> Result<Device, AppError> {
+
+    let device_filter = DeviceFilter{
+        uuid: Some(*device_uuid),
+        mac_address: None,
+    };
+
+    match get_device_filter(pool, &device_filter).await? {
+        Some(device) if device.user_id == user_id => Ok(device),
+        _ => Err(
+            AppError::NotFound(
+                AppMsgError {
+                    api_msg_error: "Device not found".into(),
+                    log_msg_error: format!("file: {}, line: {}, Device not found: device_uuid: {}, user_id: {}", file!(), line!(), device_uuid, user_id),
+                }
+            )
+        )?
+    }
+}
+
+async fn get_device_message(
+    pool: &PgPool,
+    device: &Device,
+) -> Result<DeviceMessageCreateResponse, AppError> {
+
+    match get_device_topic_filter_query(pool, &vec![device.id]).await?.into_iter().next() {
+        Some(message) => Ok(message),
+        None => Err(
+            AppError::NotFound(
+                AppMsgError {
+                    api_msg_error: format!("Topic not found for device_uuid: {}, device_name: {}", device.uuid, device.name),
+                    log_msg_error: format!("file: {}, line: {}, Topic not found for device_uuid: {}, device_name: {}", file!(), line!(), device.uuid, device.name),
+                }
+            )
+        )?
+    }
+}
+
+/// Unsubscribes the device topic on its live broker, and the command
+/// response topic of an actuator on an MQTT v5 broker.
+/// A broker that is not connected has nothing to unsubscribe, the subscribe
+/// query on connect already skips deleted and not adopted devices.
+async fn device_unsubscribe(
+    pool: &PgPool,
+    device: &Device,
+    topic: &str,
+    manager: web::Data<BrokerManager>
+) -> Result<(), AppError> {
+
+    if let Some(broker) = get_broker_with_device_id_query(pool, device.id).await? {
+        if manager.get(&broker.uuid).await.is_some() {
+            if device.device_type_int == DeviceType::Actuator.as_int() && broker.version == 5 {
+                build_unsubscribe_topic(broker.uuid, device_compose_response_topic(topic), manager.clone()).await?;
+            }
+
+            build_unsubscribe_topic(broker.uuid, topic.to_string(), manager).await?;
+        }
+    }
+
+    Ok(())
 }
\ No newline at end of file
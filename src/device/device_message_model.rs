@@ -1,3 +1,6 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
 use actix_web::web;
 use chrono::Utc;
 use serde::{Deserialize, Serialize};
@@ -58,6 +61,7 @@ pub struct DeviceScale {
     pub device_id: i32,
     pub metric: String,
     pub unit: String,
+    pub data_type: Option<String>,
     pub created_at: Option<chrono::DateTime<Utc>>,
     pub updated_at: Option<chrono::DateTime<Utc>>,
     pub deleted_at: Option<chrono::DateTime<Utc>>,
@@ -77,25 +81,84 @@ impl From<web::Json<DeviceScaleCreateRequest>> for DeviceScaleCreateRequest {
     }
 }
 
+/// Unit registered for a metric of a device.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeviceScaleUnit {
+    pub device_uuid: Uuid,
+    pub metric: String,
+    pub unit: String,
+}
+
+/// Declared type of a metric value, used to store readings as typed BSON.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ScaleDataType {
+    Double,
+    Int,
+    Bool,
+    String,
+}
+
+impl FromStr for ScaleDataType {
+    type Err = AppError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "double" => Ok(ScaleDataType::Double),
+            "int" => Ok(ScaleDataType::Int),
+            "bool" => Ok(ScaleDataType::Bool),
+            "string" => Ok(ScaleDataType::String),
+            _ => Err(AppError::BadRequest(format!("Invalid scale data type: {}", s)))?
+        }
+    }
+}
+
+impl fmt::Display for ScaleDataType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            ScaleDataType::Double => "double",
+            ScaleDataType::Int => "int",
+            ScaleDataType::Bool => "bool",
+            ScaleDataType::String => "string",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// A scale in the device create request, `[metric, unit]` or
+/// `[metric, unit, data_type]`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum DeviceScaleItem {
+    Unit(String, String),
+    Typed(String, String, String),
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct DeviceScaleCreate {
     pub uuid: Uuid,
     pub metric: String,
     pub unit: String,
+    pub data_type: Option<ScaleDataType>,
 }
 
 impl DeviceScaleCreate {
-    pub fn from_request(req: &DeviceCreateRequest) -> Vec<DeviceScaleCreate> {
+    pub fn from_request(req: &DeviceCreateRequest) -> Result<Vec<DeviceScaleCreate>, AppError> {
         match &req.get_device_create_scale() {
-            Some(scale_items) => scale_items.iter().map(|(metric, unit)| {
-                DeviceScaleCreate {
+            Some(scale_items) => scale_items.iter().map(|item| {
+                let (metric, unit, data_type) = match item {
+                    DeviceScaleItem::Unit(metric, unit) => (metric, unit, None),
+                    DeviceScaleItem::Typed(metric, unit, data_type) => (metric, unit, Some(ScaleDataType::from_str(data_type)?)),
+                };
+
+                Ok(DeviceScaleCreate {
                     uuid: Uuid::new_v4(),
                     metric: metric.clone(),
                     unit: unit.clone(),
-                }
+                    data_type,
+                })
             }).collect(),
 
-            None => Vec::new(),
+            None => Ok(Vec::new()),
         }
     }
 }
@@ -108,6 +171,7 @@ pub struct DeviceScaleCreateResponse {
     pub device_id: i32,
     pub metric: String,
     pub unit: String,
+    pub data_type: Option<String>,
     pub created_at: Option<chrono::DateTime<Utc>>,
     pub updated_at: Option<chrono::DateTime<Utc>>,
     pub deleted_at: Option<chrono::DateTime<Utc>>,
@@ -216,4 +280,41 @@ pub struct MessageReceivePayload {
     pub metric: String,
     pub scale: String,
     pub timestamp: String,
-}
\ No newline at end of file
+}
+
+/// MQTT v5 properties carried by a received message.
+#[derive(Debug, Default, Clone)]
+pub struct MessageReceiveProperties {
+    pub content_type: Option<String>,
+    pub user_properties: HashMap<String, String>,
+    pub response_topic: Option<String>,
+    pub correlation_data: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeviceCommandRequest {
+    pub value: i32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MessageCommandPayload {
+    pub topic: String,
+    pub payload: String,
+    pub timestamp: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeviceCommandResponse {
+    pub device_uuid: Uuid,
+    pub topic: String,
+    pub command_last: Option<i32>,
+    pub command_last_time: Option<chrono::DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeviceCommandPublishResponse {
+    #[serde(flatten)]
+    pub command: DeviceCommandResponse,
+    pub response_topic: Option<String>,
+    pub correlation_id: Option<Uuid>,
+}
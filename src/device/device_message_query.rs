@@ -1,28 +1,54 @@
 use log::error;
 use sqlx::PgPool;
-use crate::device::device_message_model::DeviceMessageSubscribe;
+use uuid::Uuid;
+use crate::device::device_adoption_tool::COMMAND_RESPONSE_SUFFIX;
+use crate::device::device_message_model::{DeviceCommandResponse, DeviceMessageSubscribe, DeviceScale, DeviceScaleUnit};
 use crate::error_app::error_app::AppError;
 
+/// Topics to subscribe when the broker connects: the topic of adopted
+/// sensors, and the command response topic of adopted actuators when the
+/// broker speaks MQTT v5.
 pub async fn get_device_message_subscribe_query(
     pool: &PgPool,
+    broker_uuid: &Uuid,
 )-> Result<Vec<DeviceMessageSubscribe>, AppError> {
 
     let result = sqlx::query_as!(
         DeviceMessageSubscribe,
         r#"
         SELECT
-          d.uuid as device_uuid,
-          m.uuid as message_uuid,
-          m.topic,
-          m.qos
+          d.uuid as "device_uuid!",
+          m.uuid as "message_uuid!",
+          m.topic as "topic!",
+          m.qos as "qos!"
         FROM devices d
         INNER JOIN messages m ON d.id = m.device_id
+        INNER JOIN brokers b ON d.broker_id = b.id
         WHERE
-          d.device_condition_int = 0
+          b.uuid = $1
+          AND d.device_condition_int = 0
           AND m.subscriber = true
           AND d.deleted_at IS NULL
+          AND m.deleted_at IS NULL
+        UNION ALL
+        SELECT
+          d.uuid,
+          m.uuid,
+          m.topic || $2,
+          m.qos
+        FROM devices d
+        INNER JOIN messages m ON d.id = m.device_id
+        INNER JOIN brokers b ON d.broker_id = b.id
+        WHERE
+          b.uuid = $1
+          AND b.version = 5
+          AND d.device_condition_int = 0
+          AND d.device_type_int = 1
+          AND d.deleted_at IS NULL
           AND m.deleted_at IS NULL;
         "#,
+        broker_uuid,
+        COMMAND_RESPONSE_SUFFIX
     ).fetch_all(pool)
         .await
         .map_err(|error| 
@@ -34,4 +60,153 @@ pub async fn get_device_message_subscribe_query(
         
 
     Ok(result)
+}
+
+pub async fn put_device_message_command_query(
+    pool: &PgPool,
+    message_uuid: &Uuid,
+    command: i32,
+)-> Result<DeviceCommandResponse, AppError> {
+
+    let result = sqlx::query_as!(
+        DeviceCommandResponse,
+        r#"
+        UPDATE messages m SET
+            command_last = $1,
+            command_last_time = NOW()
+        FROM devices d
+        WHERE m.uuid = $2
+          AND d.id = m.device_id
+        RETURNING
+          d.uuid as device_uuid,
+          m.topic,
+          m.command_last,
+          m.command_last_time
+        "#,
+        command,
+        message_uuid
+    ).fetch_one(pool)
+        .await
+        .map_err(|error|
+            {
+                error!("file: {}, line: {}, error: {}", file!(), line!(), error);
+                AppError::DBError(error.to_string())
+            }
+        )?;
+
+    Ok(result)
+}
+
+pub async fn get_device_scale_query(
+    pool: &PgPool,
+    device_id: i32,
+) -> Result<Vec<DeviceScale>, AppError> {
+
+    match sqlx::query_as!(
+        DeviceScale,
+        r#"
+        SELECT
+            id,
+            uuid,
+            device_id,
+            metric,
+            unit,
+            data_type,
+            created_at,
+            updated_at,
+            deleted_at
+        FROM scales
+        WHERE device_id = $1
+        AND deleted_at IS NULL
+        "#,
+        device_id
+    ).fetch_all(pool).await{
+        Ok(result) => Ok(result),
+        Err(err) => Err(AppError::DBError(format!("file: {}, line: {}, error: {}", file!(), line!(), err)))?,
+    }
+}
+
+pub async fn get_device_scale_with_device_uuid_query(
+    pool: &PgPool,
+    device_uuid: &Uuid,
+) -> Result<Vec<DeviceScale>, AppError> {
+
+    match sqlx::query_as!(
+        DeviceScale,
+        r#"
+        SELECT
+            s.id,
+            s.uuid,
+            s.device_id,
+            s.metric,
+            s.unit,
+            s.data_type,
+            s.created_at,
+            s.updated_at,
+            s.deleted_at
+        FROM scales s
+        INNER JOIN devices d ON s.device_id = d.id
+        WHERE d.uuid = $1
+        AND s.deleted_at IS NULL
+        "#,
+        device_uuid
+    ).fetch_all(pool).await{
+        Ok(result) => Ok(result),
+        Err(err) => Err(AppError::DBError(format!("file: {}, line: {}, error: {}", file!(), line!(), err)))?,
+    }
+}
+
+pub async fn get_device_scale_units_query(
+    pool: &PgPool,
+    device_uuids: &[Uuid],
+) -> Result<Vec<DeviceScaleUnit>, AppError> {
+
+    match sqlx::query_as!(
+        DeviceScaleUnit,
+        r#"
+        SELECT
+            d.uuid as device_uuid,
+            s.metric,
+            s.unit
+        FROM scales s
+        INNER JOIN devices d ON s.device_id = d.id
+        WHERE d.uuid = ANY($1)
+        AND s.deleted_at IS NULL
+        "#,
+        device_uuids
+    ).fetch_all(pool).await{
+        Ok(result) => Ok(result),
+        Err(err) => Err(AppError::DBError(format!("file: {}, line: {}, error: {}", file!(), line!(), err)))?,
+    }
+}
+
+/// Registers a metric seen on ingestion, a metric already registered is kept.
+pub async fn post_device_scale_with_device_uuid_query(
+    pool: &PgPool,
+    device_uuid: &Uuid,
+    metric: &str,
+    unit: &str,
+) -> Result<(), AppError> {
+
+    match sqlx::query!(
+        r#"
+        INSERT INTO scales (uuid, device_id, metric, unit)
+        SELECT $1, d.id, $3, $4
+        FROM devices d
+        WHERE d.uuid = $2
+        AND NOT EXISTS (
+            SELECT 1 FROM scales s
+            WHERE s.device_id = d.id
+            AND s.metric = $3
+            AND s.deleted_at IS NULL
+        )
+        "#,
+        Uuid::new_v4(),
+        device_uuid,
+        metric,
+        unit
+    ).execute(pool).await{
+        Ok(_) => Ok(()),
+        Err(err) => Err(AppError::DBError(format!("file: {}, line: {}, error: {}", file!(), line!(), err)))?,
+    }
 }
\ No newline at end of file
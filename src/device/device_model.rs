@@ -8,9 +8,10 @@ use uuid::Uuid;
 use crate::device::device_border_model::BoardType;
 use crate::device::device_type_model::DeviceType;
 use crate::error_app::error_app::AppError;
+use crate::ingestion::ingestion_model::{PayloadFormat, ScaleValidation};
 use eui48::MacAddress;
 use crate::data_store::data_store_device_model::DeviceMessagesOwned;
-use crate::device::device_message_model::{DeviceMessageCreate, DeviceMessageCreateRequest, DeviceMessageCreateResponse, DeviceScaleCreate, DeviceScaleCreateResponse};
+use crate::device::device_message_model::{DeviceMessageCreate, DeviceMessageCreateRequest, DeviceMessageCreateResponse, DeviceScaleCreate, DeviceScaleCreateResponse, DeviceScaleItem};
 use crate::paginate::paginate_model::{Pagination, PaginationFrom};
 
 #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
@@ -48,6 +49,36 @@ impl DeviceCondition {
     }
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeviceConditionRequest {
+    pub condition: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeviceDecoderRequest {
+    pub payload_format: String,
+    pub payload_metric: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeviceScaleValidationRequest {
+    pub scale_validation: String,
+}
+
+/// Retention of a device in days, `None` uses the global setting.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeviceRetentionRequest {
+    pub raw_days: Option<i32>,
+    pub rollup_days: Option<i32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, sqlx::FromRow)]
+pub struct DeviceRetention {
+    pub uuid: Uuid,
+    pub retention_raw_days: Option<i32>,
+    pub retention_rollup_days: Option<i32>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, sqlx::FromRow)]
 pub struct Device {
     pub id: i32,
@@ -63,6 +94,10 @@ pub struct Device {
     pub device_condition_int: i32,
     pub device_condition_text: String,
     pub mac_address: String,
+    pub broker_id: Option<i32>,
+    pub payload_format: String,
+    pub payload_metric: Option<String>,
+    pub scale_validation: String,
     pub created_at: Option<chrono::DateTime<Utc>>,
     pub updated_at: Option<chrono::DateTime<Utc>>,
     pub deleted_at: Option<chrono::DateTime<Utc>>,
@@ -78,7 +113,11 @@ pub struct DeviceCreateRequest{
     adopted_status: String,
     mac_address: String,
     message: DeviceMessageCreateRequest,
-    scale: Option<Vec<(String, String)>>,
+    scale: Option<Vec<DeviceScaleItem>>,
+    broker_uuid: Option<Uuid>,
+    payload_format: Option<String>,
+    payload_metric: Option<String>,
+    scale_validation: Option<String>,
 }
 impl From<web::Json<DeviceCreateRequest>> for DeviceCreateRequest {
     fn from(device: web::Json<DeviceCreateRequest>) -> Self {
@@ -93,14 +132,22 @@ impl From<web::Json<DeviceCreateRequest>> for DeviceCreateRequest {
             mac_address: device.mac_address,
             message: device.message,
             scale: device.scale,
+            broker_uuid: device.broker_uuid,
+            payload_format: device.payload_format,
+            payload_metric: device.payload_metric,
+            scale_validation: device.scale_validation,
         }
     }
 }
 
 impl DeviceCreateRequest {
-    pub fn get_device_create_scale(&self) -> &Option<Vec<(String, String)>> {
+    pub fn get_device_create_scale(&self) -> &Option<Vec<DeviceScaleItem>> {
         &self.scale
     }
+
+    pub fn get_broker_uuid(&self) -> Option<Uuid> {
+        self.broker_uuid
+    }
 }
 
 
@@ -119,7 +166,10 @@ pub struct DeviceCreate {
     pub device_condition_int: i32,
     pub device_condition_text: String,
     pub message: DeviceMessageCreate,
-    pub scale: Option<Vec<DeviceScaleCreate>>
+    pub scale: Option<Vec<DeviceScaleCreate>>,
+    pub payload_format: PayloadFormat,
+    pub payload_metric: Option<String>,
+    pub scale_validation: ScaleValidation,
 }
 
 impl DeviceCreate {
@@ -177,10 +227,21 @@ impl DeviceCreate {
         
         let message = DeviceMessageCreate::new(&params.message)?;
 
+        //payload decoder
+        let payload_format = match &params.payload_format {
+            Some(payload_format) => PayloadFormat::from_str(payload_format)?,
+            None => PayloadFormat::default(),
+        };
+
+        let scale_validation = match &params.scale_validation {
+            Some(scale_validation) => ScaleValidation::from_str(scale_validation)?,
+            None => ScaleValidation::default(),
+        };
+
         let mut scale: Option<Vec<DeviceScaleCreate>> = None;
 
         if let Some(scale_param) = &params.scale {
-            scale = Some(DeviceScaleCreate::from_request(&params));
+            scale = Some(DeviceScaleCreate::from_request(&params)?);
         };
 
         //variables
@@ -206,7 +267,10 @@ impl DeviceCreate {
                 device_condition_text,
                 mac_address,
                 message,
-                scale
+                scale,
+                payload_format,
+                payload_metric: params.payload_metric.clone(),
+                scale_validation,
             }
         )
     }
@@ -274,6 +338,7 @@ pub struct DeviceCreateResponse {
     pub deleted_at: Option<chrono::DateTime<Utc>>,
     pub message: DeviceMessageCreateResponse,
     pub scale: Vec<DeviceScaleCreateResponse>,
+    pub broker_uuid: Uuid,
     pub broker_url: String
 }
 
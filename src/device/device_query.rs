@@ -1,8 +1,10 @@
 use log::error;
 use std::vec::Vec;
 use sqlx::{PgPool, Postgres, QueryBuilder, Transaction};
-use crate::device::device_model::{Device, DeviceCreate, DeviceFilter, DevicePaginationFilter};
+use uuid::Uuid;
+use crate::device::device_model::{Device, DeviceCondition, DeviceCreate, DeviceFilter, DevicePaginationFilter, DeviceRetention, DeviceRetentionRequest};
 use crate::error_app::error_app::{AppError};
+use crate::ingestion::ingestion_model::{DeviceDecoder, ScaleValidation};
 use crate::device::device_message_model::{DeviceMessage, DeviceMessageCreateResponse, DeviceScale};
 use crate::paginate::paginate_model::Pagination;
 
@@ -63,6 +65,10 @@ pub async fn get_device_filter(
             device_condition_int,
             device_condition_text,
             mac_address,
+            broker_id,
+            payload_format,
+            payload_metric,
+            scale_validation,
             created_at,
             updated_at,
             deleted_at
@@ -96,7 +102,8 @@ pub async fn get_device_filter(
 pub async fn post_device_message_query(
     pool: &PgPool,
     device: &DeviceCreate,
-    topic_compose: String
+    topic_compose: String,
+    broker_uuid: &Uuid,
 ) -> Result<(Device, DeviceMessage, Vec<DeviceScale>), AppError>{
 
     let sensor_type_str = device.sensor_type.clone();
@@ -125,9 +132,13 @@ pub async fn post_device_message_query(
          actuator_type,
          device_condition_int,
          device_condition_text,
-         mac_address
+         mac_address,
+         broker_id,
+         payload_format,
+         payload_metric,
+         scale_validation
         )
-        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, (SELECT id FROM brokers WHERE uuid = $13), $14, $15, $16)
         RETURNING
         id,
         uuid,
@@ -142,6 +153,10 @@ pub async fn post_device_message_query(
         device_condition_int,
         device_condition_text,
         mac_address,
+        broker_id,
+        payload_format,
+        payload_metric,
+        scale_validation,
         created_at,
         updated_at,
         deleted_at
@@ -158,6 +173,10 @@ pub async fn post_device_message_query(
         device.device_condition_int,
         device.device_condition_text,
         device.mac_address,
+        broker_uuid,
+        device.payload_format.to_string(),
+        device.payload_metric,
+        device.scale_validation.to_string(),
     )
         .fetch_one(&mut *tx)
         .await
@@ -233,15 +252,17 @@ pub async fn post_device_message_query(
                 uuid,
                 device_id,
                 metric,
-                unit
+                unit,
+                data_type
             )
-            VALUES ($1, $2, $3, $4)
+            VALUES ($1, $2, $3, $4, $5)
             RETURNING
             id,
             uuid,
             device_id,
             metric,
             unit,
+            data_type,
             created_at,
             updated_at,
             deleted_at
@@ -250,7 +271,8 @@ pub async fn post_device_message_query(
             scale_item.uuid,
             inserted_device.id,
             scale_item.metric,
-            scale_item.unit
+            scale_item.unit,
+            scale_item.data_type.map(|data_type| data_type.to_string())
         )
             .fetch_one(&mut *tx)
             .await
@@ -298,6 +320,10 @@ pub async fn get_devices_owned_by_user(
             device_condition_int,
             device_condition_text,
             mac_address,
+            broker_id,
+            payload_format,
+            payload_metric,
+            scale_validation,
             created_at,
             updated_at,
             deleted_at
@@ -366,4 +392,243 @@ pub async fn get_device_count_total_owned_user(pool: &PgPool, user_id: i32) -> R
         },
         Err(error) => Err(AppError::DBError(error.to_string()))?
     }
-}
\ No newline at end of file
+}
+
+pub async fn delete_device_query(pool: &PgPool, device_id: i32) -> Result<(), AppError> {
+
+    let mut tx: Transaction<'_, Postgres> = pool.begin().await
+        .map_err(|e| {
+            error!("file: {}, line: {}, error: {}", file!(), line!(), e);
+            AppError::DBError(e.to_string())
+        })?;
+
+    sqlx::query!(
+        "UPDATE scales SET deleted_at = NOW() WHERE device_id = $1 AND deleted_at IS NULL",
+        device_id
+    ).execute(&mut *tx)
+        .await
+        .map_err(|e| AppError::DBError(format!("file: {}, line: {}, error: {}", file!(), line!(), e)))?;
+
+    sqlx::query!(
+        "UPDATE messages SET deleted_at = NOW() WHERE device_id = $1 AND deleted_at IS NULL",
+        device_id
+    ).execute(&mut *tx)
+        .await
+        .map_err(|e| AppError::DBError(format!("file: {}, line: {}, error: {}", file!(), line!(), e)))?;
+
+    sqlx::query!(
+        "UPDATE devices SET deleted_at = NOW() WHERE id = $1 AND deleted_at IS NULL",
+        device_id
+    ).execute(&mut *tx)
+        .await
+        .map_err(|e| AppError::DBError(format!("file: {}, line: {}, error: {}", file!(), line!(), e)))?;
+
+    tx.commit().await.map_err(|e|
+        {
+            error!("file: {}, line: {}, error: {}", file!(), line!(), e);
+            AppError::DBError(e.to_string())
+        }
+    )?;
+
+    Ok(())
+}
+
+pub async fn put_device_condition_query(
+    pool: &PgPool,
+    device_id: i32,
+    condition: &DeviceCondition,
+) -> Result<Device, AppError> {
+
+    match sqlx::query_as!(
+        Device,
+        r#"
+        UPDATE devices SET
+            device_condition_int = $1,
+            device_condition_text = $2
+        WHERE id = $3
+        RETURNING
+            id,
+            uuid,
+            user_id,
+            name,
+            device_type_int,
+            device_type_text,
+            board_type_int,
+            board_type_text,
+            sensor_type,
+            actuator_type,
+            device_condition_int,
+            device_condition_text,
+            mac_address,
+            broker_id,
+            payload_format,
+            payload_metric,
+            scale_validation,
+            created_at,
+            updated_at,
+            deleted_at
+        "#,
+        condition.as_int(),
+        condition.to_string(),
+        device_id
+    ).fetch_one(pool).await{
+        Ok(result) => Ok(result),
+        Err(err) => Err(AppError::DBError(format!("file: {}, line: {}, error: {}", file!(), line!(), err)))?,
+    }
+}
+
+pub async fn put_device_decoder_query(
+    pool: &PgPool,
+    device_id: i32,
+    decoder: &DeviceDecoder,
+) -> Result<Device, AppError> {
+
+    match sqlx::query_as!(
+        Device,
+        r#"
+        UPDATE devices SET
+            payload_format = $1,
+            payload_metric = $2,
+            updated_at = NOW()
+        WHERE id = $3
+        RETURNING
+            id,
+            uuid,
+            user_id,
+            name,
+            device_type_int,
+            device_type_text,
+            board_type_int,
+            board_type_text,
+            sensor_type,
+            actuator_type,
+            device_condition_int,
+            device_condition_text,
+            mac_address,
+            broker_id,
+            payload_format,
+            payload_metric,
+            scale_validation,
+            created_at,
+            updated_at,
+            deleted_at
+        "#,
+        decoder.payload_format.to_string(),
+        decoder.payload_metric,
+        device_id
+    ).fetch_one(pool).await{
+        Ok(result) => Ok(result),
+        Err(err) => Err(AppError::DBError(format!("file: {}, line: {}, error: {}", file!(), line!(), err)))?,
+    }
+}
+
+pub async fn put_device_scale_validation_query(
+    pool: &PgPool,
+    device_id: i32,
+    scale_validation: &ScaleValidation,
+) -> Result<Device, AppError> {
+
+    match sqlx::query_as!(
+        Device,
+        r#"
+        UPDATE devices SET
+            scale_validation = $1
+        WHERE id = $2
+        RETURNING
+            id,
+            uuid,
+            user_id,
+            name,
+            device_type_int,
+            device_type_text,
+            board_type_int,
+            board_type_text,
+            sensor_type,
+            actuator_type,
+            device_condition_int,
+            device_condition_text,
+            mac_address,
+            broker_id,
+            payload_format,
+            payload_metric,
+            scale_validation,
+            created_at,
+            updated_at,
+            deleted_at
+        "#,
+        scale_validation.to_string(),
+        device_id
+    ).fetch_one(pool).await{
+        Ok(result) => Ok(result),
+        Err(err) => Err(AppError::DBError(format!("file: {}, line: {}, error: {}", file!(), line!(), err)))?,
+    }
+}
+
+pub async fn put_device_retention_query(
+    pool: &PgPool,
+    device_id: i32,
+    retention: &DeviceRetentionRequest,
+) -> Result<DeviceRetention, AppError> {
+
+    match sqlx::query_as!(
+        DeviceRetention,
+        r#"
+        UPDATE devices SET
+            retention_raw_days = $1,
+            retention_rollup_days = $2
+        WHERE id = $3
+        RETURNING
+            uuid,
+            retention_raw_days,
+            retention_rollup_days
+        "#,
+        retention.raw_days,
+        retention.rollup_days,
+        device_id
+    ).fetch_one(pool).await{
+        Ok(result) => Ok(result),
+        Err(err) => Err(AppError::DBError(format!("file: {}, line: {}, error: {}", file!(), line!(), err)))?,
+    }
+}
+
+/// Devices with their own retention, the others follow the global setting.
+pub async fn get_device_retention_overrides_query(
+    pool: &PgPool,
+) -> Result<Vec<DeviceRetention>, AppError> {
+
+    match sqlx::query_as!(
+        DeviceRetention,
+        r#"
+        SELECT
+            uuid,
+            retention_raw_days,
+            retention_rollup_days
+        FROM devices
+        WHERE retention_raw_days IS NOT NULL
+        OR retention_rollup_days IS NOT NULL
+        "#
+    ).fetch_all(pool).await{
+        Ok(result) => Ok(result),
+        Err(err) => Err(AppError::DBError(format!("file: {}, line: {}, error: {}", file!(), line!(), err)))?,
+    }
+}
+
+/// Live devices not bound to any broker, left by the broker binding
+/// migration when no broker existed. They are not subscribed anywhere.
+pub async fn get_unbound_device_uuids_query(
+    pool: &PgPool,
+) -> Result<Vec<Uuid>, AppError> {
+
+    match sqlx::query_scalar!(
+        r#"
+        SELECT uuid
+        FROM devices
+        WHERE broker_id IS NULL
+          AND deleted_at IS NULL
+        "#
+    ).fetch_all(pool)
+        .await {
+        Ok(device_uuids) => Ok(device_uuids),
+        Err(e) => Err(AppError::DBError(e.to_string())),
+    }
+}
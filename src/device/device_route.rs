@@ -1,7 +1,7 @@
 use actix_web::middleware::from_fn;
 use actix_web::web;
 use crate::auth;
-use crate::device::device_handler::{device_create, devices_owned_by_user};
+use crate::device::device_handler::{device_command, device_condition_update, device_create, device_derived_metric_create, device_derived_metric_delete, device_derived_metric_get, device_decoder_update, device_delete, device_retention_update, device_scale_validation_update, device_state, devices_owned_by_user};
 
 pub fn device_cfg(cfg: &mut web::ServiceConfig){
     cfg.service(
@@ -9,5 +9,15 @@ pub fn device_cfg(cfg: &mut web::ServiceConfig){
             .wrap(from_fn(auth::auth_midlleware::auth_middleware))
             .route("", web::post().to(device_create))
             .route("/owned", web::get().to(devices_owned_by_user))
+            .route("/{uuid}", web::delete().to(device_delete))
+            .route("/{uuid}/condition", web::put().to(device_condition_update))
+            .route("/{uuid}/command", web::post().to(device_command))
+            .route("/{uuid}/decoder", web::put().to(device_decoder_update))
+            .route("/{uuid}/scale_validation", web::put().to(device_scale_validation_update))
+            .route("/{uuid}/retention", web::put().to(device_retention_update))
+            .route("/{uuid}/state", web::get().to(device_state))
+            .route("/{uuid}/derived_metric", web::post().to(device_derived_metric_create))
+            .route("/{uuid}/derived_metric", web::get().to(device_derived_metric_get))
+            .route("/{uuid}/derived_metric/{derived_uuid}", web::delete().to(device_derived_metric_delete))
     );
 }
\ No newline at end of file
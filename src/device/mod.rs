@@ -1,10 +1,13 @@
 pub mod device_model;
 mod device_handler;
-mod device_query;
+pub(crate) mod device_query;
 pub mod device_route;
 mod device_border_model;
 mod device_type_model;
 mod device_actuator_model;
 pub mod device_message_model;
 pub mod device_message_query;
-pub mod device_adoption_tool;
\ No newline at end of file
+pub mod device_adoption_tool;
+pub mod device_derived_model;
+pub(crate) mod device_derived_query;
+pub mod device_derived_expression;
\ No newline at end of file
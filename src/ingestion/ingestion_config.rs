@@ -0,0 +1,65 @@
+use std::path::PathBuf;
+use std::str::FromStr;
+use once_cell::sync::Lazy;
+use crate::ingestion::ingestion_model::OverflowPolicy;
+
+pub struct IngestionConfig {
+    queue_capacity: usize,
+    workers: usize,
+    overflow_policy: OverflowPolicy,
+    spill_dir: PathBuf,
+    derived_input_max_age_secs: i64,
+}
+
+impl IngestionConfig {
+    pub fn init_ingestion_config() -> IngestionConfig {
+        IngestionConfig {
+            queue_capacity: std::env::var("INGESTION_QUEUE_CAPACITY")
+                .unwrap_or_else(|_| "1024".to_string())
+                .parse()
+                .expect("INGESTION_QUEUE_CAPACITY must be a number"),
+
+            workers: std::env::var("INGESTION_WORKERS")
+                .unwrap_or_else(|_| "4".to_string())
+                .parse()
+                .expect("INGESTION_WORKERS must be a number"),
+
+            overflow_policy: OverflowPolicy::from_str(
+                &std::env::var("INGESTION_OVERFLOW_POLICY")
+                    .unwrap_or_else(|_| "block".to_string())
+            ).expect("INGESTION_OVERFLOW_POLICY must be one of: drop_oldest, block, spill_to_disk"),
+
+            spill_dir: std::env::var("INGESTION_SPILL_DIR")
+                .map(PathBuf::from)
+                .unwrap_or_else(|_| std::env::temp_dir().join("device_app_spill")),
+
+            derived_input_max_age_secs: std::env::var("INGESTION_DERIVED_INPUT_MAX_AGE_SECS")
+                .unwrap_or_else(|_| "300".to_string())
+                .parse()
+                .expect("INGESTION_DERIVED_INPUT_MAX_AGE_SECS must be a number"),
+        }
+    }
+
+    pub fn get_queue_capacity() -> usize {
+        INGESTION_CONFIG.queue_capacity.max(1)
+    }
+
+    pub fn get_workers() -> usize {
+        INGESTION_CONFIG.workers.max(1)
+    }
+
+    pub fn get_overflow_policy() -> OverflowPolicy {
+        INGESTION_CONFIG.overflow_policy
+    }
+
+    pub fn get_spill_dir() -> &'static PathBuf {
+        &INGESTION_CONFIG.spill_dir
+    }
+
+    /// Oldest stored reading a derived metric takes as input, 0 means no limit.
+    pub fn get_derived_input_max_age_secs() -> i64 {
+        INGESTION_CONFIG.derived_input_max_age_secs.max(0)
+    }
+}
+
+static INGESTION_CONFIG: Lazy<IngestionConfig> = Lazy::new(IngestionConfig::init_ingestion_config);
@@ -0,0 +1,350 @@
+use std::collections::HashMap;
+use chrono::{DateTime, Utc};
+use log::error;
+use prost_reflect::{DynamicMessage, FieldDescriptor, Kind, Value};
+use serde::Deserialize;
+use sqlx::PgPool;
+use crate::broker::broker_tool::decode_received_message;
+use crate::device::device_adoption_tool::device_decompose_topic;
+use crate::device::device_message_model::{MessageReceivePayload, MessageReceiveProperties};
+use crate::device::device_message_query::get_device_scale_query;
+use crate::device::device_model::DeviceFilter;
+use crate::device::device_query::get_device_filter;
+use crate::error_app::error_app::{AppError, AppMsgError, AppMsgInfError};
+use crate::ingestion::ingestion_model::{DeviceDecoder, PayloadFormat};
+use crate::payload_schema::payload_schema_tool::{get_payload_schema_descriptor, PROTOBUF_TIMESTAMP};
+
+/// Fields of a compact payload (CBOR or MessagePack), short keys are accepted
+/// so constrained nodes can keep the frames small.
+#[derive(Debug, Deserialize)]
+struct CompactReadingPayload {
+    #[serde(default, alias = "m")]
+    metric: Option<String>,
+    #[serde(alias = "v", alias = "payload")]
+    value: CompactReadingValue,
+    #[serde(default, alias = "s")]
+    scale: Option<String>,
+    #[serde(default, alias = "t")]
+    timestamp: Option<CompactReadingTimestamp>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum CompactReadingValue {
+    Bool(bool),
+    Integer(i64),
+    Float(f64),
+    Text(String),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum CompactReadingTimestamp {
+    Epoch(i64),
+    Text(String),
+}
+
+/// Decodes a received message into the readings stored by ingestion.
+/// The MQTT v5 content type selects the decoder per message, otherwise the
+/// device decoder is used. Every format normalizes into `MessageReceivePayload`,
+/// protobuf payloads produce one reading per field.
+pub async fn decode_readings(
+    pool: &PgPool,
+    message: &paho_mqtt::Message,
+    properties: &MessageReceiveProperties,
+    decoder: &DeviceDecoder,
+) -> Result<Vec<MessageReceivePayload>, AppError> {
+
+    let format = properties.content_type
+        .as_deref()
+        .and_then(PayloadFormat::from_content_type)
+        .unwrap_or(decoder.payload_format);
+
+    let reading = match format {
+        PayloadFormat::Json => decode_received_message(message)?,
+        PayloadFormat::Numeric => decode_numeric(message, properties, decoder)?,
+        PayloadFormat::Csv => decode_csv(message, properties, decoder)?,
+        PayloadFormat::Cbor => {
+            let payload = ciborium::de::from_reader::<CompactReadingPayload, _>(message.payload())
+                .map_err(|err| decode_error(format, err.to_string()))?;
+            normalize_compact(message, properties, decoder, format, payload)?
+        }
+        PayloadFormat::MessagePack => {
+            let payload = rmp_serde::from_slice::<CompactReadingPayload>(message.payload())
+                .map_err(|err| decode_error(format, err.to_string()))?;
+            normalize_compact(message, properties, decoder, format, payload)?
+        }
+        PayloadFormat::Protobuf => return decode_protobuf(pool, message, properties).await,
+    };
+
+    Ok(vec![reading])
+}
+
+/// Decodes with the schema registered for the device sensor type. Every
+/// field becomes a metric (nested messages flattened with `_`), the unit comes
+/// from the device scales and a top level `timestamp` field sets the time.
+async fn decode_protobuf(
+    pool: &PgPool,
+    message: &paho_mqtt::Message,
+    properties: &MessageReceiveProperties,
+) -> Result<Vec<MessageReceivePayload>, AppError> {
+
+    let format = PayloadFormat::Protobuf;
+
+    let decompose_topic = device_decompose_topic(message.topic())?;
+
+    let device_filter = DeviceFilter {
+        uuid: Some(decompose_topic.device_uuid),
+        mac_address: None,
+    };
+
+    let device = match get_device_filter(pool, &device_filter).await? {
+        Some(device) => device,
+        None => Err(AppError::NotFound(AppMsgError {
+            api_msg_error: "Device not found".into(),
+            log_msg_error: format!("file: {}, line: {}, Device not found: device_uuid: {}", file!(), line!(), decompose_topic.device_uuid),
+        }))?,
+    };
+
+    let sensor_type = match &device.sensor_type {
+        Some(sensor_type) => sensor_type,
+        None => return Err(decode_error(format, format!("Device {} has no sensor type", device.uuid))),
+    };
+
+    let descriptor = match get_payload_schema_descriptor(pool, sensor_type).await? {
+        Some(descriptor) => descriptor,
+        None => return Err(decode_error(format, format!("No payload schema for sensor type {}", sensor_type))),
+    };
+
+    let dynamic_message = DynamicMessage::decode(descriptor, message.payload())
+        .map_err(|err| decode_error(format, err.to_string()))?;
+
+    let scales: HashMap<String, String> = get_device_scale_query(pool, device.id)
+        .await?
+        .into_iter()
+        .map(|scale| (scale.metric, scale.unit))
+        .collect();
+
+    let mut timestamp = None;
+    let mut values = Vec::new();
+
+    for (field, value) in dynamic_message.fields() {
+        if field.name() == "timestamp" {
+            timestamp = protobuf_timestamp(value, format)?;
+            continue;
+        }
+        collect_protobuf_values(field.name().to_string(), &field, value, &mut values);
+    }
+
+    if values.is_empty() {
+        return Err(decode_error(format, "Protobuf payload has no metric".into()));
+    }
+
+    let timestamp = resolve_timestamp(timestamp, properties);
+
+    Ok(values
+        .into_iter()
+        .map(|(metric, value)| MessageReceivePayload {
+            topic: message.topic().to_string(),
+            payload: value,
+            scale: scales.get(&metric).cloned().unwrap_or_default(),
+            metric,
+            timestamp: timestamp.clone(),
+        })
+        .collect())
+}
+
+fn collect_protobuf_values(
+    metric: String,
+    field: &FieldDescriptor,
+    value: &Value,
+    values: &mut Vec<(String, String)>,
+) {
+    let text = match value {
+        Value::Bool(value) => value.to_string(),
+        Value::I32(value) => value.to_string(),
+        Value::I64(value) => value.to_string(),
+        Value::U32(value) => value.to_string(),
+        Value::U64(value) => value.to_string(),
+        Value::F32(value) => value.to_string(),
+        Value::F64(value) => value.to_string(),
+        Value::String(value) => value.clone(),
+        Value::EnumNumber(number) => match field.kind() {
+            Kind::Enum(enum_descriptor) => enum_descriptor
+                .get_value(*number)
+                .map(|enum_value| enum_value.name().to_string())
+                .unwrap_or_else(|| number.to_string()),
+            _ => number.to_string(),
+        },
+        Value::Message(message) => {
+            for (nested_field, nested_value) in message.fields() {
+                let nested_metric = format!("{}_{}", metric, nested_field.name());
+                collect_protobuf_values(nested_metric, &nested_field, nested_value, values);
+            }
+            return;
+        }
+        Value::Bytes(_) | Value::List(_) | Value::Map(_) => {
+            error!("file: {}, line: {}, Skipping protobuf field {}, only scalar fields become metrics", file!(), line!(), metric);
+            return;
+        }
+    };
+
+    values.push((metric, text));
+}
+
+fn protobuf_timestamp(value: &Value, format: PayloadFormat) -> Result<Option<String>, AppError> {
+    match value {
+        Value::I64(epoch) => Ok(Some(epoch_to_rfc3339(*epoch, format)?)),
+        Value::U64(epoch) => Ok(Some(epoch_to_rfc3339(*epoch as i64, format)?)),
+        Value::String(text) => Ok(Some(text.clone())),
+        Value::Message(message) if message.descriptor().full_name() == PROTOBUF_TIMESTAMP => {
+            let seconds = message.get_field_by_name("seconds").and_then(|v| v.as_i64()).unwrap_or_default();
+            let nanos = message.get_field_by_name("nanos").and_then(|v| v.as_i32()).unwrap_or_default();
+
+            DateTime::<Utc>::from_timestamp(seconds, nanos.max(0) as u32)
+                .map(|datetime| Some(datetime.to_rfc3339()))
+                .ok_or_else(|| decode_error(format, format!("Invalid protobuf timestamp: {}", seconds)))
+        }
+        _ => Err(decode_error(format, "Unsupported protobuf timestamp field".into())),
+    }
+}
+
+/// Plain number, e.g. `23.5`. Metric, scale and timestamp come from the
+/// user properties or the device decoder, the timestamp defaults to now.
+fn decode_numeric(
+    message: &paho_mqtt::Message,
+    properties: &MessageReceiveProperties,
+    decoder: &DeviceDecoder,
+) -> Result<MessageReceivePayload, AppError> {
+
+    let value = payload_text(message, PayloadFormat::Numeric)?;
+
+    if value.parse::<f64>().is_err() {
+        return Err(decode_error(PayloadFormat::Numeric, format!("Not a number: {}", value)));
+    }
+
+    Ok(MessageReceivePayload {
+        topic: message.topic().to_string(),
+        payload: value,
+        metric: resolve_metric(None, properties, decoder, PayloadFormat::Numeric)?,
+        scale: resolve_scale(None, properties),
+        timestamp: resolve_timestamp(None, properties),
+    })
+}
+
+/// One CSV line: `metric,value[,scale[,timestamp]]`, a single field is a
+/// value for the device metric.
+fn decode_csv(
+    message: &paho_mqtt::Message,
+    properties: &MessageReceiveProperties,
+    decoder: &DeviceDecoder,
+) -> Result<MessageReceivePayload, AppError> {
+
+    let line = payload_text(message, PayloadFormat::Csv)?;
+    let fields: Vec<&str> = line.lines().next().unwrap_or_default().split(',').map(str::trim).collect();
+    let field = |index: usize| fields.get(index).filter(|f| !f.is_empty()).map(|f| f.to_string());
+
+    let (metric, value, scale, timestamp) = match fields.len() {
+        1 => (None, field(0), None, None),
+        2..=4 => (field(0), field(1), field(2), field(3)),
+        _ => return Err(decode_error(PayloadFormat::Csv, format!("Invalid csv line: {}", line))),
+    };
+
+    let value = match value {
+        Some(value) => value,
+        None => return Err(decode_error(PayloadFormat::Csv, format!("Missing value: {}", line))),
+    };
+
+    Ok(MessageReceivePayload {
+        topic: message.topic().to_string(),
+        payload: value,
+        metric: resolve_metric(metric, properties, decoder, PayloadFormat::Csv)?,
+        scale: resolve_scale(scale, properties),
+        timestamp: resolve_timestamp(timestamp, properties),
+    })
+}
+
+fn normalize_compact(
+    message: &paho_mqtt::Message,
+    properties: &MessageReceiveProperties,
+    decoder: &DeviceDecoder,
+    format: PayloadFormat,
+    payload: CompactReadingPayload,
+) -> Result<MessageReceivePayload, AppError> {
+
+    let value = match payload.value {
+        CompactReadingValue::Bool(value) => value.to_string(),
+        CompactReadingValue::Integer(value) => value.to_string(),
+        CompactReadingValue::Float(value) => value.to_string(),
+        CompactReadingValue::Text(value) => value,
+    };
+
+    let timestamp = match payload.timestamp {
+        Some(CompactReadingTimestamp::Epoch(epoch)) => Some(epoch_to_rfc3339(epoch, format)?),
+        Some(CompactReadingTimestamp::Text(text)) => Some(text),
+        None => None,
+    };
+
+    Ok(MessageReceivePayload {
+        topic: message.topic().to_string(),
+        payload: value,
+        metric: resolve_metric(payload.metric, properties, decoder, format)?,
+        scale: resolve_scale(payload.scale, properties),
+        timestamp: resolve_timestamp(timestamp, properties),
+    })
+}
+
+fn payload_text(message: &paho_mqtt::Message, format: PayloadFormat) -> Result<String, AppError> {
+    match std::str::from_utf8(message.payload()) {
+        Ok(text) => Ok(text.trim().to_string()),
+        Err(err) => Err(decode_error(format, err.to_string())),
+    }
+}
+
+fn resolve_metric(
+    metric: Option<String>,
+    properties: &MessageReceiveProperties,
+    decoder: &DeviceDecoder,
+    format: PayloadFormat,
+) -> Result<String, AppError> {
+    metric
+        .or_else(|| properties.user_properties.get("metric").cloned())
+        .or_else(|| decoder.payload_metric.clone())
+        .ok_or_else(|| decode_error(format, "Missing metric, set payload_metric on the device".into()))
+}
+
+fn resolve_scale(scale: Option<String>, properties: &MessageReceiveProperties) -> String {
+    scale
+        .or_else(|| properties.user_properties.get("scale").cloned())
+        .unwrap_or_default()
+}
+
+fn resolve_timestamp(timestamp: Option<String>, properties: &MessageReceiveProperties) -> String {
+    timestamp
+        .or_else(|| properties.user_properties.get("timestamp").cloned())
+        .unwrap_or_else(|| Utc::now().to_rfc3339())
+}
+
+/// Epoch in seconds, or in milliseconds when it is too large for seconds.
+fn epoch_to_rfc3339(epoch: i64, format: PayloadFormat) -> Result<String, AppError> {
+    let datetime = if epoch.abs() >= 100_000_000_000 {
+        DateTime::<Utc>::from_timestamp_millis(epoch)
+    } else {
+        DateTime::<Utc>::from_timestamp(epoch, 0)
+    };
+
+    datetime
+        .map(|datetime| datetime.to_rfc3339())
+        .ok_or_else(|| decode_error(format, format!("Invalid epoch timestamp: {}", epoch)))
+}
+
+fn decode_error(format: PayloadFormat, log_msg_error: String) -> AppError {
+    error!("file: {}, line: {}, Failed to decode {} payload: {}", file!(), line!(), format, log_msg_error);
+
+    AppError::MqttError(AppMsgInfError {
+        file: file!().to_string(),
+        line: line!(),
+        api_msg_error: "MqttError".into(),
+        log_msg_error,
+    })
+}
@@ -0,0 +1,179 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use chrono::DateTime;
+use log::error;
+use mongodb::Client;
+use once_cell::sync::Lazy;
+use sqlx::PgPool;
+use uuid::Uuid;
+use crate::data_store::data_store_device_model::ReadingValue;
+use crate::data_store::data_store_device_query::get_message_data_store_query;
+use crate::device::device_derived_expression::DerivedExpression;
+use crate::device::device_derived_query::get_derived_metrics_with_device_uuid_query;
+use crate::device::device_message_model::MessageReceivePayload;
+use crate::error_app::error_app::AppError;
+use crate::ingestion::ingestion_config::IngestionConfig;
+
+pub struct CompiledDerivedMetric {
+    pub metric: String,
+    pub unit: String,
+    pub expression: DerivedExpression,
+}
+
+/// A reading computed by a derived metric, timestamped like the latest
+/// reading of the message it was computed from.
+pub struct DerivedReading {
+    pub metric: String,
+    pub unit: String,
+    pub value: f64,
+    pub timestamp: String,
+}
+
+/// Compiled derived metrics by device, devices without any are cached too so
+/// ingestion does not query them for every message.
+static DERIVED_METRIC_CACHE: Lazy<RwLock<HashMap<Uuid, Arc<Vec<CompiledDerivedMetric>>>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+
+pub async fn get_device_derived_metrics(
+    pool: &PgPool,
+    device_uuid: &Uuid,
+) -> Result<Arc<Vec<CompiledDerivedMetric>>, AppError> {
+
+    // a poisoned lock only means a panic elsewhere, the map is still usable
+    let cached = DERIVED_METRIC_CACHE
+        .read()
+        .unwrap_or_else(|err| err.into_inner())
+        .get(device_uuid)
+        .cloned();
+
+    if let Some(metrics) = cached {
+        return Ok(metrics);
+    }
+
+    let metrics = get_derived_metrics_with_device_uuid_query(pool, device_uuid)
+        .await?
+        .into_iter()
+        .filter_map(|derived| match DerivedExpression::parse(&derived.expression) {
+            Ok(expression) => Some(CompiledDerivedMetric {
+                metric: derived.metric,
+                unit: derived.unit,
+                expression,
+            }),
+            Err(err) => {
+                error!("file: {}, line: {}, Invalid derived metric skipped: uuid: {}, error: {}", file!(), line!(), derived.uuid, err);
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let metrics = Arc::new(metrics);
+    DERIVED_METRIC_CACHE
+        .write()
+        .unwrap_or_else(|err| err.into_inner())
+        .insert(*device_uuid, metrics.clone());
+
+    Ok(metrics)
+}
+
+pub fn invalidate_derived_metrics(device_uuid: &Uuid) {
+    DERIVED_METRIC_CACHE
+        .write()
+        .unwrap_or_else(|err| err.into_inner())
+        .remove(device_uuid);
+}
+
+/// Evaluates the derived metrics of the device reading at least one of the
+/// stored readings of a message. Inputs the message does not carry take
+/// their latest stored value unless older than the configured input age,
+/// derived values never feed another metric.
+/// A metric that cannot be evaluated is logged and skipped.
+pub async fn derive_readings(
+    pool: &PgPool,
+    client: &Client,
+    device_uuid: &Uuid,
+    readings: &[(&MessageReceivePayload, &ReadingValue)],
+) -> Result<Vec<DerivedReading>, AppError> {
+
+    let metrics = get_device_derived_metrics(pool, device_uuid).await?;
+
+    if metrics.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut values: HashMap<String, f64> = HashMap::new();
+    let mut latest = None;
+
+    for (reading, value) in readings {
+        if let Some(number) = value.as_f64() {
+            values.insert(reading.metric.clone(), number);
+        }
+
+        match DateTime::parse_from_rfc3339(&reading.timestamp) {
+            Ok(timestamp) if latest.as_ref().is_none_or(|(latest, _)| timestamp > *latest) => {
+                latest = Some((timestamp, reading.timestamp.clone()));
+            }
+            _ => {}
+        }
+    }
+
+    let (latest_at, timestamp) = match latest {
+        Some(latest) => latest,
+        None => return Ok(Vec::new()),
+    };
+
+    let triggered: Vec<&CompiledDerivedMetric> = metrics
+        .iter()
+        .filter(|derived| derived.expression.variables().iter().any(|variable| values.contains_key(variable)))
+        .collect();
+
+    if triggered.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let incomplete = triggered
+        .iter()
+        .any(|derived| derived.expression.variables().iter().any(|variable| !values.contains_key(variable)));
+
+    if incomplete {
+        let max_age = IngestionConfig::get_derived_input_max_age_secs();
+
+        for owned in get_message_data_store_query(client, vec![*device_uuid]).await? {
+            for (metric, reading) in owned.messages {
+                if reading.derived {
+                    continue;
+                }
+
+                // a device that stopped reporting a metric must not feed a stale value
+                if max_age > 0 && (latest_at - reading.timestamp).num_seconds() > max_age {
+                    continue;
+                }
+
+                if let Some(number) = reading.value.as_f64() {
+                    values.entry(metric).or_insert(number);
+                }
+            }
+        }
+    }
+
+    let mut derived_readings = Vec::with_capacity(triggered.len());
+
+    for derived in triggered {
+        match derived.expression.evaluate(&values) {
+            Ok(value) => derived_readings.push(DerivedReading {
+                metric: derived.metric.clone(),
+                unit: derived.unit.clone(),
+                value,
+                timestamp: timestamp.clone(),
+            }),
+            Err(err) => error!("file: {}, line: {}, Derived metric not evaluated: device_uuid: {}, metric: {}, error: {}",
+                file!(),
+                line!(),
+                device_uuid,
+                derived.metric,
+                err
+            ),
+        }
+    }
+
+    Ok(derived_readings)
+}
@@ -0,0 +1,230 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use openssl::base64::{decode_block, encode_block};
+use serde::{Deserialize, Serialize};
+use crate::error_app::error_app::AppError;
+
+/// What the broker task does when the ingestion queue is full.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    DropOldest,
+    Block,
+    SpillToDisk,
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = AppError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "drop_oldest" => Ok(OverflowPolicy::DropOldest),
+            "block" => Ok(OverflowPolicy::Block),
+            "spill_to_disk" => Ok(OverflowPolicy::SpillToDisk),
+            _ => Err(AppError::BadRequest(format!("Invalid overflow policy: {}", s)))?
+        }
+    }
+}
+
+impl fmt::Display for OverflowPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            OverflowPolicy::DropOldest => "drop_oldest",
+            OverflowPolicy::Block => "block",
+            OverflowPolicy::SpillToDisk => "spill_to_disk",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// Decoder used to turn a received payload into a reading.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum PayloadFormat {
+    #[default]
+    Json,
+    Numeric,
+    Csv,
+    Cbor,
+    MessagePack,
+    Protobuf,
+}
+
+impl FromStr for PayloadFormat {
+    type Err = AppError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "json" => Ok(PayloadFormat::Json),
+            "numeric" => Ok(PayloadFormat::Numeric),
+            "csv" => Ok(PayloadFormat::Csv),
+            "cbor" => Ok(PayloadFormat::Cbor),
+            "message_pack" | "msgpack" => Ok(PayloadFormat::MessagePack),
+            "protobuf" => Ok(PayloadFormat::Protobuf),
+            _ => Err(AppError::BadRequest(format!("Invalid payload format: {}", s)))?
+        }
+    }
+}
+
+impl fmt::Display for PayloadFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            PayloadFormat::Json => "json",
+            PayloadFormat::Numeric => "numeric",
+            PayloadFormat::Csv => "csv",
+            PayloadFormat::Cbor => "cbor",
+            PayloadFormat::MessagePack => "message_pack",
+            PayloadFormat::Protobuf => "protobuf",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl PayloadFormat {
+    /// Per message override through the MQTT v5 content type.
+    pub fn from_content_type(content_type: &str) -> Option<Self> {
+        let mime = content_type.split(';').next().unwrap_or_default().trim().to_lowercase();
+
+        match mime.as_str() {
+            "application/json" => Some(PayloadFormat::Json),
+            "text/plain" => Some(PayloadFormat::Numeric),
+            "text/csv" => Some(PayloadFormat::Csv),
+            "application/cbor" => Some(PayloadFormat::Cbor),
+            "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => Some(PayloadFormat::MessagePack),
+            "application/protobuf" | "application/x-protobuf" | "application/vnd.google.protobuf" => Some(PayloadFormat::Protobuf),
+            _ => None,
+        }
+    }
+}
+
+/// How readings are checked against the scales registered for the device.
+/// `Reject` drops invalid readings, `DeadLetter` keeps them in the dead-letter
+/// store and `Lenient` registers unknown metrics, dead-lettering unit mismatches.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ScaleValidation {
+    #[default]
+    Off,
+    Reject,
+    DeadLetter,
+    Lenient,
+}
+
+impl FromStr for ScaleValidation {
+    type Err = AppError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "off" => Ok(ScaleValidation::Off),
+            "reject" => Ok(ScaleValidation::Reject),
+            "dead_letter" => Ok(ScaleValidation::DeadLetter),
+            "lenient" => Ok(ScaleValidation::Lenient),
+            _ => Err(AppError::BadRequest(format!("Invalid scale validation: {}", s)))?
+        }
+    }
+}
+
+impl fmt::Display for ScaleValidation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            ScaleValidation::Off => "off",
+            ScaleValidation::Reject => "reject",
+            ScaleValidation::DeadLetter => "dead_letter",
+            ScaleValidation::Lenient => "lenient",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// Ingestion settings of a device, mirrored in its data store document.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct DeviceDecoder {
+    #[serde(default)]
+    pub payload_format: PayloadFormat,
+    #[serde(default)]
+    pub payload_metric: Option<String>,
+    #[serde(default)]
+    pub scale_validation: ScaleValidation,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct IngestionMetricsResponse {
+    pub overflow_policy: OverflowPolicy,
+    pub workers: usize,
+    pub queue_capacity: usize,
+    pub queue_depth: usize,
+    pub enqueued: u64,
+    pub processed: u64,
+    pub dropped: u64,
+    pub blocked: u64,
+    pub spilled: u64,
+    pub replayed: u64,
+}
+
+/// A received MQTT message in a storable form, used by the spill file
+/// (one JSON line each) and by the dead-letter store.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StoredMessage {
+    pub topic: String,
+    pub payload: String,
+    pub qos: i32,
+    pub retained: bool,
+    pub content_type: Option<String>,
+    pub user_properties: HashMap<String, String>,
+    pub response_topic: Option<String>,
+    pub correlation_data: Option<String>,
+}
+
+impl StoredMessage {
+    pub fn from_message(message: &paho_mqtt::Message) -> Self {
+        let properties = message.properties();
+
+        StoredMessage {
+            topic: message.topic().to_string(),
+            payload: encode_block(message.payload()),
+            qos: message.qos(),
+            retained: message.retained(),
+            content_type: properties.get_string(paho_mqtt::PropertyCode::ContentType),
+            user_properties: properties.user_iter().collect(),
+            response_topic: properties.get_string(paho_mqtt::PropertyCode::ResponseTopic),
+            correlation_data: properties
+                .get_binary(paho_mqtt::PropertyCode::CorrelationData)
+                .map(|data| encode_block(&data)),
+        }
+    }
+
+    pub fn into_message(self) -> Result<paho_mqtt::Message, String> {
+        let payload = decode_block(&self.payload).map_err(|err| err.to_string())?;
+
+        let mut properties = paho_mqtt::Properties::new();
+
+        if let Some(content_type) = &self.content_type {
+            properties.push_string(paho_mqtt::PropertyCode::ContentType, content_type)
+                .map_err(|err| err.to_string())?;
+        }
+
+        for (key, value) in &self.user_properties {
+            properties.push_string_pair(paho_mqtt::PropertyCode::UserProperty, key, value)
+                .map_err(|err| err.to_string())?;
+        }
+
+        if let Some(response_topic) = &self.response_topic {
+            properties.push_string(paho_mqtt::PropertyCode::ResponseTopic, response_topic)
+                .map_err(|err| err.to_string())?;
+        }
+
+        if let Some(correlation_data) = &self.correlation_data {
+            let correlation_data = decode_block(correlation_data).map_err(|err| err.to_string())?;
+            properties.push_binary(paho_mqtt::PropertyCode::CorrelationData, correlation_data)
+                .map_err(|err| err.to_string())?;
+        }
+
+        Ok(
+            paho_mqtt::MessageBuilder::new()
+                .topic(self.topic)
+                .payload(payload)
+                .qos(self.qos)
+                .retained(self.retained)
+                .properties(properties)
+                .finalize()
+        )
+    }
+}
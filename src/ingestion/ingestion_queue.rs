@@ -0,0 +1,341 @@
+use std::collections::VecDeque;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use log::{error, info};
+use tokio::fs::{self, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Notify;
+use uuid::Uuid;
+use crate::ingestion::ingestion_config::IngestionConfig;
+use crate::ingestion::ingestion_model::{IngestionMetricsResponse, OverflowPolicy, StoredMessage};
+
+struct IngestionQueueInner {
+    messages: VecDeque<paho_mqtt::Message>,
+    closed: bool,
+}
+
+/// Bounded queue between a broker stream and its ingestion workers.
+/// When full, the configured `OverflowPolicy` decides whether the oldest
+/// message is dropped, the broker task waits, or the message is spilled to
+/// disk and replayed by the workers once the queue is empty again.
+pub struct IngestionQueue {
+    inner: Mutex<IngestionQueueInner>,
+    capacity: usize,
+    workers: usize,
+    policy: OverflowPolicy,
+    spill_path: PathBuf,
+    spill_lock: tokio::sync::Mutex<()>,
+    spill_pending: AtomicBool,
+    replaying: AtomicBool,
+    not_empty: Notify,
+    not_full: Notify,
+    enqueued: AtomicU64,
+    processed: AtomicU64,
+    dropped: AtomicU64,
+    blocked: AtomicU64,
+    spilled: AtomicU64,
+    replayed: AtomicU64,
+}
+
+impl IngestionQueue {
+    pub fn new(broker_uuid: &Uuid) -> Self {
+        let spill_path = IngestionConfig::get_spill_dir().join(format!("{}.spill", broker_uuid));
+
+        IngestionQueue {
+            inner: Mutex::new(IngestionQueueInner {
+                messages: VecDeque::new(),
+                closed: false,
+            }),
+            capacity: IngestionConfig::get_queue_capacity(),
+            workers: IngestionConfig::get_workers(),
+            policy: IngestionConfig::get_overflow_policy(),
+            // a spill file left by the previous run is replayed too
+            spill_pending: AtomicBool::new(spill_path.exists()),
+            spill_path,
+            spill_lock: tokio::sync::Mutex::new(()),
+            replaying: AtomicBool::new(false),
+            not_empty: Notify::new(),
+            not_full: Notify::new(),
+            enqueued: AtomicU64::new(0),
+            processed: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            blocked: AtomicU64::new(0),
+            spilled: AtomicU64::new(0),
+            replayed: AtomicU64::new(0),
+        }
+    }
+
+    pub fn workers(&self) -> usize {
+        self.workers
+    }
+
+    /// Enqueues a received message, applying the overflow policy when full.
+    /// With `OverflowPolicy::Block` this waits for a worker to free a slot,
+    /// which stops the broker task from reading the MQTT stream meanwhile.
+    pub async fn push(&self, message: paho_mqtt::Message) {
+        let mut message = Some(message);
+        let mut waited = false;
+
+        loop {
+            let notified = self.not_full.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            let spill = {
+                let mut inner = self.inner.lock().unwrap();
+
+                if inner.messages.len() < self.capacity {
+                    inner.messages.push_back(message.take().unwrap());
+                    self.enqueued.fetch_add(1, Ordering::Relaxed);
+                    drop(inner);
+                    self.not_empty.notify_one();
+                    return;
+                }
+
+                match self.policy {
+                    OverflowPolicy::DropOldest => {
+                        inner.messages.pop_front();
+                        inner.messages.push_back(message.take().unwrap());
+                        self.enqueued.fetch_add(1, Ordering::Relaxed);
+                        self.dropped.fetch_add(1, Ordering::Relaxed);
+                        drop(inner);
+                        self.not_empty.notify_one();
+                        return;
+                    }
+                    OverflowPolicy::SpillToDisk => true,
+                    OverflowPolicy::Block => {
+                        if inner.closed {
+                            self.dropped.fetch_add(1, Ordering::Relaxed);
+                            return;
+                        }
+                        if !waited {
+                            waited = true;
+                            self.blocked.fetch_add(1, Ordering::Relaxed);
+                        }
+                        false
+                    }
+                }
+            };
+
+            if spill {
+                let message = message.take().unwrap();
+                match self.spill(&message).await {
+                    Ok(_) => {
+                        self.spilled.fetch_add(1, Ordering::Relaxed);
+                    }
+                    Err(err) => {
+                        error!("file: {}, line: {}: Failed to spill message to {:?}: {}",
+                            file!(),
+                            line!(),
+                            self.spill_path,
+                            err
+                        );
+                        self.dropped.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+                self.not_empty.notify_one();
+                return;
+            }
+
+            notified.await;
+        }
+    }
+
+    /// Takes the next message, waiting while the queue is empty.
+    /// Returns `None` once the queue is closed and fully drained.
+    pub async fn pop(&self) -> Option<paho_mqtt::Message> {
+        loop {
+            let notified = self.not_empty.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            {
+                let mut inner = self.inner.lock().unwrap();
+
+                if let Some(message) = inner.messages.pop_front() {
+                    drop(inner);
+                    self.not_full.notify_one();
+                    return Some(message);
+                }
+
+                if inner.closed && !self.has_spill() {
+                    return None;
+                }
+            }
+
+            if self.has_spill() {
+                if let Some(messages) = self.take_spill().await {
+                    return Some(self.requeue(messages).await);
+                }
+            }
+
+            notified.await;
+        }
+    }
+
+    /// Marks a message as processed, used for the backpressure metrics.
+    pub fn done(&self) {
+        self.processed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Stops accepting new messages and wakes the workers so they drain
+    /// what is left and exit.
+    pub fn close(&self) {
+        self.inner.lock().unwrap().closed = true;
+        self.not_empty.notify_waiters();
+        self.not_full.notify_waiters();
+    }
+
+    pub fn metrics(&self) -> IngestionMetricsResponse {
+        let queue_depth = self.inner.lock().unwrap().messages.len();
+
+        IngestionMetricsResponse {
+            overflow_policy: self.policy,
+            workers: self.workers,
+            queue_capacity: self.capacity,
+            queue_depth,
+            enqueued: self.enqueued.load(Ordering::Relaxed),
+            processed: self.processed.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            blocked: self.blocked.load(Ordering::Relaxed),
+            spilled: self.spilled.load(Ordering::Relaxed),
+            replayed: self.replayed.load(Ordering::Relaxed),
+        }
+    }
+
+    async fn spill(&self, message: &paho_mqtt::Message) -> std::io::Result<()> {
+        let record = serde_json::to_string(&StoredMessage::from_message(message))?;
+
+        // one writer at a time, records never interleave
+        let _guard = self.spill_lock.lock().await;
+
+        if let Some(dir) = self.spill_path.parent() {
+            fs::create_dir_all(dir).await?;
+        }
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.spill_path)
+            .await?;
+        file.write_all(format!("{}\n", record).as_bytes()).await?;
+        file.flush().await?;
+
+        self.spill_pending.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    fn has_spill(&self) -> bool {
+        self.policy == OverflowPolicy::SpillToDisk
+            && !self.replaying.load(Ordering::Acquire)
+            && self.spill_pending.load(Ordering::Acquire)
+    }
+
+    /// Moves the spill file aside and reads it back, only one worker replays
+    /// at a time while new overflow keeps going to a fresh spill file.
+    async fn take_spill(&self) -> Option<VecDeque<paho_mqtt::Message>> {
+        if self.replaying.swap(true, Ordering::AcqRel) {
+            return None;
+        }
+
+        let replay_path = self.spill_path.with_extension("replay");
+
+        let result = {
+            let _guard = self.spill_lock.lock().await;
+            self.spill_pending.store(false, Ordering::Release);
+
+            match fs::rename(&self.spill_path, &replay_path).await {
+                Ok(_) => fs::read_to_string(&replay_path).await,
+                Err(err) => Err(err),
+            }
+        };
+
+        let content = match result {
+            Ok(content) => content,
+            Err(err) => {
+                if err.kind() != ErrorKind::NotFound {
+                    error!("file: {}, line: {}: Failed to open spill file {:?}: {}",
+                        file!(),
+                        line!(),
+                        self.spill_path,
+                        err
+                    );
+                }
+                self.replaying.store(false, Ordering::Release);
+                return None;
+            }
+        };
+
+        let mut messages = VecDeque::new();
+        for line in content.lines().filter(|line| !line.is_empty()) {
+            let message = serde_json::from_str::<StoredMessage>(line)
+                .map_err(|err| err.to_string())
+                .and_then(StoredMessage::into_message);
+
+            match message {
+                Ok(message) => messages.push_back(message),
+                Err(err) => {
+                    error!("file: {}, line: {}: Discarding unreadable spill record: {}",
+                        file!(),
+                        line!(),
+                        err
+                    );
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+
+        let _ = fs::remove_file(&replay_path).await;
+        self.replaying.store(false, Ordering::Release);
+
+        info!("file: {}, line: {}: Replaying {} spilled message(s) from {:?}",
+            file!(),
+            line!(),
+            messages.len(),
+            self.spill_path
+        );
+
+        if messages.is_empty() {
+            return None;
+        }
+
+        self.replayed.fetch_add(messages.len() as u64, Ordering::Relaxed);
+        Some(messages)
+    }
+
+    /// Puts replayed messages back in the queue ahead of anything newer and
+    /// hands the first one to the calling worker. Whatever does not fit in
+    /// the queue goes back to the spill file.
+    async fn requeue(&self, mut messages: VecDeque<paho_mqtt::Message>) -> paho_mqtt::Message {
+        let first = messages.pop_front().unwrap();
+
+        let overflow = {
+            let mut inner = self.inner.lock().unwrap();
+            let room = self.capacity.saturating_sub(inner.messages.len());
+            let take = room.min(messages.len());
+            let overflow = messages.split_off(take);
+
+            for message in messages.into_iter().rev() {
+                inner.messages.push_front(message);
+            }
+            overflow
+        };
+
+        for message in overflow {
+            if let Err(err) = self.spill(&message).await {
+                error!("file: {}, line: {}: Failed to spill message to {:?}: {}",
+                    file!(),
+                    line!(),
+                    self.spill_path,
+                    err
+                );
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        self.not_empty.notify_waiters();
+        first
+    }
+}
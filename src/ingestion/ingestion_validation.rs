@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use log::info;
+use sqlx::PgPool;
+use uuid::Uuid;
+use crate::data_store::data_store_dead_letter_model::{DeadLetterReason, IngestionFailure};
+use crate::data_store::data_store_device_model::ReadingValue;
+use crate::device::device_adoption_tool::DecomposeTopic;
+use crate::device::device_message_model::{MessageReceivePayload, ScaleDataType};
+use crate::device::device_message_query::{get_device_scale_with_device_uuid_query, post_device_scale_with_device_uuid_query};
+use crate::error_app::error_app::{AppError, AppMsgError};
+use crate::ingestion::ingestion_model::ScaleValidation;
+
+/// Checks decoded readings against the scales registered for their device
+/// and returns their values typed by the metric data type, in order.
+/// A reading without a unit takes the registered one, an unknown metric or a
+/// different unit fails according to the device `ScaleValidation`, a value
+/// that does not parse as the declared data type is a decode error.
+pub async fn validate_readings(
+    pool: &PgPool,
+    scale_validation: ScaleValidation,
+    readings: &mut [(DecomposeTopic, MessageReceivePayload)],
+) -> Result<Vec<ReadingValue>, IngestionFailure> {
+
+    let mut scales: HashMap<Uuid, HashMap<String, (String, Option<ScaleDataType>)>> = HashMap::new();
+    let mut values: Vec<ReadingValue> = Vec::with_capacity(readings.len());
+
+    for (decompose_topic, reading) in readings.iter_mut() {
+        let device_uuid = decompose_topic.device_uuid;
+
+        if !scales.contains_key(&device_uuid) {
+            let device_scales = get_device_scale_with_device_uuid_query(pool, &device_uuid)
+                .await?
+                .into_iter()
+                .map(|scale| {
+                    let data_type = scale.data_type
+                        .as_deref()
+                        .and_then(|data_type| ScaleDataType::from_str(data_type).ok());
+                    (scale.metric, (scale.unit, data_type))
+                })
+                .collect();
+            scales.insert(device_uuid, device_scales);
+        }
+
+        let device_scales = scales.get_mut(&device_uuid).unwrap();
+        let data_type = device_scales.get(&reading.metric).and_then(|(_, data_type)| *data_type);
+
+        let value = match ReadingValue::parse(&reading.payload, data_type) {
+            Ok(value) => value,
+            Err(log_msg_error) => {
+                return Err(IngestionFailure::dead_letter(
+                    DeadLetterReason::DecodeError,
+                    AppError::UnprocessableEntity(AppMsgError {
+                        api_msg_error: "Reading value does not match the metric data type".into(),
+                        log_msg_error: format!("device_uuid: {}, metric: {}, {}", device_uuid, reading.metric, log_msg_error),
+                    })
+                ));
+            }
+        };
+        values.push(value);
+
+        if scale_validation == ScaleValidation::Off {
+            continue;
+        }
+
+        let scale = reading.scale.trim().to_string();
+
+        match device_scales.get(&reading.metric).map(|(unit, _)| unit) {
+            Some(unit) if scale.is_empty() => {
+                reading.scale = unit.clone();
+            }
+            Some(unit) if scale != *unit => {
+                return Err(scale_failure(
+                    scale_validation,
+                    format!("Unit mismatch: device_uuid: {}, metric: {}, unit: {}, registered: {}", device_uuid, reading.metric, scale, unit)
+                ));
+            }
+            Some(_) => {}
+            None if scale_validation == ScaleValidation::Lenient => {
+                post_device_scale_with_device_uuid_query(pool, &device_uuid, &reading.metric, &scale).await?;
+
+                info!("file: {}, line: {}, Metric {} registered for device {}, unit: {}",
+                    file!(),
+                    line!(),
+                    reading.metric,
+                    device_uuid,
+                    scale
+                );
+
+                device_scales.insert(reading.metric.clone(), (scale, None));
+            }
+            None => {
+                return Err(scale_failure(
+                    scale_validation,
+                    format!("Metric not registered: device_uuid: {}, metric: {}", device_uuid, reading.metric)
+                ));
+            }
+        }
+    }
+
+    Ok(values)
+}
+
+fn scale_failure(scale_validation: ScaleValidation, log_msg_error: String) -> IngestionFailure {
+    let error = AppError::UnprocessableEntity(AppMsgError {
+        api_msg_error: "Reading does not match the device scales".into(),
+        log_msg_error,
+    });
+
+    match scale_validation {
+        ScaleValidation::Reject => IngestionFailure::from(error),
+        _ => IngestionFailure::dead_letter(DeadLetterReason::InvalidScale, error),
+    }
+}
@@ -0,0 +1,49 @@
+use std::sync::Arc;
+use actix_web::web;
+use log::info;
+use mongodb::Client;
+use sqlx::PgPool;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+use crate::broker::broker_model::{BrokerManager, BrokerStats};
+use crate::data_store::data_store_device_handler::put_device_collection;
+use crate::ingestion::ingestion_queue::IngestionQueue;
+
+/// Spawns the ingestion workers of a broker on the manager's task tracker,
+/// so a graceful shutdown waits for the queue to be drained into Mongo.
+/// Workers write concurrently, readings of one device may land out of order.
+pub fn spawn_ingestion_workers(
+    broker_uuid: Uuid,
+    queue: Arc<IngestionQueue>,
+    pool: PgPool,
+    mongo_db: Client,
+    stats: Arc<Mutex<BrokerStats>>,
+    manager: &web::Data<BrokerManager>,
+) {
+    for worker in 0..queue.workers() {
+        let queue = queue.clone();
+        let pool = pool.clone();
+        let mongo_db = mongo_db.clone();
+        let stats = stats.clone();
+
+        manager.spawn(async move {
+            while let Some(msg) = queue.pop().await {
+                let result = put_device_collection(&pool, mongo_db.clone(), &broker_uuid, &msg).await;
+                queue.done();
+
+                let mut stats = stats.lock().await;
+                match result {
+                    Ok(_) => stats.messages_received += 1,
+                    Err(_) => stats.messages_failed += 1,
+                }
+            }
+
+            info!("file: {}, line: {}: Ingestion worker {} of broker {} stopped",
+                file!(),
+                line!(),
+                worker,
+                broker_uuid
+            );
+        });
+    }
+}
@@ -0,0 +1,7 @@
+pub mod ingestion_config;
+pub mod ingestion_decoder;
+pub mod ingestion_derived;
+pub mod ingestion_model;
+pub mod ingestion_queue;
+pub mod ingestion_validation;
+pub mod ingestion_worker;
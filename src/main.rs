@@ -9,6 +9,10 @@ pub mod device;
 pub mod paginate;
 mod timezone;
 mod data_store;
+mod ingestion;
+mod payload_schema;
+mod retention;
+mod realtime;
 
 use std::io;
 use actix_web::{web, App, HttpServer};
@@ -18,10 +22,20 @@ use auth::auth_route::auth_cfg;
 use user::user_route::user_cfg;
 use auth::auth_config::AuthConfig;
 use crate::broker::broker_model::BrokerManager;
+use crate::broker::broker_connection::{restore_broker_connections, shutdown_broker_connections};
+use crate::broker::broker_secret::BrokerSecretConfig;
+use crate::broker::broker_tool::encrypt_plaintext_client_keys;
 use crate::broker::broker_route::broker_cfg;
+use crate::data_store::data_store_dead_letter_route::data_store_dead_letter_cfg;
 use crate::data_store::data_store_device_route::data_store_device_cfg;
-use crate::database::connection_mongo::init_devices_collection;
+use crate::data_store::data_store_migration::run_data_store_migrations;
+use crate::database::connection_mongo::{init_dead_letters_collection, init_devices_collection, init_latest_readings_collection, init_readings_collection, init_rollups_collection};
 use crate::device::device_route::device_cfg;
+use crate::payload_schema::payload_schema_route::payload_schema_cfg;
+use crate::realtime::realtime_route::realtime_cfg;
+use crate::retention::retention_job::spawn_retention_job;
+use crate::retention::retention_model::RetentionState;
+use crate::retention::retention_route::retention_cfg;
 use crate::timezone::timezone_route::timezone_cfg;
 
 #[actix_web::main]
@@ -37,20 +51,67 @@ async fn main()-> io::Result<()> {
         AuthConfig::get_public_key_path()
     ).await;
 
+    //Init broker secret config, used to encrypt broker credentials at rest
+    BrokerSecretConfig::load();
+
     let dp_postgres_pool = database::connection_postgres::get_postgres_pool().await;
     let shared_data = state::app_state(dp_postgres_pool).await;
 
+    encrypt_plaintext_client_keys(&shared_data.db)
+        .await
+        .expect("Failed to encrypt stored broker client keys");
+
     let _= init_devices_collection(
         shared_data.clone(),
         "devices").await.expect("Failed to initialize devices collection");
 
+    let _= init_readings_collection(
+        shared_data.clone(),
+        "devices").await.expect("Failed to initialize readings collection");
+
+    let _= init_rollups_collection(
+        shared_data.clone(),
+        "devices").await.expect("Failed to initialize rollups collection");
+
+    let _= init_latest_readings_collection(
+        shared_data.clone(),
+        "devices").await.expect("Failed to initialize latest readings collection");
+
+    let _= init_dead_letters_collection(
+        shared_data.clone(),
+        "devices").await.expect("Failed to initialize dead letters collection");
+
+    run_data_store_migrations(
+        &shared_data.db,
+        &shared_data.mongo,
+        "devices").await.expect("Failed to run data store migrations");
+
+
+    let broker_manager = web::Data::new(BrokerManager::default());
+
+    restore_broker_connections(
+        &shared_data.db,
+        shared_data.mongo.clone(),
+        broker_manager.clone()
+    ).await.expect("Failed to restore broker connections");
+
+    //Rollups and pruning of expired readings
+    let retention_state = web::Data::new(RetentionState::default());
+
+    spawn_retention_job(
+        shared_data.db.clone(),
+        shared_data.mongo.clone(),
+        retention_state.clone()
+    );
+
+    let shutdown_pool = shared_data.db.clone();
+    let broker_manager_shutdown = broker_manager.clone();
 
-    let broker_manager = BrokerManager::default();
-    
     let app = move ||{
         App::new()
             .app_data(shared_data.clone())
-            .app_data(web::Data::new(broker_manager.clone()))
+            .app_data(broker_manager.clone())
+            .app_data(retention_state.clone())
             .configure(health_check_cfg)
             .configure(auth_cfg)
             .configure(user_cfg)
@@ -58,6 +119,10 @@ async fn main()-> io::Result<()> {
             .configure(broker_cfg)
             .configure(device_cfg)
             .configure(data_store_device_cfg)
+            .configure(data_store_dead_letter_cfg)
+            .configure(payload_schema_cfg)
+            .configure(retention_cfg)
+            .configure(realtime_cfg)
     };
 
 
@@ -66,5 +131,10 @@ async fn main()-> io::Result<()> {
 
     let server_address = host_address.as_str();
 
-    HttpServer::new(app).bind(server_address)?.run().await
+    // actix stops on SIGTERM/SIGINT, then the broker tasks are drained
+    let server_result = HttpServer::new(app).bind(server_address)?.run().await;
+
+    shutdown_broker_connections(&shutdown_pool, broker_manager_shutdown).await;
+
+    server_result
 }
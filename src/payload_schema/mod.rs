@@ -0,0 +1,5 @@
+pub mod payload_schema_model;
+pub(crate) mod payload_schema_query;
+mod payload_schema_handler;
+pub mod payload_schema_route;
+pub mod payload_schema_tool;
\ No newline at end of file
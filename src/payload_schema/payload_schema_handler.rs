@@ -0,0 +1,110 @@
+use actix_web::{web, HttpResponse};
+use log::info;
+use uuid::Uuid;
+use web::Json;
+use crate::error_app::error_app::{AppError, AppMsgError};
+use crate::payload_schema::payload_schema_model::{PayloadSchemaCreate, PayloadSchemaUpdate};
+use crate::payload_schema::payload_schema_query::{delete_payload_schema_query, get_payload_schema_query, get_payload_schema_with_sensor_type_query, get_payload_schema_with_uuid_query, post_payload_schema_query, put_payload_schema_query};
+use crate::payload_schema::payload_schema_tool::{compile_payload_schema, decode_descriptor_set, invalidate_payload_schema, payload_schema_response};
+use crate::state::AppState;
+
+pub async fn payload_schema_create(
+    schema: Json<PayloadSchemaCreate>,
+    app_state: web::Data<AppState>
+) -> Result<HttpResponse, AppError> {
+
+    let schema = schema.into_inner();
+
+    let descriptor_set = decode_descriptor_set(&schema.descriptor_set)?;
+    compile_payload_schema(&descriptor_set, &schema.message_name)?;
+
+    if get_payload_schema_with_sensor_type_query(&app_state.db, &schema.sensor_type).await?.is_some() {
+        return Err(AppError::ConstraintViolation(
+            AppMsgError{
+                api_msg_error: "Payload schema already registered for sensor type".to_string(),
+                log_msg_error: format!("Payload schema already registered, sensor_type: {}", &schema.sensor_type)
+            }
+        ))?
+    }
+
+    let result = post_payload_schema_query(
+        &app_state.db,
+        &Uuid::new_v4(),
+        &schema.sensor_type,
+        &schema.message_name,
+        &descriptor_set
+    ).await?;
+
+    invalidate_payload_schema(&result.sensor_type);
+
+    info!("file: {}, line: {}, Payload schema {} registered for sensor type {}",
+        file!(),
+        line!(),
+        result.message_name,
+        result.sensor_type
+    );
+
+    Ok(HttpResponse::Ok().json(payload_schema_response(result)?))
+}
+
+pub async fn payload_schema_get(
+    app_state: web::Data<AppState>
+) -> Result<HttpResponse, AppError> {
+
+    let result = get_payload_schema_query(&app_state.db)
+        .await?
+        .into_iter()
+        .map(payload_schema_response)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(HttpResponse::Ok().json(result))
+}
+
+pub async fn payload_schema_get_uuid(
+    schema_uuid: web::Path<Uuid>,
+    app_state: web::Data<AppState>
+) -> Result<HttpResponse, AppError> {
+
+    let result = get_payload_schema_with_uuid_query(&app_state.db, &schema_uuid).await?;
+
+    Ok(HttpResponse::Ok().json(payload_schema_response(result)?))
+}
+
+pub async fn payload_schema_update(
+    schema_uuid: web::Path<Uuid>,
+    schema: Json<PayloadSchemaUpdate>,
+    app_state: web::Data<AppState>
+) -> Result<HttpResponse, AppError> {
+
+    let schema = schema.into_inner();
+
+    let descriptor_set = decode_descriptor_set(&schema.descriptor_set)?;
+    compile_payload_schema(&descriptor_set, &schema.message_name)?;
+
+    get_payload_schema_with_uuid_query(&app_state.db, &schema_uuid).await?;
+
+    let result = put_payload_schema_query(
+        &app_state.db,
+        &schema_uuid,
+        &schema.message_name,
+        &descriptor_set
+    ).await?;
+
+    invalidate_payload_schema(&result.sensor_type);
+
+    Ok(HttpResponse::Ok().json(payload_schema_response(result)?))
+}
+
+pub async fn payload_schema_delete(
+    schema_uuid: web::Path<Uuid>,
+    app_state: web::Data<AppState>
+) -> Result<HttpResponse, AppError> {
+
+    let schema = get_payload_schema_with_uuid_query(&app_state.db, &schema_uuid).await?;
+
+    delete_payload_schema_query(&app_state.db, &schema.uuid).await?;
+
+    invalidate_payload_schema(&schema.sensor_type);
+
+    Ok(HttpResponse::NoContent().finish())
+}
@@ -0,0 +1,46 @@
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct PayloadSchema {
+    pub id: i32,
+    pub uuid: Uuid,
+    pub sensor_type: String,
+    pub message_name: String,
+    pub descriptor_set: Vec<u8>,
+    pub created_at: Option<chrono::DateTime<Utc>>,
+    pub updated_at: Option<chrono::DateTime<Utc>>,
+    pub deleted_at: Option<chrono::DateTime<Utc>>,
+}
+
+/// `descriptor_set` is a base64 `FileDescriptorSet`, e.g. the output of
+/// `protoc --include_imports --descriptor_set_out=...`.
+#[derive(Debug, Deserialize)]
+pub struct PayloadSchemaCreate {
+    pub sensor_type: String,
+    pub message_name: String,
+    pub descriptor_set: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PayloadSchemaUpdate {
+    pub message_name: String,
+    pub descriptor_set: String,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct PayloadSchemaField {
+    pub metric: String,
+    pub kind: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PayloadSchemaResponse {
+    pub uuid: Uuid,
+    pub sensor_type: String,
+    pub message_name: String,
+    pub fields: Vec<PayloadSchemaField>,
+    pub created_at: Option<chrono::DateTime<Utc>>,
+    pub updated_at: Option<chrono::DateTime<Utc>>,
+}
@@ -0,0 +1,176 @@
+use sqlx::PgPool;
+use uuid::Uuid;
+use crate::error_app::error_app::{AppError, AppMsgError};
+use crate::payload_schema::payload_schema_model::PayloadSchema;
+
+pub async fn post_payload_schema_query(
+    pool: &PgPool,
+    schema_uuid: &Uuid,
+    sensor_type: &str,
+    message_name: &str,
+    descriptor_set: &[u8],
+) -> Result<PayloadSchema, AppError> {
+
+    match sqlx::query_as!(
+        PayloadSchema,
+        r#"
+        INSERT INTO payload_schemas(
+            uuid,
+            sensor_type,
+            message_name,
+            descriptor_set
+        )
+        VALUES ($1, $2, $3, $4)
+        RETURNING
+            id,
+            uuid,
+            sensor_type,
+            message_name,
+            descriptor_set,
+            created_at,
+            updated_at,
+            deleted_at
+        "#,
+        schema_uuid,
+        sensor_type,
+        message_name,
+        descriptor_set,
+    ).fetch_one(pool).await{
+        Ok(result) => Ok(result),
+        Err(err) => Err(AppError::DBError(format!("file: {}, line: {}, error: {}", file!(), line!(), err)))?,
+    }
+}
+
+pub async fn get_payload_schema_query(
+    pool: &PgPool,
+) -> Result<Vec<PayloadSchema>, AppError> {
+
+    match sqlx::query_as!(
+        PayloadSchema,
+        r#"
+        SELECT
+            id,
+            uuid,
+            sensor_type,
+            message_name,
+            descriptor_set,
+            created_at,
+            updated_at,
+            deleted_at
+        FROM payload_schemas
+        WHERE deleted_at IS NULL
+        ORDER BY sensor_type ASC
+        "#,
+    ).fetch_all(pool).await{
+        Ok(result) => Ok(result),
+        Err(err) => Err(AppError::DBError(format!("file: {}, line: {}, error: {}", file!(), line!(), err)))?,
+    }
+}
+
+pub async fn get_payload_schema_with_uuid_query(
+    pool: &PgPool,
+    schema_uuid: &Uuid,
+) -> Result<PayloadSchema, AppError> {
+
+    match sqlx::query_as!(
+        PayloadSchema,
+        r#"
+        SELECT
+            id,
+            uuid,
+            sensor_type,
+            message_name,
+            descriptor_set,
+            created_at,
+            updated_at,
+            deleted_at
+        FROM payload_schemas
+        WHERE uuid = $1
+        AND deleted_at IS NULL
+        "#,
+        schema_uuid
+    ).fetch_optional(pool).await{
+        Ok(Some(result)) => Ok(result),
+        Ok(None) => Err(AppError::NotFound(AppMsgError {
+            api_msg_error: "Payload schema not found".into(),
+            log_msg_error: format!("file: {}, line: {}, Payload schema not found: uuid: {}", file!(), line!(), schema_uuid),
+        }))?,
+        Err(err) => Err(AppError::DBError(format!("file: {}, line: {}, error: {}", file!(), line!(), err)))?,
+    }
+}
+
+pub async fn get_payload_schema_with_sensor_type_query(
+    pool: &PgPool,
+    sensor_type: &str,
+) -> Result<Option<PayloadSchema>, AppError> {
+
+    match sqlx::query_as!(
+        PayloadSchema,
+        r#"
+        SELECT
+            id,
+            uuid,
+            sensor_type,
+            message_name,
+            descriptor_set,
+            created_at,
+            updated_at,
+            deleted_at
+        FROM payload_schemas
+        WHERE LOWER(sensor_type) = LOWER($1)
+        AND deleted_at IS NULL
+        "#,
+        sensor_type
+    ).fetch_optional(pool).await{
+        Ok(result) => Ok(result),
+        Err(err) => Err(AppError::DBError(format!("file: {}, line: {}, error: {}", file!(), line!(), err)))?,
+    }
+}
+
+pub async fn put_payload_schema_query(
+    pool: &PgPool,
+    schema_uuid: &Uuid,
+    message_name: &str,
+    descriptor_set: &[u8],
+) -> Result<PayloadSchema, AppError> {
+
+    match sqlx::query_as!(
+        PayloadSchema,
+        r#"
+        UPDATE payload_schemas SET
+            message_name = $1,
+            descriptor_set = $2
+        WHERE uuid = $3
+        AND deleted_at IS NULL
+        RETURNING
+            id,
+            uuid,
+            sensor_type,
+            message_name,
+            descriptor_set,
+            created_at,
+            updated_at,
+            deleted_at
+        "#,
+        message_name,
+        descriptor_set,
+        schema_uuid
+    ).fetch_one(pool).await{
+        Ok(result) => Ok(result),
+        Err(err) => Err(AppError::DBError(format!("file: {}, line: {}, error: {}", file!(), line!(), err)))?,
+    }
+}
+
+pub async fn delete_payload_schema_query(
+    pool: &PgPool,
+    schema_uuid: &Uuid,
+) -> Result<(), AppError> {
+
+    match sqlx::query!(
+        "UPDATE payload_schemas SET deleted_at = NOW() WHERE uuid = $1 AND deleted_at IS NULL",
+        schema_uuid
+    ).execute(pool).await{
+        Ok(_) => Ok(()),
+        Err(err) => Err(AppError::DBError(format!("file: {}, line: {}, error: {}", file!(), line!(), err)))?,
+    }
+}
@@ -0,0 +1,16 @@
+use actix_web::middleware::from_fn;
+use actix_web::web;
+use crate::auth;
+use crate::payload_schema::payload_schema_handler::{payload_schema_create, payload_schema_delete, payload_schema_get, payload_schema_get_uuid, payload_schema_update};
+
+pub fn payload_schema_cfg(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/payload_schema")
+            .wrap(from_fn(auth::auth_midlleware::auth_middleware))
+            .route("", web::post().to(payload_schema_create))
+            .route("", web::get().to(payload_schema_get))
+            .route("/{uuid}", web::get().to(payload_schema_get_uuid))
+            .route("/{uuid}", web::put().to(payload_schema_update))
+            .route("/{uuid}", web::delete().to(payload_schema_delete))
+    );
+}
@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+use std::sync::RwLock;
+use log::info;
+use once_cell::sync::Lazy;
+use openssl::base64::decode_block;
+use prost_reflect::{DescriptorPool, Kind, MessageDescriptor};
+use sqlx::PgPool;
+use crate::error_app::error_app::AppError;
+use crate::payload_schema::payload_schema_model::{PayloadSchema, PayloadSchemaField, PayloadSchemaResponse};
+use crate::payload_schema::payload_schema_query::get_payload_schema_with_sensor_type_query;
+
+pub const PROTOBUF_TIMESTAMP: &str = "google.protobuf.Timestamp";
+
+/// Compiled message descriptors by lowercase sensor type, so ingestion does
+/// not parse the descriptor set for every message.
+static PAYLOAD_SCHEMA_CACHE: Lazy<RwLock<HashMap<String, MessageDescriptor>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+
+pub fn decode_descriptor_set(descriptor_set: &str) -> Result<Vec<u8>, AppError> {
+    decode_block(descriptor_set.trim())
+        .map_err(|err| AppError::BadRequest(format!("descriptor_set must be base64: {}", err)))
+}
+
+pub fn compile_payload_schema(descriptor_set: &[u8], message_name: &str) -> Result<MessageDescriptor, AppError> {
+
+    let pool = DescriptorPool::decode(descriptor_set)
+        .map_err(|err| AppError::BadRequest(format!("Invalid descriptor set: {}", err)))?;
+
+    pool.get_message_by_name(message_name)
+        .ok_or_else(|| AppError::BadRequest(format!("Message not found in descriptor set: {}", message_name)))
+}
+
+/// Descriptor of the schema registered for a sensor type, if any.
+pub async fn get_payload_schema_descriptor(
+    pool: &PgPool,
+    sensor_type: &str,
+) -> Result<Option<MessageDescriptor>, AppError> {
+
+    let key = sensor_type.to_lowercase();
+
+    if let Some(descriptor) = PAYLOAD_SCHEMA_CACHE.read().unwrap().get(&key) {
+        return Ok(Some(descriptor.clone()));
+    }
+
+    let schema = match get_payload_schema_with_sensor_type_query(pool, sensor_type).await? {
+        Some(schema) => schema,
+        None => return Ok(None),
+    };
+
+    let descriptor = compile_payload_schema(&schema.descriptor_set, &schema.message_name)?;
+
+    info!("file: {}, line: {}, Payload schema {} loaded for sensor type {}",
+        file!(),
+        line!(),
+        schema.message_name,
+        sensor_type
+    );
+
+    PAYLOAD_SCHEMA_CACHE.write().unwrap().insert(key, descriptor.clone());
+
+    Ok(Some(descriptor))
+}
+
+pub fn invalidate_payload_schema(sensor_type: &str) {
+    PAYLOAD_SCHEMA_CACHE.write().unwrap().remove(&sensor_type.to_lowercase());
+}
+
+/// Metrics produced by a schema, nested messages are flattened with `_`.
+pub fn payload_schema_fields(descriptor: &MessageDescriptor) -> Vec<PayloadSchemaField> {
+    let mut fields = Vec::new();
+    collect_fields(descriptor, "", &mut fields);
+    fields
+}
+
+fn collect_fields(descriptor: &MessageDescriptor, prefix: &str, fields: &mut Vec<PayloadSchemaField>) {
+    for field in descriptor.fields() {
+        let metric = format!("{}{}", prefix, field.name());
+
+        match field.kind() {
+            Kind::Message(message) if message.full_name() != PROTOBUF_TIMESTAMP && !field.is_list() && !field.is_map() => {
+                collect_fields(&message, &format!("{}_", metric), fields);
+            }
+            kind => fields.push(PayloadSchemaField {
+                metric,
+                kind: match kind {
+                    Kind::Message(message) => message.full_name().to_string(),
+                    Kind::Enum(enum_descriptor) => enum_descriptor.full_name().to_string(),
+                    kind => format!("{:?}", kind).to_lowercase(),
+                },
+            }),
+        }
+    }
+}
+
+pub fn payload_schema_response(schema: PayloadSchema) -> Result<PayloadSchemaResponse, AppError> {
+    let descriptor = compile_payload_schema(&schema.descriptor_set, &schema.message_name)?;
+
+    Ok(PayloadSchemaResponse {
+        uuid: schema.uuid,
+        sensor_type: schema.sensor_type,
+        message_name: schema.message_name,
+        fields: payload_schema_fields(&descriptor),
+        created_at: schema.created_at,
+        updated_at: schema.updated_at,
+    })
+}
@@ -0,0 +1,4 @@
+pub mod realtime_model;
+pub mod realtime_hub;
+mod realtime_handler;
+pub mod realtime_route;
@@ -0,0 +1,264 @@
+use std::collections::{HashSet, VecDeque};
+use std::time::Duration;
+use actix_web::web::Bytes;
+use actix_web::{web, HttpRequest, HttpResponse};
+use actix_ws::{CloseReason, Message, MessageStream, Session};
+use futures::stream;
+use log::{error, info};
+use mongodb::Client;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::Interval;
+use uuid::Uuid;
+use crate::auth::auth_tool::{request_token, token_info};
+use crate::data_store::data_store_device_query::get_device_with_uuid_data_store_query;
+use crate::error_app::error_app::AppError;
+use crate::realtime::realtime_hub::{subscribe_readings, subscribe_state};
+use crate::realtime::realtime_model::{RealtimeClientMessage, RealtimeQuery, RealtimeServerMessage, StateEvent, StateEventsQuery};
+use crate::state::AppState;
+use crate::user::user_query::get_user_by_uuid;
+
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Comment sent on an idle state feed so proxies keep the connection open.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Upgrades to a WebSocket pushing the readings of the subscribed devices,
+/// every device must be owned by the token user.
+pub async fn realtime_readings(
+    req: HttpRequest,
+    body: web::Payload,
+    app_state: web::Data<AppState>,
+    query: web::Query<RealtimeQuery>,
+) -> Result<HttpResponse, AppError> {
+
+    let token = token_info(request_token(&req, query.token.as_deref())?).await?;
+    let user = get_user_by_uuid(&app_state.db, &token.inf.uuid).await?;
+
+    let mut devices: HashSet<Uuid> = HashSet::new();
+
+    if let Some(device_uuids) = &query.device_uuid {
+        for device_uuid in device_uuids.split(',').map(str::trim).filter(|uuid| !uuid.is_empty()) {
+            let device_uuid = match Uuid::parse_str(device_uuid) {
+                Ok(device_uuid) => device_uuid,
+                Err(_) => Err(AppError::BadRequest(format!("Invalid device uuid: {}", device_uuid)))?,
+            };
+
+            let _ = get_device_with_uuid_data_store_query(&app_state.mongo, &device_uuid, &user.uuid).await?;
+            devices.insert(device_uuid);
+        }
+    }
+
+    let (response, session, msg_stream) = match actix_ws::handle(&req, body) {
+        Ok(result) => result,
+        Err(err) => Err(AppError::ActixError(format!("file: {}, line: {}, error: {}", file!(), line!(), err)))?,
+    };
+
+    info!("file: {}, line: {}, Realtime session opened: user_uuid: {}, devices: {}", file!(), line!(), user.uuid, devices.len());
+
+    actix_web::rt::spawn(reading_session(session, msg_stream, app_state.mongo.clone(), user.uuid, devices));
+
+    Ok(response)
+}
+
+async fn reading_session(
+    mut session: Session,
+    mut msg_stream: MessageStream,
+    client: Client,
+    user_uuid: Uuid,
+    mut devices: HashSet<Uuid>,
+) {
+    let mut readings = subscribe_readings();
+    let mut heartbeat = tokio::time::interval(HEARTBEAT_INTERVAL);
+
+    if !devices.is_empty() {
+        let subscribed = RealtimeServerMessage::Subscribed { device_uuids: devices.iter().copied().collect() };
+
+        if send(&mut session, &subscribed).await.is_err() {
+            return;
+        }
+    }
+
+    let close_reason: Option<CloseReason> = loop {
+        tokio::select! {
+            message = msg_stream.recv() => match message {
+                Some(Ok(Message::Text(text))) => {
+                    let reply = client_message(&text, &client, &user_uuid, &mut devices).await;
+
+                    if send(&mut session, &reply).await.is_err() {
+                        return;
+                    }
+                }
+                Some(Ok(Message::Ping(bytes))) => {
+                    if session.pong(&bytes).await.is_err() {
+                        return;
+                    }
+                }
+                Some(Ok(Message::Close(reason))) => break reason,
+                Some(Ok(_)) => {}
+                Some(Err(err)) => {
+                    error!("file: {}, line: {}, Realtime session error: user_uuid: {}, error: {}", file!(), line!(), user_uuid, err);
+                    break None;
+                }
+                None => break None,
+            },
+
+            // a slow client skips readings, ingestion never waits on it
+            event = readings.recv() => match event {
+                Ok(event) => {
+                    if event.user_uuid == user_uuid && devices.contains(&event.device_uuid)
+                        && send(&mut session, &RealtimeServerMessage::Reading(&event)).await.is_err() {
+                        return;
+                    }
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    if send(&mut session, &RealtimeServerMessage::Lagged { skipped }).await.is_err() {
+                        return;
+                    }
+                }
+                Err(RecvError::Closed) => break None,
+            },
+
+            _ = heartbeat.tick() => {
+                if session.ping(b"").await.is_err() {
+                    return;
+                }
+            }
+        }
+    };
+
+    let _ = session.close(close_reason).await;
+
+    info!("file: {}, line: {}, Realtime session closed: user_uuid: {}", file!(), line!(), user_uuid);
+}
+
+async fn client_message(
+    text: &str,
+    client: &Client,
+    user_uuid: &Uuid,
+    devices: &mut HashSet<Uuid>,
+) -> RealtimeServerMessage<'static> {
+
+    match serde_json::from_str::<RealtimeClientMessage>(text) {
+        Ok(RealtimeClientMessage::Subscribe { device_uuids }) => {
+            for device_uuid in &device_uuids {
+                if get_device_with_uuid_data_store_query(client, device_uuid, user_uuid).await.is_err() {
+                    return RealtimeServerMessage::Error { message: format!("Device not found: {}", device_uuid) };
+                }
+            }
+
+            devices.extend(device_uuids.iter().copied());
+            RealtimeServerMessage::Subscribed { device_uuids }
+        }
+        Ok(RealtimeClientMessage::Unsubscribe { device_uuids }) => {
+            for device_uuid in &device_uuids {
+                devices.remove(device_uuid);
+            }
+
+            RealtimeServerMessage::Unsubscribed { device_uuids }
+        }
+        Err(err) => RealtimeServerMessage::Error { message: format!("Invalid message: {}", err) },
+    }
+}
+
+async fn send(session: &mut Session, message: &RealtimeServerMessage<'_>) -> Result<(), actix_ws::Closed> {
+    match serde_json::to_string(message) {
+        Ok(text) => session.text(text).await,
+        Err(err) => {
+            error!("file: {}, line: {}, Error serializing realtime message: {}", file!(), line!(), err);
+            Ok(())
+        }
+    }
+}
+
+struct StateFeed {
+    replay: VecDeque<StateEvent>,
+    events: broadcast::Receiver<StateEvent>,
+    user_uuid: Uuid,
+    keep_alive: Interval,
+}
+
+/// Server-sent events of the broker, device condition and command changes
+/// of the token user. A client resumes from `Last-Event-ID` while the event
+/// is still in the short history, older events are lost.
+pub async fn realtime_events(
+    req: HttpRequest,
+    app_state: web::Data<AppState>,
+    query: web::Query<StateEventsQuery>,
+) -> Result<HttpResponse, AppError> {
+
+    let token = token_info(request_token(&req, query.token.as_deref())?).await?;
+    let user = get_user_by_uuid(&app_state.db, &token.inf.uuid).await?;
+
+    let last_event_id = match req.headers().get("Last-Event-ID") {
+        Some(header) => match header.to_str().ok().and_then(|id| id.trim().parse::<u64>().ok()) {
+            Some(id) => Some(id),
+            None => Err(AppError::BadRequest("Invalid Last-Event-ID".to_string()))?,
+        },
+        None => query.last_event_id,
+    };
+
+    let (replay, events) = subscribe_state(last_event_id);
+
+    let feed = StateFeed {
+        replay: replay.into_iter().filter(|event| event.user_uuids.contains(&user.uuid)).collect(),
+        events,
+        user_uuid: user.uuid,
+        keep_alive: tokio::time::interval(KEEP_ALIVE_INTERVAL),
+    };
+
+    info!("file: {}, line: {}, State feed opened: user_uuid: {}, last_event_id: {:?}, replayed: {}",
+        file!(),
+        line!(),
+        user.uuid,
+        last_event_id,
+        feed.replay.len()
+    );
+
+    let body = stream::unfold(feed, |mut feed| async move {
+        while let Some(event) = feed.replay.pop_front() {
+            if let Some(frame) = state_frame(&event) {
+                return Some((Ok::<Bytes, actix_web::Error>(frame), feed));
+            }
+        }
+
+        loop {
+            let event = tokio::select! {
+                event = feed.events.recv() => Some(event),
+                _ = feed.keep_alive.tick() => None,
+            };
+
+            match event {
+                None => return Some((Ok(Bytes::from_static(b": keep-alive\n\n")), feed)),
+                Some(Ok(event)) if event.user_uuids.contains(&feed.user_uuid) => {
+                    if let Some(frame) = state_frame(&event) {
+                        return Some((Ok(frame), feed));
+                    }
+                }
+                Some(Ok(_)) => {}
+                // no id, a client reconnecting from its last id replays what is still kept
+                Some(Err(RecvError::Lagged(skipped))) => {
+                    let frame = format!("event: lagged\ndata: {{\"skipped\":{}}}\n\n", skipped);
+                    return Some((Ok(Bytes::from(frame)), feed));
+                }
+                Some(Err(RecvError::Closed)) => return None,
+            }
+        }
+    });
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .insert_header(("X-Accel-Buffering", "no"))
+        .streaming(body))
+}
+
+fn state_frame(event: &StateEvent) -> Option<Bytes> {
+    match serde_json::to_string(event) {
+        Ok(data) => Some(Bytes::from(format!("id: {}\nevent: {}\ndata: {}\n\n", event.id, event.change.event_name(), data))),
+        Err(err) => {
+            error!("file: {}, line: {}, Error serializing state event: {}", file!(), line!(), err);
+            None
+        }
+    }
+}
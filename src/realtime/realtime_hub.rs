@@ -0,0 +1,96 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use chrono::Utc;
+use once_cell::sync::Lazy;
+use tokio::sync::broadcast;
+use uuid::Uuid;
+use crate::realtime::realtime_model::{ReadingEvent, StateChange, StateEvent};
+
+/// Readings buffered per subscriber, a slower subscriber skips the oldest.
+const READING_CHANNEL_CAPACITY: usize = 4096;
+
+/// State events buffered per subscriber.
+const STATE_CHANNEL_CAPACITY: usize = 1024;
+
+/// State events kept to resume a feed from its `Last-Event-ID`.
+const STATE_HISTORY_CAPACITY: usize = 512;
+
+static READING_CHANNEL: Lazy<broadcast::Sender<ReadingEvent>> =
+    Lazy::new(|| broadcast::channel(READING_CHANNEL_CAPACITY).0);
+
+static STATE_CHANNEL: Lazy<broadcast::Sender<StateEvent>> =
+    Lazy::new(|| broadcast::channel(STATE_CHANNEL_CAPACITY).0);
+
+struct StateHistory {
+    next_id: u64,
+    events: VecDeque<StateEvent>,
+}
+
+// ids start from the boot time, an id received before a restart is older
+// than every event published after it
+static STATE_HISTORY: Lazy<Mutex<StateHistory>> = Lazy::new(|| Mutex::new(StateHistory {
+    next_id: Utc::now().timestamp_millis() as u64 * 1000,
+    events: VecDeque::with_capacity(STATE_HISTORY_CAPACITY),
+}));
+
+/// Publishes a stored reading, never waits on the subscribers.
+pub fn publish_reading(event: ReadingEvent) {
+    // no subscriber is not an error
+    let _ = READING_CHANNEL.send(event);
+}
+
+pub fn subscribe_readings() -> broadcast::Receiver<ReadingEvent> {
+    READING_CHANNEL.subscribe()
+}
+
+/// Numbers the change, keeps it in the history and publishes it to the
+/// subscribers, a change without audience is dropped.
+pub fn publish_state(change: StateChange, user_uuids: Vec<Uuid>) {
+    if user_uuids.is_empty() {
+        return;
+    }
+
+    let mut history = match STATE_HISTORY.lock() {
+        Ok(history) => history,
+        Err(err) => err.into_inner(),
+    };
+
+    let event = StateEvent {
+        id: history.next_id,
+        change,
+        user_uuids,
+        at: Utc::now(),
+    };
+
+    history.next_id += 1;
+
+    if history.events.len() == STATE_HISTORY_CAPACITY {
+        history.events.pop_front();
+    }
+
+    history.events.push_back(event.clone());
+
+    // sent under the lock so a subscriber sees each event either in its
+    // replay or on its receiver, never both nor neither
+    let _ = STATE_CHANNEL.send(event);
+}
+
+/// Subscribes to the state events, with the events kept after
+/// `last_event_id` to replay first.
+pub fn subscribe_state(last_event_id: Option<u64>) -> (Vec<StateEvent>, broadcast::Receiver<StateEvent>) {
+    let history = match STATE_HISTORY.lock() {
+        Ok(history) => history,
+        Err(err) => err.into_inner(),
+    };
+
+    let replay = match last_event_id {
+        Some(last_event_id) => history.events
+            .iter()
+            .filter(|event| event.id > last_event_id)
+            .cloned()
+            .collect(),
+        None => Vec::new(),
+    };
+
+    (replay, STATE_CHANNEL.subscribe())
+}
@@ -0,0 +1,91 @@
+use chrono::{FixedOffset, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use crate::data_store::data_store_device_model::ReadingValue;
+
+/// A reading stored by ingestion, fanned out to the realtime subscribers.
+#[derive(Debug, Serialize, Clone)]
+pub struct ReadingEvent {
+    pub device_uuid: Uuid,
+    #[serde(skip)]
+    pub user_uuid: Uuid,
+    pub metric: String,
+    pub value: ReadingValue,
+    pub scale: String,
+    pub timestamp: chrono::DateTime<FixedOffset>,
+}
+
+/// `token` authenticates clients that cannot set the Authorization header,
+/// `device_uuid` is a comma separated list of devices subscribed on connect.
+#[derive(Debug, Deserialize)]
+pub struct RealtimeQuery {
+    pub token: Option<String>,
+    pub device_uuid: Option<String>,
+}
+
+/// Messages sent by the WebSocket client.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "action", rename_all = "lowercase")]
+pub enum RealtimeClientMessage {
+    Subscribe { device_uuids: Vec<Uuid> },
+    Unsubscribe { device_uuids: Vec<Uuid> },
+}
+
+/// Messages sent to the WebSocket client.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum RealtimeServerMessage<'a> {
+    Reading(&'a ReadingEvent),
+    Subscribed { device_uuids: Vec<Uuid> },
+    Unsubscribed { device_uuids: Vec<Uuid> },
+    Lagged { skipped: u64 },
+    Error { message: String },
+}
+
+/// Connection transitions of a broker.
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BrokerState {
+    Connected,
+    Disconnected,
+    Reconnecting,
+}
+
+/// A change of a broker, a device or a command pushed on the state feed.
+#[derive(Debug, Serialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StateChange {
+    BrokerState { broker_uuid: Uuid, state: BrokerState },
+    DeviceCondition { device_uuid: Uuid, condition: String },
+    CommandAck { device_uuid: Uuid, correlation_id: String, response: String },
+}
+
+impl StateChange {
+    /// Name of the server-sent event.
+    pub fn event_name(&self) -> &'static str {
+        match self {
+            StateChange::BrokerState { .. } => "broker_state",
+            StateChange::DeviceCondition { .. } => "device_condition",
+            StateChange::CommandAck { .. } => "command_ack",
+        }
+    }
+}
+
+/// A state change numbered for `Last-Event-ID` resume, seen only by
+/// `user_uuids`.
+#[derive(Debug, Serialize, Clone)]
+pub struct StateEvent {
+    pub id: u64,
+    #[serde(flatten)]
+    pub change: StateChange,
+    #[serde(skip)]
+    pub user_uuids: Vec<Uuid>,
+    pub at: chrono::DateTime<Utc>,
+}
+
+/// `last_event_id` resumes clients that cannot set the `Last-Event-ID` header.
+#[derive(Debug, Deserialize)]
+pub struct StateEventsQuery {
+    pub token: Option<String>,
+    pub last_event_id: Option<u64>,
+}
@@ -0,0 +1,12 @@
+use actix_web::web;
+use crate::realtime::realtime_handler::{realtime_events, realtime_readings};
+
+/// Clients authenticate in the handlers, browsers cannot set the Authorization
+/// header on a WebSocket upgrade nor on an `EventSource`.
+pub fn realtime_cfg(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/realtime")
+            .route("/readings", web::get().to(realtime_readings))
+            .route("/events", web::get().to(realtime_events))
+    );
+}
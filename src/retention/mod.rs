@@ -0,0 +1,6 @@
+pub mod retention_config;
+pub mod retention_model;
+pub(crate) mod retention_query;
+pub mod retention_job;
+mod retention_handler;
+pub mod retention_route;
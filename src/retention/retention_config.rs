@@ -0,0 +1,44 @@
+use once_cell::sync::Lazy;
+
+pub struct RetentionConfig {
+    raw_days: u32,
+    rollup_days: u32,
+    interval_secs: u64,
+}
+
+impl RetentionConfig {
+    pub fn init_retention_config() -> RetentionConfig {
+        RetentionConfig {
+            raw_days: std::env::var("RETENTION_RAW_DAYS")
+                .unwrap_or_else(|_| "30".to_string())
+                .parse()
+                .expect("RETENTION_RAW_DAYS must be a number"),
+
+            rollup_days: std::env::var("RETENTION_ROLLUP_DAYS")
+                .unwrap_or_else(|_| "730".to_string())
+                .parse()
+                .expect("RETENTION_ROLLUP_DAYS must be a number"),
+
+            interval_secs: std::env::var("RETENTION_INTERVAL_SECS")
+                .unwrap_or_else(|_| "3600".to_string())
+                .parse()
+                .expect("RETENTION_INTERVAL_SECS must be a number"),
+        }
+    }
+
+    /// Days raw readings are kept, 0 keeps them forever.
+    pub fn get_raw_days() -> u32 {
+        RETENTION_CONFIG.raw_days
+    }
+
+    /// Days hourly rollups are kept, 0 keeps them forever.
+    pub fn get_rollup_days() -> u32 {
+        RETENTION_CONFIG.rollup_days
+    }
+
+    pub fn get_interval_secs() -> u64 {
+        RETENTION_CONFIG.interval_secs.max(60)
+    }
+}
+
+static RETENTION_CONFIG: Lazy<RetentionConfig> = Lazy::new(RetentionConfig::init_retention_config);
@@ -0,0 +1,40 @@
+use actix_web::{web, HttpResponse};
+use actix_web_httpauth::extractors::bearer::BearerAuth;
+use crate::auth::auth_config::AuthConfig;
+use crate::auth::auth_tool::token_info;
+use crate::error_app::error_app::{AppError, AppMsgError};
+use crate::retention::retention_config::RetentionConfig;
+use crate::retention::retention_model::{RetentionState, RetentionStatusResponse};
+use crate::state::AppState;
+use crate::user::user_query::get_user_by_uuid;
+
+pub async fn retention_status(
+    credentials: BearerAuth,
+    app_state: web::Data<AppState>,
+    retention_state: web::Data<RetentionState>,
+) -> Result<HttpResponse, AppError> {
+
+    let token = token_info(credentials.token().to_string()).await?;
+    let user = get_user_by_uuid(&app_state.db, &token.inf.uuid).await?;
+
+    if !AuthConfig::is_admin(&user.email) {
+        Err(AppError::Unauthorized(AppMsgError {
+            api_msg_error: "Admin access required".into(),
+            log_msg_error: format!("file: {}, line: {}, User is not an admin: user_uuid: {}", file!(), line!(), user.uuid),
+        }))?
+    }
+
+    let status = match retention_state.status.read() {
+        Ok(status) => status.clone(),
+        Err(_) => Err(AppError::InternalServerError("Retention status lock poisoned".into()))?,
+    };
+
+    let result = RetentionStatusResponse {
+        raw_days: RetentionConfig::get_raw_days(),
+        rollup_days: RetentionConfig::get_rollup_days(),
+        interval_secs: RetentionConfig::get_interval_secs(),
+        status,
+    };
+
+    Ok(HttpResponse::Ok().json(result))
+}
@@ -0,0 +1,118 @@
+use std::time::Duration;
+use actix_web::web;
+use chrono::{Duration as ChronoDuration, Utc};
+use log::{error, info};
+use mongodb::bson::{doc, DateTime as BsonDateTime, Document};
+use mongodb::Client;
+use sqlx::PgPool;
+use crate::data_store::data_store_device_model::READING_BUCKET_MILLIS;
+use crate::device::device_query::get_device_retention_overrides_query;
+use crate::error_app::error_app::AppError;
+use crate::retention::retention_config::RetentionConfig;
+use crate::retention::retention_model::{RetentionRun, RetentionState};
+use crate::retention::retention_query::{delete_expired_readings_query, delete_expired_rollups_query, post_rollups_query};
+
+/// Runs the retention job every `RETENTION_INTERVAL_SECS`: rolls up ended
+/// buckets, then prunes expired raw readings and rollups.
+pub fn spawn_retention_job(pool: PgPool, client: Client, state: web::Data<RetentionState>) {
+    let interval_secs = RetentionConfig::get_interval_secs();
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(interval_secs));
+
+        loop {
+            interval.tick().await;
+
+            let started_at = Utc::now();
+
+            if let Ok(mut status) = state.status.write() {
+                status.running = true;
+                status.last_started_at = Some(started_at);
+            }
+
+            let result = run_retention(&pool, &client).await;
+            let finished_at = Utc::now();
+
+            if let Ok(mut status) = state.status.write() {
+                status.running = false;
+                status.runs += 1;
+                status.last_finished_at = Some(finished_at);
+                status.last_duration_ms = Some((finished_at - started_at).num_milliseconds());
+                status.next_run_at = Some(started_at + ChronoDuration::seconds(interval_secs as i64));
+
+                match &result {
+                    Ok(run) => {
+                        status.last_error = None;
+                        status.last_rolled_up = run.rolled_up;
+                        status.last_pruned_readings = run.pruned_readings;
+                        status.last_pruned_rollups = run.pruned_rollups;
+                        status.total_rolled_up += run.rolled_up;
+                        status.total_pruned_readings += run.pruned_readings;
+                        status.total_pruned_rollups += run.pruned_rollups;
+                    }
+                    Err(err) => {
+                        status.failed_runs += 1;
+                        status.last_error = Some(format!("{:?}", err));
+                    }
+                }
+            }
+
+            match result {
+                Ok(run) => info!("file: {}, line: {}, Retention run: rolled up: {}, pruned readings: {}, pruned rollups: {}",
+                    file!(),
+                    line!(),
+                    run.rolled_up,
+                    run.pruned_readings,
+                    run.pruned_rollups
+                ),
+                Err(err) => error!("file: {}, line: {}, Retention run failed: {:?}", file!(), line!(), err),
+            }
+        }
+    });
+}
+
+async fn run_retention(pool: &PgPool, client: &Client) -> Result<RetentionRun, AppError> {
+    let now = Utc::now().timestamp_millis();
+    let mut run = RetentionRun::default();
+
+    // the current bucket is still receiving readings
+    run.rolled_up = post_rollups_query(client, BsonDateTime::from_millis(now - READING_BUCKET_MILLIS)).await?;
+
+    let overrides = get_device_retention_overrides_query(pool).await?;
+    let override_uuids: Vec<String> = overrides.iter().map(|device| device.uuid.to_string()).collect();
+
+    let global = doc! { "$nin": override_uuids };
+
+    if let Some(cutoff) = retention_cutoff(now, RetentionConfig::get_raw_days() as i32) {
+        run.pruned_readings += delete_expired_readings_query(client, global.clone(), cutoff).await?;
+    }
+
+    if let Some(cutoff) = retention_cutoff(now, RetentionConfig::get_rollup_days() as i32) {
+        run.pruned_rollups += delete_expired_rollups_query(client, global, cutoff).await?;
+    }
+
+    for device in overrides {
+        let device_uuid: Document = doc! { "$eq": device.uuid.to_string() };
+        let raw_days = device.retention_raw_days.unwrap_or(RetentionConfig::get_raw_days() as i32);
+        let rollup_days = device.retention_rollup_days.unwrap_or(RetentionConfig::get_rollup_days() as i32);
+
+        if let Some(cutoff) = retention_cutoff(now, raw_days) {
+            run.pruned_readings += delete_expired_readings_query(client, device_uuid.clone(), cutoff).await?;
+        }
+
+        if let Some(cutoff) = retention_cutoff(now, rollup_days) {
+            run.pruned_rollups += delete_expired_rollups_query(client, device_uuid, cutoff).await?;
+        }
+    }
+
+    Ok(run)
+}
+
+/// Oldest timestamp kept, `None` when the data is kept forever.
+fn retention_cutoff(now: i64, days: i32) -> Option<BsonDateTime> {
+    if days <= 0 {
+        return None;
+    }
+
+    Some(BsonDateTime::from_millis(now - days as i64 * 86_400_000))
+}
@@ -0,0 +1,44 @@
+use std::sync::RwLock;
+use chrono::Utc;
+use serde::Serialize;
+
+/// Outcome of the retention job runs, shared with the admin endpoint.
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct RetentionStatus {
+    pub running: bool,
+    pub runs: u64,
+    pub failed_runs: u64,
+    pub last_started_at: Option<chrono::DateTime<Utc>>,
+    pub last_finished_at: Option<chrono::DateTime<Utc>>,
+    pub last_duration_ms: Option<i64>,
+    pub last_error: Option<String>,
+    pub last_rolled_up: u64,
+    pub last_pruned_readings: u64,
+    pub last_pruned_rollups: u64,
+    pub total_rolled_up: u64,
+    pub total_pruned_readings: u64,
+    pub total_pruned_rollups: u64,
+    pub next_run_at: Option<chrono::DateTime<Utc>>,
+}
+
+#[derive(Debug, Default)]
+pub struct RetentionState {
+    pub status: RwLock<RetentionStatus>,
+}
+
+/// Counts of one retention run.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RetentionRun {
+    pub rolled_up: u64,
+    pub pruned_readings: u64,
+    pub pruned_rollups: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RetentionStatusResponse {
+    pub raw_days: u32,
+    pub rollup_days: u32,
+    pub interval_secs: u64,
+    #[serde(flatten)]
+    pub status: RetentionStatus,
+}
@@ -0,0 +1,188 @@
+use futures_util::TryStreamExt;
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::{doc, Bson, DateTime as BsonDateTime, Document};
+use mongodb::{Client, Collection};
+use crate::error_app::error_app::{AppError, AppMsgInfError};
+
+/// Rolls up every hour with a bucket that ended before `cutoff` and changed
+/// since its last rollup into the `rollups` collection, one document per
+/// device, metric and hour over all the buckets of the hour, then marks the
+/// buckets with the count they were rolled up with.
+/// A bucket that received a reading after its rollup keeps its older mark and
+/// its hour is rolled up again on the next run.
+pub async fn post_rollups_query(
+    client: &Client,
+    cutoff: BsonDateTime,
+) -> Result<u64, AppError> {
+
+    let database = client.database("devices");
+    let readings: Collection<Document> = database.collection("readings");
+    let rollups: Collection<Document> = database.collection("rollups");
+
+    // tags the rollups written by this run with the buckets and counts they hold
+    let run_id = ObjectId::new();
+
+    let pending = doc! {
+        "bucket_start": { "$lt": cutoff },
+        "$expr": { "$ne": ["$count", { "$ifNull": ["$rolled_up_count", -1] }] }
+    };
+
+    let value = "$bucket.readings.value";
+    let numeric = doc! { "$isNumber": value };
+    let by_at = doc! { "bucket.readings.at": 1 };
+
+    let pipeline = vec![
+        doc! { "$match": pending },
+        doc! { "$group": { "_id": { "device_uuid": "$device_uuid", "metric": "$metric", "bucket_start": "$bucket_start" } } },
+        // every bucket of a pending hour, a full hour rolled over by seq has several
+        doc! {
+            "$lookup": {
+                "from": "readings",
+                "let": { "device_uuid": "$_id.device_uuid", "metric": "$_id.metric", "bucket_start": "$_id.bucket_start" },
+                "pipeline": [
+                    { "$match": { "$expr": { "$and": [
+                        { "$eq": ["$device_uuid", "$$device_uuid"] },
+                        { "$eq": ["$metric", "$$metric"] },
+                        { "$eq": ["$bucket_start", "$$bucket_start"] }
+                    ] } } }
+                ],
+                "as": "bucket"
+            }
+        },
+        doc! { "$unwind": "$bucket" },
+        doc! { "$unwind": "$bucket.readings" },
+        doc! {
+            "$group": {
+                "_id": "$_id",
+                "user_uuid": { "$first": "$bucket.user_uuid" },
+                "buckets": { "$addToSet": { "id": "$bucket._id", "count": "$bucket.count" } },
+                "count": { "$sum": 1_i64 },
+                "numeric_count": { "$sum": { "$cond": [numeric.clone(), 1, 0] } },
+                "min": { "$min": { "$cond": [numeric.clone(), value, Bson::Null] } },
+                "max": { "$max": { "$cond": [numeric.clone(), value, Bson::Null] } },
+                "avg": { "$avg": { "$cond": [numeric.clone(), value, Bson::Null] } },
+                "sum": { "$sum": { "$cond": [numeric, value, Bson::Null] } },
+                "scale": { "$bottom": { "sortBy": by_at.clone(), "output": "$bucket.readings.scale" } },
+                "first": { "$top": { "sortBy": by_at.clone(), "output": value } },
+                "last": { "$bottom": { "sortBy": by_at, "output": value } }
+            }
+        },
+        doc! {
+            "$project": {
+                "_id": { "$concat": ["$_id.device_uuid", ":", "$_id.metric", ":", { "$toString": { "$toLong": "$_id.bucket_start" } }] },
+                "device_uuid": "$_id.device_uuid",
+                "user_uuid": 1,
+                "metric": "$_id.metric",
+                "window_start": "$_id.bucket_start",
+                "count": 1,
+                "buckets": 1,
+                "rollup_run": { "$literal": run_id },
+                "scale": 1,
+                "min": { "$toDouble": "$min" },
+                "max": { "$toDouble": "$max" },
+                "avg": { "$toDouble": "$avg" },
+                "sum": { "$cond": [{ "$gt": ["$numeric_count", 0] }, { "$toDouble": "$sum" }, Bson::Null] },
+                "first": 1,
+                "last": 1,
+                "rolled_up_at": "$$NOW"
+            }
+        },
+        doc! {
+            "$merge": {
+                "into": "rollups",
+                "on": "_id",
+                "whenMatched": "replace",
+                "whenNotMatched": "insert"
+            }
+        },
+    ];
+
+    readings.aggregate(pipeline).await.map_err(|e| mongo_error(line!(), e.to_string()))?;
+
+    let mut cursor = rollups
+        .find(doc! { "rollup_run": run_id })
+        .projection(doc! { "buckets": 1 })
+        .await
+        .map_err(|e| mongo_error(line!(), e.to_string()))?;
+
+    let mut marked = 0;
+
+    while let Some(rollup) = cursor.try_next().await.map_err(|e| mongo_error(line!(), e.to_string()))? {
+        let buckets = match rollup.get_array("buckets") {
+            Ok(buckets) => buckets,
+            Err(_) => continue,
+        };
+
+        for bucket in buckets.iter().filter_map(Bson::as_document) {
+            let (Some(bucket_id), Some(count)) = (bucket.get("id"), bucket.get("count")) else {
+                continue;
+            };
+
+            // only marked when no reading was pushed since the rollup read it
+            let result = readings
+                .update_one(
+                    doc! { "_id": bucket_id.clone(), "count": count.clone() },
+                    doc! { "$set": { "rolled_up_count": count.clone() } },
+                )
+                .await
+                .map_err(|e| mongo_error(line!(), e.to_string()))?;
+
+            marked += result.modified_count;
+        }
+    }
+
+    Ok(marked)
+}
+
+/// Deletes buckets of the selected devices whose last reading is older than
+/// `cutoff`, buckets not rolled up yet are kept.
+pub async fn delete_expired_readings_query(
+    client: &Client,
+    devices: Document,
+    cutoff: BsonDateTime,
+) -> Result<u64, AppError> {
+
+    let database = client.database("devices");
+    let readings: Collection<Document> = database.collection("readings");
+
+    let result = readings
+        .delete_many(doc! {
+            "device_uuid": devices,
+            "last_at": { "$lt": cutoff },
+            "$expr": { "$eq": ["$count", "$rolled_up_count"] }
+        })
+        .await
+        .map_err(|e| mongo_error(line!(), e.to_string()))?;
+
+    Ok(result.deleted_count)
+}
+
+/// Deletes rollups of the selected devices older than `cutoff`.
+pub async fn delete_expired_rollups_query(
+    client: &Client,
+    devices: Document,
+    cutoff: BsonDateTime,
+) -> Result<u64, AppError> {
+
+    let database = client.database("devices");
+    let rollups: Collection<Document> = database.collection("rollups");
+
+    let result = rollups
+        .delete_many(doc! {
+            "device_uuid": devices,
+            "window_start": { "$lt": cutoff }
+        })
+        .await
+        .map_err(|e| mongo_error(line!(), e.to_string()))?;
+
+    Ok(result.deleted_count)
+}
+
+fn mongo_error(line: u32, log_msg_error: String) -> AppError {
+    AppError::MongoDBError(AppMsgInfError {
+        file: file!().to_string(),
+        line,
+        api_msg_error: "Internal server error".into(),
+        log_msg_error,
+    })
+}
@@ -0,0 +1,12 @@
+use actix_web::middleware::from_fn;
+use actix_web::web;
+use crate::auth;
+use crate::retention::retention_handler::retention_status;
+
+pub fn retention_cfg(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/admin/retention")
+            .wrap(from_fn(auth::auth_midlleware::auth_middleware))
+            .route("", web::get().to(retention_status))
+    );
+}
@@ -1,3 +1,4 @@
 mod timezone_model;
 mod timezone_handler;
-pub mod timezone_route;
\ No newline at end of file
+pub mod timezone_route;
+pub mod timezone_tool;
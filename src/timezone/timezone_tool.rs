@@ -0,0 +1,14 @@
+use std::str::FromStr;
+use chrono_tz::Tz;
+use crate::error_app::error_app::AppError;
+
+/// IANA timezone from a request, UTC when none is given.
+pub fn parse_timezone(name: Option<&str>) -> Result<Tz, AppError> {
+    match name {
+        Some(name) => match Tz::from_str(name) {
+            Ok(tz) => Ok(tz),
+            Err(_) => Err(AppError::BadRequest(format!("Invalid timezone: {}", name)))?,
+        },
+        None => Ok(Tz::UTC),
+    }
+}